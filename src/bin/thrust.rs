@@ -9,24 +9,26 @@ use std::io::{Write, Read};
 use std::fs::File;
 use std::path::Path;
 use thrust_parser::Parser;
-use thrust_codegen::{compile, find_rust_namespace};
+use thrust_codegen::{compile_with, find_rust_namespace, Options};
 
 const USAGE: &'static str = "
 Thrust: Thrift compiler for Rust
 
 Usage:
-  thrust <input> <output>
+  thrust [--context] <input> <output>
   thrust --version
 
 Options:
   -h --help     Show this screen.
   --version     Show version.
+  --context     Pass the call's thrust::Context to every service method.
 ";
 
 #[derive(Debug, RustcDecodable)]
 struct Args {
     arg_input: String,
-    arg_output: String
+    arg_output: String,
+    flag_context: bool
 }
 
 fn main() {
@@ -45,7 +47,11 @@ fn main() {
     let module = Path::new(&args.arg_output).join(ns.module).with_extension("rs");
     let mut output = File::create(module).expect("error creating the module.");
 
-    compile(&mut parser, &mut output).unwrap();
+    let options = Options {
+        context: args.flag_context
+    };
+
+    compile_with(&mut parser, &mut output, &options).unwrap();
     // println!("{}", String::from_utf8(buf).unwrap());
     // let mut file = File::create("src/testing.rs").unwrap();
     // file.write_all(&buf[..]).unwrap();
@@ -1,6 +1,8 @@
 use protocol::{Serializer, Deserializer, ThriftSerializer, ThriftField, ThriftMessage, ThriftDeserializer, ThriftMessageType, ThriftType, Error};
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::io::{self, Read, Write};
+use protocol::Protocol;
+use header;
+use byteorder::{ByteOrder, BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write, Cursor};
 use std::iter;
 use byteorder;
 use std::convert;
@@ -9,19 +11,73 @@ pub const THRIFT_VERSION_1: i32 = 0x80010000;
 pub const THRIFT_VERSION_MASK: i32 = 0xffff0000;
 pub const THRIFT_TYPE_MASK: i32 = 0x000000ff;
 
-pub struct BinarySerializer<'a> {
-    wr: &'a mut Write
+/// The binary protocol, which is what Thrift speaks by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Binary;
+
+impl Protocol for Binary {
+    type Serializer = BinarySerializer<Vec<u8>>;
+    type Deserializer = BinaryDeserializer<Cursor<Vec<u8>>>;
+
+    fn serializer(&self) -> BinarySerializer<Vec<u8>> {
+        BinarySerializer::new(Vec::new())
+    }
+
+    fn finish(&self, se: BinarySerializer<Vec<u8>>) -> Vec<u8> {
+        se.into_inner()
+    }
+
+    fn deserializer(&self, buf: Vec<u8>) -> BinaryDeserializer<Cursor<Vec<u8>>> {
+        BinaryDeserializer::new(Cursor::new(buf))
+    }
+
+    fn read_message(&self, buf: &[u8]) -> Result<ThriftMessage, Error> {
+        BinaryDeserializer::new(Cursor::new(buf)).read_message_begin()
+    }
+
+    fn read_from<R: Read>(&self, rd: &mut R, limit: usize) -> Result<Vec<u8>, Error> {
+        // The length isn't known up front, so read a byte at a time until the scanner
+        // finds the end. Streams are buffered by their transport.
+        let mut scanner = MessageScanner::new();
+        let mut buf = Vec::new();
+        let mut byte = [0u8; 1];
+
+        loop {
+            rd.read_exact(&mut byte)?;
+            buf.push(byte[0]);
+
+            if buf.len() > limit {
+                return Err(Error::TooLarge(limit));
+            }
+
+            if scanner.scan(&buf)?.is_some() {
+                return Ok(buf);
+            }
+        }
+    }
+
+    fn header_id(&self) -> u32 {
+        header::BINARY_PROTOCOL
+    }
+}
+
+pub struct BinarySerializer<W: Write> {
+    wr: W
 }
 
-impl<'a> BinarySerializer<'a> {
-    pub fn new(wr: &'a mut Write) -> BinarySerializer<'a> {
+impl<W: Write> BinarySerializer<W> {
+    pub fn new(wr: W) -> BinarySerializer<W> {
         BinarySerializer {
             wr: wr
         }
     }
+
+    pub fn into_inner(self) -> W {
+        self.wr
+    }
 }
 
-impl<'a> Serializer for BinarySerializer<'a> {
+impl<W: Write> Serializer for BinarySerializer<W> {
 
     fn serialize_bool(&mut self, val: bool) -> Result<(), Error> {
         if val {
@@ -90,13 +146,17 @@ impl<'a> Serializer for BinarySerializer<'a> {
     }
 }
 
-impl<'a> ThriftSerializer for BinarySerializer<'a> {
+impl<W: Write> ThriftSerializer for BinarySerializer<W> {
     fn write_message_begin(&mut self, name: &str, message_type: ThriftMessageType) -> Result<(), Error> {
+        self.write_message_begin_seq(name, message_type, 0)
+    }
+
+    fn write_message_begin_seq(&mut self, name: &str, message_type: ThriftMessageType, seq: i32) -> Result<(), Error> {
         let version = THRIFT_VERSION_1 | message_type as i32;
 
         try!(self.serialize_i32(version));
         try!(self.serialize_str(name));
-        try!(self.serialize_i16(0));
+        try!(self.serialize_i32(seq));
 
         Ok(())
     }
@@ -191,7 +251,7 @@ impl<R: Read + ReadBytesExt> Deserializer for BinaryDeserializer<R> {
         let mut buf = Vec::with_capacity(len);
 
         buf.extend(iter::repeat(0).take(len));
-        try!(self.rd.read(&mut buf));
+        try!(self.rd.read_exact(&mut buf));
 
         Ok(buf)
     }
@@ -215,7 +275,7 @@ impl<R: Read + ReadBytesExt> ThriftDeserializer for BinaryDeserializer<R> {
                 Ok(ThriftMessage {
                     name: try!(self.deserialize_str()),
                     ty: ThriftMessageType::from((size & THRIFT_TYPE_MASK) as i8),
-                    seq: try!(self.deserialize_i16())
+                    seq: try!(self.deserialize_i32())
                 })
             }
         } else {
@@ -255,11 +315,202 @@ impl<R: Read + ReadBytesExt> ThriftDeserializer for BinaryDeserializer<R> {
     }
 }
 
+/// The deepest nesting of structs and containers `message_len` will follow.
+const MAX_DEPTH: usize = 64;
+
+/// What the scanner expects next, innermost last.
+#[derive(Debug, Clone, Copy)]
+enum Frame {
+    /// A value of the type.
+    Value(i8),
+    /// The next field of a struct, or its stop byte.
+    Struct,
+    /// The elements of a list or set still to come.
+    List(i8, usize),
+    /// The keys and values of a map still to come, counted separately.
+    Map(i8, i8, usize)
+}
+
+/// Walks encoded messages without decoding them to find out where they end, which is
+/// how message boundaries are found on unframed transports, where there's no length
+/// prefix to go by.
+///
+/// When the buffer ends before the message does, the scanner remembers how far it got,
+/// so it picks up from there once more bytes have arrived instead of starting over.
+#[derive(Debug, Default)]
+pub struct MessageScanner {
+    pos: usize,
+    stack: Vec<Frame>,
+    /// Whether the message header has been scanned.
+    started: bool
+}
+
+impl MessageScanner {
+    pub fn new() -> MessageScanner {
+        MessageScanner::default()
+    }
+
+    /// Find the length of the message at the start of `buf`, or `None` if it hasn't
+    /// been fully received yet. `buf` has to start with the same bytes as on the last
+    /// call, until a length is returned, after which the scanner starts on the next
+    /// message.
+    pub fn scan(&mut self, buf: &[u8]) -> Result<Option<usize>, Error> {
+        if !self.started {
+            if buf.len() < 8 {
+                return Ok(None);
+            }
+
+            let version = BigEndian::read_i32(&buf[..4]);
+            if version >= 0 {
+                return Err(Error::ProtocolVersionMissing);
+            } else if version & THRIFT_VERSION_MASK != THRIFT_VERSION_1 {
+                return Err(Error::BadVersion);
+            }
+
+            // The method name and sequence id.
+            let len = size(&buf[4..8])?;
+            if buf.len() < 8 + len + 4 {
+                return Ok(None);
+            }
+
+            self.pos = 8 + len + 4;
+            self.stack.push(Frame::Struct);
+            self.started = true;
+        }
+
+        while let Some(frame) = self.stack.pop() {
+            if self.stack.len() > MAX_DEPTH {
+                return Err(Error::NestingTooDeep);
+            }
+
+            match self.step(buf, frame)? {
+                true => {},
+                false => {
+                    // Try the same frame again once there's more to go on.
+                    self.stack.push(frame);
+                    return Ok(None);
+                }
+            }
+        }
+
+        let len = self.pos;
+        *self = MessageScanner::new();
+        Ok(Some(len))
+    }
+
+    /// Make progress on `frame`, pushing whatever it still expects. Returns false,
+    /// without consuming anything, if the buffer ends too soon.
+    fn step(&mut self, buf: &[u8], frame: Frame) -> Result<bool, Error> {
+        let rest = &buf[self.pos..];
+
+        match frame {
+            Frame::Struct => {
+                if rest.is_empty() {
+                    return Ok(false);
+                } else if rest[0] as i8 == ThriftType::Stop as i8 {
+                    self.pos += 1;
+                } else if rest.len() < 3 {
+                    return Ok(false);
+                } else {
+                    // The field type and id, and then the field itself.
+                    self.pos += 3;
+                    self.stack.push(Frame::Struct);
+                    self.stack.push(Frame::Value(rest[0] as i8));
+                }
+            },
+            Frame::List(_, 0) | Frame::Map(_, _, 0) => {},
+            Frame::List(ety, left) => {
+                self.stack.push(Frame::List(ety, left - 1));
+                self.stack.push(Frame::Value(ety));
+            },
+            Frame::Map(kty, vty, left) => {
+                self.stack.push(Frame::Map(kty, vty, left - 1));
+                self.stack.push(Frame::Value(if left % 2 == 0 { kty } else { vty }));
+            },
+            Frame::Value(ty) => {
+                let fixed = match ty {
+                    // Void fields don't carry a value.
+                    1 => 0,
+                    2 | 3 => 1,
+                    6 => 2,
+                    8 => 4,
+                    4 | 9 | 10 => 8,
+                    11 => {
+                        if rest.len() < 4 {
+                            return Ok(false);
+                        }
+
+                        4 + size(&rest[..4])?
+                    },
+                    12 => {
+                        self.stack.push(Frame::Struct);
+                        0
+                    },
+                    13 => {
+                        if rest.len() < 6 {
+                            return Ok(false);
+                        }
+
+                        let (kty, vty) = (element(rest[0] as i8)?, element(rest[1] as i8)?);
+                        self.stack.push(Frame::Map(kty, vty, size(&rest[2..6])? * 2));
+                        6
+                    },
+                    14 | 15 => {
+                        if rest.len() < 5 {
+                            return Ok(false);
+                        }
+
+                        let ety = element(rest[0] as i8)?;
+                        self.stack.push(Frame::List(ety, size(&rest[1..5])?));
+                        5
+                    },
+                    ty => return Err(Error::UnknownType(ty))
+                };
+
+                if rest.len() < fixed {
+                    return Ok(false);
+                }
+
+                self.pos += fixed;
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// Read a container or string size.
+fn size(buf: &[u8]) -> Result<usize, Error> {
+    let size = BigEndian::read_i32(buf);
+    if size < 0 {
+        Err(Error::NegativeSize)
+    } else {
+        Ok(size as usize)
+    }
+}
+
+/// Check the element type of a container. Void elements take up no bytes, so a
+/// container of them would be scanned forever.
+fn element(ty: i8) -> Result<i8, Error> {
+    match ty {
+        1 => Err(Error::VoidElement),
+        ty => Ok(ty)
+    }
+}
+
+/// Find the length of the first complete message in `buf`, or `None` if the message
+/// hasn't been fully received yet. See `MessageScanner`, which doesn't start over
+/// every time.
+pub fn message_len(buf: &[u8]) -> Result<Option<usize>, Error> {
+    MessageScanner::new().scan(buf)
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::{Cursor, Read};
     use byteorder::{ReadBytesExt, BigEndian};
-    use protocol::{ThriftMessageType, ThriftType, ThriftMessage, ThriftDeserializer, ThriftSerializer, Serializer, Serialize, Deserializer};
+    use protocol::{ThriftMessageType, ThriftType, ThriftMessage, ThriftDeserializer, ThriftSerializer, Serializer, Serialize, Deserializer, Error};
+    use protocol::Protocol;
     use super::*;
 
     #[test]
@@ -408,4 +659,144 @@ mod tests {
         assert_eq!(msg.name, "Foobar123");
         assert_eq!(msg.ty, ThriftMessageType::Call);
     }
+
+    fn encoded_message() -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        {
+            let mut se = BinarySerializer::new(&mut buf);
+            se.write_message_begin("Foobar123", ThriftMessageType::Call);
+            se.write_struct_begin("Foobar123_Args");
+            se.write_field_begin("a", ThriftType::String, 1);
+            se.serialize_str("hello");
+            se.write_field_end();
+            se.write_field_begin("b", ThriftType::I64, 2);
+            se.serialize_i64(45);
+            se.write_field_end();
+            se.write_field_stop();
+            se.write_struct_end();
+            se.write_message_end();
+        }
+
+        buf
+    }
+
+    #[test]
+    fn message_len_of_complete_message() {
+        let buf = encoded_message();
+        assert_eq!(message_len(&buf).unwrap(), Some(buf.len()));
+    }
+
+    #[test]
+    fn message_len_of_incomplete_message() {
+        let buf = encoded_message();
+
+        for n in 0..buf.len() {
+            assert_eq!(message_len(&buf[..n]).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn message_len_of_multiple_messages() {
+        let mut buf = encoded_message();
+        let len = buf.len();
+        buf.extend_from_slice(&encoded_message()[..10]);
+        assert_eq!(message_len(&buf).unwrap(), Some(len));
+    }
+
+    #[test]
+    fn should_resume_scanning_where_it_left_off() {
+        let buf = encoded_message();
+        let mut scanner = MessageScanner::new();
+
+        for n in 0..buf.len() {
+            assert_eq!(scanner.scan(&buf[..n]).unwrap(), None);
+        }
+
+        assert!(scanner.pos > 0);
+        assert_eq!(scanner.scan(&buf).unwrap(), Some(buf.len()));
+        // The scanner starts over on the next message.
+        assert_eq!(scanner.scan(&buf).unwrap(), Some(buf.len()));
+    }
+
+    #[test]
+    fn message_len_rejects_void_elements() {
+        let mut buf = Vec::new();
+        {
+            let mut se = BinarySerializer::new(&mut buf);
+            se.write_message_begin("foo", ThriftMessageType::Call).unwrap();
+        }
+
+        // A list field claiming a million void elements.
+        buf.extend_from_slice(&[15, 0, 1, 1, 0x00, 0x0F, 0x42, 0x40]);
+        match message_len(&buf) {
+            Err(Error::VoidElement) => {},
+            res => panic!("Expected the list to be rejected, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn message_len_without_version() {
+        let buf = vec![0, 0, 0, 1, 97, 1];
+        assert!(message_len(&buf).is_err());
+    }
+
+    #[test]
+    fn should_round_trip_through_the_protocol() {
+        let mut se = Binary.serializer();
+        se.write_message_begin("foo", ThriftMessageType::Call).unwrap();
+        se.serialize_i32(5).unwrap();
+        se.write_message_end().unwrap();
+        let buf = Binary.finish(se);
+
+        assert_eq!(&*Binary.read_message(&buf).unwrap().name, "foo");
+
+        let mut de = Binary.deserializer(buf);
+        assert_eq!(de.read_message_begin().unwrap().ty, ThriftMessageType::Call);
+        assert_eq!(de.deserialize_i32().unwrap(), 5);
+    }
+
+    #[test]
+    fn should_write_the_sequence_id() {
+        let mut se = Binary.serializer();
+        se.write_message_begin_seq("foo", ThriftMessageType::Reply, 42).unwrap();
+        se.write_message_end().unwrap();
+        let buf = Binary.finish(se);
+
+        let msg = Binary.read_message(&buf).unwrap();
+        assert_eq!((msg.ty, msg.seq), (ThriftMessageType::Reply, 42));
+    }
+    #[test]
+    fn should_read_one_message_off_a_stream() {
+        let mut se = Binary.serializer();
+        se.write_message_begin("foo", ThriftMessageType::Call).unwrap();
+        se.write_message_end().unwrap();
+        let mut msg = Binary.finish(se);
+
+        // The arguments: an i32 field and the stop that ends them.
+        msg.extend_from_slice(&[8, 0, 1, 0, 0, 0, 5, 0]);
+
+        let mut input = msg.clone();
+        input.extend_from_slice(&msg);
+        let mut rd = Cursor::new(input);
+
+        assert_eq!(Binary.read_from(&mut rd, 1024).unwrap(), msg);
+        assert_eq!(Binary.read_from(&mut rd, 1024).unwrap(), msg);
+        assert!(Binary.read_from(&mut rd, 1024).is_err());
+    }
+
+    #[test]
+    fn should_stop_reading_messages_past_the_limit() {
+        let mut se = Binary.serializer();
+        se.write_message_begin("foo", ThriftMessageType::Call).unwrap();
+        se.write_message_end().unwrap();
+        let mut msg = Binary.finish(se);
+        msg.extend_from_slice(&[8, 0, 1, 0, 0, 0, 5, 0]);
+
+        let limit = msg.len() - 1;
+        match Binary.read_from(&mut Cursor::new(msg), limit) {
+            Err(Error::TooLarge(size)) => assert_eq!(size, limit),
+            res => panic!("[test]: Expected the message to be too large, got {:?}.", res)
+        }
+    }
 }
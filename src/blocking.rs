@@ -0,0 +1,170 @@
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::channel;
+use std::thread;
+use byteorder;
+use tangle::{Future, Async};
+use protocol::{Error, ThriftDeserializer, Protocol};
+use reactor::{Framing, Limits, Addr};
+use context::Context;
+use header::Headers;
+use result::{ThrustResult, ThrustError};
+use runner::Runner;
+use transport::{Framed, Buffered, TcpTransport};
+
+/// Block the current thread until the future resolves.
+pub fn wait<T>(future: Future<T>) -> ThrustResult<T>
+    where T: 'static + Send
+{
+    let (tx, rx) = channel();
+    future.and_then(move |val| {
+        tx.send(val);
+        Async::Ok(())
+    });
+
+    Ok(rx.recv()?)
+}
+
+/// Whether the error means the peer hung up instead of sending a malformed message.
+fn is_eof(err: &Error) -> bool {
+    match *err {
+        Error::Byteorder(byteorder::Error::UnexpectedEOF) => true,
+        Error::Byteorder(byteorder::Error::Io(ref err)) |
+        Error::Io(ref err) => err.kind() == io::ErrorKind::UnexpectedEof,
+        _ => false
+    }
+}
+
+/// Serve requests on a single connection until the peer hangs up. Each request is run
+/// on the current thread and its reply is written back before the next one is read.
+/// `peer` ends up in the context of every call. A request longer than `max_size` bytes
+/// fails the connection.
+pub fn serve_connection<R, P, T>(runner: &mut R, protocol: &P, peer: Option<Addr>, transport: &mut T, max_size: usize) -> ThrustResult<()>
+    where R: Runner,
+          P: Protocol,
+          T: Read + Write
+{
+    loop {
+        let buf = match protocol.read_from(&mut *transport, max_size) {
+            Ok(buf) => buf,
+            Err(ref err) if is_eof(err) => return Ok(()),
+            Err(err) => return Err(err.into())
+        };
+
+        let reply = {
+            let mut de = protocol.deserializer(buf);
+            let msg = de.read_message_begin()?;
+            let ctx = Context::new(peer.clone(), &msg, Headers::new());
+            wait(runner.run(&ctx, protocol, &mut de, msg)?)?
+        };
+
+        transport.write_all(&reply)?;
+        transport.flush()?;
+    }
+}
+
+/// Accept connections forever and serve each one on its own thread. Every connection
+/// gets a fresh `Runner` from `new_runner`, which is called on the accepting thread.
+/// Requests are capped at `limits.max_frame_size` bytes.
+pub fn serve<F, R, P>(listener: TcpListener, framing: Framing, limits: Limits, protocol: P, mut new_runner: F) -> ThrustResult<()>
+    where F: FnMut() -> R,
+          R: 'static + Runner + Send,
+          P: Protocol
+{
+    if framing == Framing::Header {
+        return Err(ThrustError::Str("Blocking servers don't support THeader.".to_string()));
+    }
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let mut runner = new_runner();
+        let protocol = protocol.clone();
+        let max_size = limits.max_frame_size;
+
+        thread::spawn(move || -> ThrustResult<()> {
+            let peer = Some(Addr::Tcp(stream.peer_addr()?));
+            let transport = TcpTransport::from_stream(stream)?;
+
+            let res = match framing {
+                Framing::Framed => serve_connection(&mut runner, &protocol, peer, &mut Framed::new(transport), max_size),
+                Framing::Buffered => serve_connection(&mut runner, &protocol, peer, &mut Buffered::new(transport), max_size),
+                Framing::Header => unreachable!()
+            };
+
+            if let Err(ref err) = res {
+                warn!("connection failed; err={:?}", err);
+            }
+
+            res
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tangle::Future;
+    use byteorder::{BigEndian, WriteBytesExt};
+    use protocol::{Deserializer, ThriftDeserializer, ThriftMessage, ThriftMessageType, Protocol, Error};
+    use binary_protocol::{Binary, BinaryDeserializer};
+    use transport::{Framed, MemoryTransport};
+    use runner::Runner;
+    use context::Context;
+    use util;
+
+    struct Echo;
+
+    impl Runner for Echo {
+        fn run<P, D>(&mut self, ctx: &Context, protocol: &P, de: &mut D, msg: ThriftMessage) -> Result<Future<Vec<u8>>, Error>
+            where P: Protocol,
+                  D: Deserializer + ThriftDeserializer
+        {
+            Ok(Future::unit(util::create_empty_thrift_message(&msg.name, ThriftMessageType::Reply)))
+        }
+    }
+
+    #[test]
+    fn should_serve_until_the_peer_hangs_up() {
+        let mut input = Vec::new();
+        for name in ["foo", "bar"].iter() {
+            let mut msg = util::create_empty_thrift_message(name, ThriftMessageType::Call);
+            // The stop of the empty arguments.
+            msg.push(0);
+            input.write_u32::<BigEndian>(msg.len() as u32);
+            input.extend_from_slice(&msg);
+        }
+
+        let mut transport = Framed::new(MemoryTransport::new(input));
+        serve_connection(&mut Echo, &Binary, None, &mut transport, Limits::default().max_frame_size).unwrap();
+
+        let mut replies = Framed::new(MemoryTransport::new(transport.into_inner().take_output()));
+        for name in ["foo", "bar"].iter() {
+            let mut de = BinaryDeserializer::new(&mut replies);
+            let msg = de.read_message_begin().unwrap();
+            assert_eq!(&*msg.name, *name);
+            assert_eq!(msg.ty, ThriftMessageType::Reply);
+        }
+    }
+
+    #[test]
+    fn should_fail_requests_past_the_limit() {
+        let mut msg = util::create_empty_thrift_message("foo", ThriftMessageType::Call);
+        msg.push(0);
+
+        let mut input = Vec::new();
+        input.write_u32::<BigEndian>(msg.len() as u32);
+        input.extend_from_slice(&msg);
+
+        let mut transport = Framed::new(MemoryTransport::new(input));
+        assert!(serve_connection(&mut Echo, &Binary, None, &mut transport, msg.len() - 1).is_err());
+        assert!(transport.into_inner().take_output().is_empty());
+    }
+
+    #[test]
+    fn should_wait_for_future() {
+        assert_eq!(wait(Future::unit(5)).unwrap(), 5);
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use result::{ThrustResult, ThrustError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Calls go through and their outcomes are tracked.
+    Closed,
+    /// Too many calls failed. Calls fail fast with `ThrustError::CircuitOpen`.
+    Open,
+    /// The breaker has been open for long enough, and a few trial calls are let
+    /// through to see whether the server has recovered.
+    HalfOpen
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    /// The share of failed calls, between 0 and 1, that opens the breaker.
+    pub failure_rate: f64,
+    /// The number of most recent calls the failure rate is computed over.
+    pub window: usize,
+    /// The breaker doesn't open before this many calls have been made.
+    pub min_calls: usize,
+    /// Calls that take longer than this to be replied to count as failures.
+    pub slow_call: Duration,
+    /// How long the breaker stays open before letting trial calls through.
+    pub open_for: Duration,
+    /// The number of trial calls that have to succeed for the breaker to close.
+    pub half_open_calls: usize
+}
+
+impl Default for BreakerConfig {
+    fn default() -> BreakerConfig {
+        BreakerConfig {
+            failure_rate: 0.5,
+            window: 20,
+            min_calls: 10,
+            slow_call: Duration::from_secs(5),
+            open_for: Duration::from_secs(10),
+            half_open_calls: 3
+        }
+    }
+}
+
+struct Inner {
+    state: State,
+    /// The outcomes of the most recent calls, `true` being a failure.
+    outcomes: VecDeque<bool>,
+    opened_at: Instant,
+    /// Trial calls let through while half-open.
+    trials: usize,
+    /// Trial calls that succeeded while half-open.
+    recovered: usize
+}
+
+impl Inner {
+    fn open(&mut self) {
+        warn!("opening the circuit breaker");
+        self.state = State::Open;
+        self.opened_at = Instant::now();
+        self.outcomes.clear();
+    }
+}
+
+/// A circuit breaker that stops calls to a server that keeps failing.
+///
+/// Breakers are cheap to clone; clones share their state, so one breaker can guard
+/// several connections to the same server.
+#[derive(Clone)]
+pub struct Breaker {
+    config: BreakerConfig,
+    inner: Arc<Mutex<Inner>>
+}
+
+impl Breaker {
+    pub fn new(config: BreakerConfig) -> Breaker {
+        Breaker {
+            config: config,
+            inner: Arc::new(Mutex::new(Inner {
+                state: State::Closed,
+                outcomes: VecDeque::new(),
+                opened_at: Instant::now(),
+                trials: 0,
+                recovered: 0
+            }))
+        }
+    }
+
+    pub fn state(&self) -> State {
+        let inner = self.inner.lock().expect("Failed to take the breaker lock.");
+        match inner.state {
+            State::Open if inner.opened_at.elapsed() >= self.config.open_for => State::HalfOpen,
+            state => state
+        }
+    }
+
+    /// Ask whether a call can be made. Every call that's allowed has to have its
+    /// outcome recorded with `success`, `failure` or `release`.
+    pub fn allow(&self) -> ThrustResult<()> {
+        let mut inner = self.inner.lock()?;
+
+        if inner.state == State::Open {
+            if inner.opened_at.elapsed() < self.config.open_for {
+                return Err(ThrustError::CircuitOpen);
+            }
+
+            info!("the circuit breaker is half-open");
+            inner.state = State::HalfOpen;
+            inner.trials = 0;
+            inner.recovered = 0;
+        }
+
+        if inner.state == State::HalfOpen {
+            if inner.trials >= self.config.half_open_calls {
+                return Err(ThrustError::CircuitOpen);
+            }
+
+            inner.trials += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Record a call that was replied to after `elapsed`.
+    pub fn success(&self, elapsed: Duration) {
+        if elapsed > self.config.slow_call {
+            return self.failure();
+        }
+
+        let mut inner = self.inner.lock().expect("Failed to take the breaker lock.");
+        match inner.state {
+            State::Closed => self.push(&mut inner, false),
+            State::HalfOpen => {
+                inner.recovered += 1;
+                if inner.recovered >= self.config.half_open_calls {
+                    info!("closing the circuit breaker");
+                    inner.state = State::Closed;
+                }
+            },
+            // A call that was allowed before the breaker opened.
+            State::Open => {}
+        }
+    }
+
+    /// Record a call that failed.
+    pub fn failure(&self) {
+        let mut inner = self.inner.lock().expect("Failed to take the breaker lock.");
+        match inner.state {
+            State::Closed => self.push(&mut inner, true),
+            // The server hasn't recovered yet.
+            State::HalfOpen => inner.open(),
+            State::Open => {}
+        }
+    }
+
+    /// Record a call whose outcome says nothing about the server, like a cancelled
+    /// one. While half-open, this frees the trial slot the call took up.
+    pub fn release(&self) {
+        let mut inner = self.inner.lock().expect("Failed to take the breaker lock.");
+        if inner.state == State::HalfOpen && inner.trials > 0 {
+            inner.trials -= 1;
+        }
+    }
+
+    fn push(&self, inner: &mut Inner, failed: bool) {
+        inner.outcomes.push_back(failed);
+        while inner.outcomes.len() > self.config.window {
+            inner.outcomes.pop_front();
+        }
+
+        let calls = inner.outcomes.len();
+        let failures = inner.outcomes.iter().filter(|&&failed| failed).count();
+        if calls >= self.config.min_calls && failures as f64 >= self.config.failure_rate * calls as f64 {
+            inner.open();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use result::ThrustError;
+
+    fn config() -> BreakerConfig {
+        BreakerConfig {
+            failure_rate: 0.5,
+            window: 4,
+            min_calls: 4,
+            slow_call: Duration::from_millis(50),
+            open_for: Duration::from_millis(50),
+            half_open_calls: 2
+        }
+    }
+
+    fn trip(breaker: &Breaker) {
+        for _ in 0..4 {
+            breaker.allow().unwrap();
+            breaker.failure();
+        }
+    }
+
+    #[test]
+    fn should_open_on_failure_rate() {
+        let breaker = Breaker::new(config());
+
+        breaker.success(Duration::from_millis(1));
+        breaker.success(Duration::from_millis(1));
+        breaker.failure();
+        assert_eq!(breaker.state(), State::Closed);
+
+        breaker.failure();
+        assert_eq!(breaker.state(), State::Open);
+
+        match breaker.allow() {
+            Err(ThrustError::CircuitOpen) => {},
+            res => panic!("[test]: Expected the breaker to be open, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn should_count_slow_calls_as_failures() {
+        let breaker = Breaker::new(config());
+        for _ in 0..4 {
+            breaker.success(Duration::from_millis(100));
+        }
+
+        assert_eq!(breaker.state(), State::Open);
+    }
+
+    #[test]
+    fn should_close_after_trial_calls() {
+        let breaker = Breaker::new(config());
+        trip(&breaker);
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(breaker.state(), State::HalfOpen);
+
+        breaker.allow().unwrap();
+        breaker.allow().unwrap();
+        // Only two trial calls are let through.
+        assert!(breaker.allow().is_err());
+
+        breaker.success(Duration::from_millis(1));
+        breaker.success(Duration::from_millis(1));
+        assert_eq!(breaker.state(), State::Closed);
+        assert!(breaker.allow().is_ok());
+    }
+
+    #[test]
+    fn should_reopen_on_failed_trial() {
+        let breaker = Breaker::new(config());
+        trip(&breaker);
+
+        thread::sleep(Duration::from_millis(60));
+        breaker.allow().unwrap();
+        breaker.failure();
+
+        assert_eq!(breaker.state(), State::Open);
+        assert!(breaker.allow().is_err());
+    }
+
+    #[test]
+    fn should_free_trial_slots_of_released_calls() {
+        let breaker = Breaker::new(config());
+        trip(&breaker);
+
+        thread::sleep(Duration::from_millis(60));
+        breaker.allow().unwrap();
+        breaker.allow().unwrap();
+        assert!(breaker.allow().is_err());
+
+        breaker.release();
+        breaker.allow().unwrap();
+        assert_eq!(breaker.state(), State::HalfOpen);
+    }
+}
@@ -0,0 +1,498 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::sync::mpsc::Sender;
+use tangle::Future;
+use protocol::{Serializer, ThriftSerializer, Deserializer, ThriftDeserializer, ThriftMessageType, Protocol, Error};
+use dispatcher::{Incoming, Reply};
+use header::Headers;
+use result::ThrustError;
+use server::ExceptionKind;
+
+/// Hooks around every call a client makes, for things like retries, logging, metrics or
+/// injecting headers. Filters are installed with `add_filter` and apply to every client
+/// created afterwards, generated or not. A client can be given its own stack instead
+/// with `Incoming::Filters`, which is what `PoolConfig::filters` does.
+///
+/// Filters run on the dispatcher thread of the client, and are shared by several
+/// clients, so any state needs to be synchronized.
+pub trait Filter: Send + Sync {
+    /// Called before a call is sent with the encoded request, which can be modified.
+    /// Retries are sent through here again, with the request as it was encoded.
+    fn outgoing(&self, method: &str, request: &mut Vec<u8>) {}
+
+    /// Called with the encoded reply of a successful call, which can be modified.
+    fn incoming(&self, method: &str, reply: &mut Vec<u8>) {}
+
+    /// Called before a call is sent on a connection using `Framing::Header`, to add
+    /// info headers.
+    fn headers(&self, method: &str, headers: &mut Headers) {}
+
+    /// Called with the info headers of a reply that came with a THeader.
+    fn reply_headers(&self, method: &str, headers: &Headers) {}
+
+    /// Called once a call has failed and won't be retried.
+    fn failed(&self, method: &str, err: &ThrustError) {}
+
+    /// Whether a call that failed with `err` should be sent again. `attempts` is the
+    /// number of times it has been sent so far.
+    fn retry(&self, method: &str, err: &ThrustError, attempts: usize) -> bool {
+        false
+    }
+}
+
+impl<F: Filter> Filter for Arc<F> {
+    fn outgoing(&self, method: &str, request: &mut Vec<u8>) {
+        (**self).outgoing(method, request)
+    }
+
+    fn incoming(&self, method: &str, reply: &mut Vec<u8>) {
+        (**self).incoming(method, reply)
+    }
+
+    fn headers(&self, method: &str, headers: &mut Headers) {
+        (**self).headers(method, headers)
+    }
+
+    fn reply_headers(&self, method: &str, headers: &Headers) {
+        (**self).reply_headers(method, headers)
+    }
+
+    fn failed(&self, method: &str, err: &ThrustError) {
+        (**self).failed(method, err)
+    }
+
+    fn retry(&self, method: &str, err: &ThrustError, attempts: usize) -> bool {
+        (**self).retry(method, err, attempts)
+    }
+}
+
+/// An ordered stack of filters. The first filter added is the outermost: it sees
+/// requests first and replies last.
+#[derive(Clone)]
+pub struct Filters {
+    filters: Vec<Arc<Filter>>
+}
+
+impl Filters {
+    pub fn new() -> Filters {
+        Filters {
+            filters: Vec::new()
+        }
+    }
+
+    pub fn push<F: 'static + Filter>(&mut self, filter: F) {
+        self.filters.push(Arc::new(filter));
+    }
+
+    pub fn outgoing(&self, method: &str, request: &mut Vec<u8>) {
+        for filter in self.filters.iter() {
+            filter.outgoing(method, request);
+        }
+    }
+
+    pub fn incoming(&self, method: &str, reply: &mut Vec<u8>) {
+        for filter in self.filters.iter().rev() {
+            filter.incoming(method, reply);
+        }
+    }
+
+    pub fn headers(&self, method: &str, headers: &mut Headers) {
+        for filter in self.filters.iter() {
+            filter.headers(method, headers);
+        }
+    }
+
+    pub fn reply_headers(&self, method: &str, headers: &Headers) {
+        for filter in self.filters.iter().rev() {
+            filter.reply_headers(method, headers);
+        }
+    }
+
+    pub fn failed(&self, method: &str, err: &ThrustError) {
+        for filter in self.filters.iter().rev() {
+            filter.failed(method, err);
+        }
+    }
+
+    /// A call is retried if any of the filters asks for it.
+    pub fn retry(&self, method: &str, err: &ThrustError, attempts: usize) -> bool {
+        self.filters.iter().any(|filter| filter.retry(method, err, attempts))
+    }
+}
+
+lazy_static! {
+    static ref FILTERS: RwLock<Filters> = RwLock::new(Filters::new());
+}
+
+/// Install a filter on every client created from now on. Filters run in the order
+/// they're added.
+pub fn add_filter<F: 'static + Filter>(filter: F) {
+    FILTERS.write().expect("Failed to take the `FILTERS` lock.").push(filter);
+}
+
+/// The filters a new client starts with.
+pub fn filters() -> Filters {
+    FILTERS.read().expect("Failed to take the `FILTERS` lock.").clone()
+}
+
+impl fmt::Debug for Filters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Filters({})", self.filters.len())
+    }
+}
+
+/// Whether a call that failed with `err` might succeed if it's sent again.
+pub fn is_retryable(err: &ThrustError) -> bool {
+    match *err {
+        ThrustError::Disconnected => true,
+        ThrustError::Exception(ref exception) => {
+            exception.kind == ExceptionKind::Unknown || exception.kind == ExceptionKind::InternalError
+        },
+        _ => false
+    }
+}
+
+/// Retries calls that failed with a retryable error. Only methods that have been
+/// marked as idempotent are retried, since the server might have already run them.
+///
+/// Methods are marked here rather than in the IDL. Thrift has no standard annotation
+/// for idempotency and the parser doesn't read annotations, and a filter only ever sees
+/// method names, so a list of names is all it needs to decide.
+pub struct Retry {
+    max_attempts: usize,
+    idempotent: HashSet<String>
+}
+
+impl Retry {
+    /// Send a call at most `max_attempts` times in total.
+    pub fn new(max_attempts: usize) -> Retry {
+        Retry {
+            max_attempts: max_attempts,
+            idempotent: HashSet::new()
+        }
+    }
+
+    /// Mark `method` as safe to retry.
+    pub fn idempotent(mut self, method: &str) -> Retry {
+        self.idempotent.insert(method.to_string());
+        self
+    }
+}
+
+impl Filter for Retry {
+    fn retry(&self, method: &str, err: &ThrustError, attempts: usize) -> bool {
+        attempts < self.max_attempts && self.idempotent.contains(method) && is_retryable(err)
+    }
+}
+
+/// Logs every call and its outcome through the `log` facade, at the info level, and
+/// failures at the warn level.
+pub struct Logging;
+
+impl Filter for Logging {
+    fn outgoing(&self, method: &str, request: &mut Vec<u8>) {
+        info!("calling; method={} bytes={}", method, request.len());
+    }
+
+    fn incoming(&self, method: &str, reply: &mut Vec<u8>) {
+        info!("replied; method={} bytes={}", method, reply.len());
+    }
+
+    fn failed(&self, method: &str, err: &ThrustError) {
+        warn!("call failed; method={} err={:?}", method, err);
+    }
+}
+
+/// Counts calls and their outcomes. Add it as an `Arc<Stats>` to keep a handle on the
+/// numbers.
+#[derive(Default)]
+pub struct Stats {
+    sent: AtomicUsize,
+    replies: AtomicUsize,
+    failures: AtomicUsize
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    /// The number of requests sent, including retries.
+    pub fn sent(&self) -> usize {
+        self.sent.load(Ordering::SeqCst)
+    }
+
+    pub fn replies(&self) -> usize {
+        self.replies.load(Ordering::SeqCst)
+    }
+
+    pub fn failures(&self) -> usize {
+        self.failures.load(Ordering::SeqCst)
+    }
+}
+
+impl Filter for Stats {
+    fn outgoing(&self, method: &str, request: &mut Vec<u8>) {
+        self.sent.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn incoming(&self, method: &str, reply: &mut Vec<u8>) {
+        self.replies.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn failed(&self, method: &str, err: &ThrustError) {
+        self.failures.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// The name of the one-way message that tells a server a call was cancelled.
+pub const CANCEL: &'static str = "thrust.cancel";
+
+static SEQ: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The sequence id of a new call. Ids are shared by every client of the process, so a
+/// call's id is unique on its connection until they wrap around.
+pub fn next_seq() -> i32 {
+    (SEQ.fetch_add(1, Ordering::SeqCst) & 0x7FFFFFFF) as i32
+}
+
+/// Encode the message telling the server that the call to `method` with the sequence
+/// id `seq` was cancelled.
+pub fn cancel_request<P: Protocol>(protocol: &P, method: &str, seq: i32) -> Result<Vec<u8>, Error> {
+    let mut se = protocol.serializer();
+    se.write_message_begin_seq(CANCEL, ThriftMessageType::Oneway, seq)?;
+    se.serialize_str(method)?;
+    se.write_message_end()?;
+
+    Ok(protocol.finish(se))
+}
+
+/// Decode the name of the cancelled method, following the header of a cancel message.
+/// The sequence id of the cancelled call is the one in the header.
+pub fn read_cancel<D>(de: &mut D) -> Result<String, Error>
+    where D: Deserializer + ThriftDeserializer
+{
+    let method = de.deserialize_str()?;
+    de.read_message_end()?;
+    Ok(method)
+}
+
+/// Cancels an outstanding call of a client, found by its sequence id. The reply is
+/// dropped without being decoded, and the call's future never resolves.
+pub struct Cancel {
+    dispatcher: Sender<Incoming>,
+    seq: i32,
+    notify: bool
+}
+
+impl Cancel {
+    pub fn new(dispatcher: Sender<Incoming>, seq: i32) -> Cancel {
+        Cancel {
+            dispatcher: dispatcher,
+            seq: seq,
+            notify: false
+        }
+    }
+
+    /// Also tell the server about the cancellation, so its interceptors can stop any
+    /// work on the call.
+    pub fn notify_server(mut self) -> Cancel {
+        self.notify = true;
+        self
+    }
+
+    pub fn cancel(self) {
+        self.dispatcher.send(Incoming::Cancel(self.seq, self.notify));
+    }
+
+    /// Cancel the call once the returned guard is dropped, unless it's disarmed first.
+    pub fn guard(self) -> CancelGuard {
+        CancelGuard {
+            cancel: Some(self),
+            replied: Arc::new(AtomicBool::new(false))
+        }
+    }
+}
+
+/// Tie the cancellation of the call `seq` to a guard, given the future its reply
+/// resolves. The guard is disarmed once the reply arrives. This is what the
+/// `_cancellable` methods of generated clients return.
+pub fn cancellable(dispatcher: Sender<Incoming>, seq: i32, reply: Future<Reply>) -> (Future<Reply>, CancelGuard) {
+    let guard = Cancel::new(dispatcher, seq).guard();
+    let replied = guard.replied.clone();
+    let reply = reply.map(move |reply| {
+        replied.store(true, Ordering::SeqCst);
+        reply
+    });
+
+    (reply, guard)
+}
+
+/// Cancels a call when dropped, unless its reply has arrived or it's been disarmed.
+pub struct CancelGuard {
+    cancel: Option<Cancel>,
+    replied: Arc<AtomicBool>
+}
+
+impl CancelGuard {
+    /// Also tell the server about the cancellation. See `Cancel::notify_server`.
+    pub fn notify_server(mut self) -> CancelGuard {
+        self.cancel = self.cancel.take().map(Cancel::notify_server);
+        self
+    }
+
+    pub fn disarm(mut self) {
+        self.cancel.take();
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            if !self.replied.load(Ordering::SeqCst) {
+                cancel.cancel();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::io::Cursor;
+    use std::sync::mpsc::channel;
+    use tangle::Future;
+    use protocol::{ThriftDeserializer, ThriftMessage, ThriftMessageType};
+    use binary_protocol::{Binary, BinaryDeserializer};
+    use dispatcher::{Incoming, Reply};
+    use blocking;
+    use result::ThrustError;
+    use server::{Exception, ExceptionKind};
+
+    struct Tag(u8);
+
+    impl Filter for Tag {
+        fn outgoing(&self, method: &str, request: &mut Vec<u8>) {
+            request.push(self.0);
+        }
+
+        fn incoming(&self, method: &str, reply: &mut Vec<u8>) {
+            reply.push(self.0);
+        }
+    }
+
+    #[test]
+    fn should_run_filters_in_order() {
+        let mut filters = Filters::new();
+        filters.push(Tag(1));
+        filters.push(Tag(2));
+
+        let mut request = Vec::new();
+        filters.outgoing("foo", &mut request);
+        assert_eq!(request, vec![1, 2]);
+
+        let mut reply = Vec::new();
+        filters.incoming("foo", &mut reply);
+        assert_eq!(reply, vec![2, 1]);
+    }
+
+    #[test]
+    fn should_only_retry_idempotent_methods() {
+        let retry = Retry::new(3).idempotent("get");
+        let err = ThrustError::Exception(Exception::new(ExceptionKind::InternalError, "oops"));
+
+        assert!(retry.retry("get", &ThrustError::Disconnected, 1));
+        assert!(retry.retry("get", &err, 2));
+        assert!(!retry.retry("get", &err, 3));
+        assert!(!retry.retry("set", &err, 1));
+
+        let err = ThrustError::Exception(Exception::new(ExceptionKind::UnknownMethod, "nope"));
+        assert!(!retry.retry("get", &err, 1));
+    }
+
+    #[test]
+    fn should_count_calls() {
+        let stats = Arc::new(Stats::new());
+        let mut filters = Filters::new();
+        filters.push(stats.clone());
+
+        filters.outgoing("foo", &mut Vec::new());
+        filters.incoming("foo", &mut Vec::new());
+        filters.outgoing("foo", &mut Vec::new());
+        filters.failed("foo", &ThrustError::Disconnected);
+
+        assert_eq!(stats.sent(), 2);
+        assert_eq!(stats.replies(), 1);
+        assert_eq!(stats.failures(), 1);
+    }
+
+    #[test]
+    fn should_start_clients_with_the_installed_filters() {
+        // Only counts, so it doesn't get in the way of the clients of other tests.
+        let stats = Arc::new(Stats::new());
+        add_filter(stats.clone());
+
+        filters().outgoing("foo", &mut Vec::new());
+        assert!(stats.sent() >= 1);
+    }
+
+    #[test]
+    fn should_encode_cancel_request() {
+        let buf = cancel_request(&Binary, "foo", 7).unwrap();
+        let mut de = BinaryDeserializer::new(Cursor::new(buf));
+
+        let msg = de.read_message_begin().unwrap();
+        assert_eq!(&*msg.name, CANCEL);
+        assert_eq!((msg.ty, msg.seq), (ThriftMessageType::Oneway, 7));
+        assert_eq!(read_cancel(&mut de).unwrap(), "foo");
+    }
+
+    #[test]
+    fn should_cancel_on_drop() {
+        let (tx, rx) = channel();
+
+        Cancel::new(tx.clone(), 1).guard().disarm();
+        {
+            let _guard = Cancel::new(tx.clone(), 2).guard().notify_server();
+        }
+
+        match rx.try_recv() {
+            Ok(Incoming::Cancel(seq, notify)) => {
+                assert_eq!(seq, 2);
+                assert!(notify);
+            },
+            _ => panic!("[test]: Expected a cancellation.")
+        }
+
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn should_not_cancel_after_the_reply() {
+        let (tx, rx) = channel();
+        let (res, future) = Future::<Reply>::channel();
+        let (future, guard) = cancellable(tx, next_seq(), future);
+
+        let msg = ThriftMessage {
+            name: "foo".to_string(),
+            ty: ThriftMessageType::Reply,
+            seq: 0
+        };
+        res.send((msg, Vec::new())).unwrap();
+        blocking::wait(future).unwrap();
+
+        drop(guard);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn should_hand_out_distinct_sequence_ids() {
+        let a = next_seq();
+        let b = next_seq();
+        assert!(a != b);
+        assert!(a >= 0 && b >= 0);
+    }
+}
@@ -0,0 +1,107 @@
+use std::time::{Duration, Instant};
+use protocol::ThriftMessage;
+use reactor::Addr;
+use header::Headers;
+use tracing::TraceContext;
+
+/// The info header clients set to the number of milliseconds they're willing to wait
+/// for a reply, like fbthrift does.
+pub const TIMEOUT_HEADER: &'static str = "client_timeout";
+
+/// What a server knows about the call it's running, besides its arguments.
+///
+/// Services generated with the context option get it as the first argument of every
+/// method.
+#[derive(Debug, Clone)]
+pub struct Context {
+    /// The address of the peer that made the call. Unix sockets report the listener's
+    /// path, and in-process loopback connections have none.
+    pub peer: Option<Addr>,
+    pub name: String,
+    pub seq: i32,
+    /// The THeader info headers of the call. Calls without a THeader have none.
+    pub headers: Headers,
+    /// When the client stops waiting for the reply, if it said so.
+    pub deadline: Option<Instant>,
+    /// The trace the call is part of.
+    pub trace: Option<TraceContext>,
+    /// The common name of the certificate the client presented over TLS, for servers
+    /// that require one.
+    pub identity: Option<String>
+}
+
+impl Context {
+    /// The context of the call `msg`, received just now. The deadline is read from
+    /// `TIMEOUT_HEADER`, and the trace from the B3 headers.
+    pub fn new(peer: Option<Addr>, msg: &ThriftMessage, headers: Headers) -> Context {
+        let deadline = headers.get(TIMEOUT_HEADER)
+            .and_then(|timeout| timeout.parse::<u64>().ok())
+            .map(|ms| Instant::now() + Duration::from_millis(ms));
+
+        Context {
+            peer: peer,
+            name: msg.name.clone(),
+            seq: msg.seq,
+            trace: TraceContext::from_headers(&headers),
+            headers: headers,
+            deadline: deadline,
+            identity: None
+        }
+    }
+
+    /// The time left until the deadline, which is zero once it's passed.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| {
+            let now = Instant::now();
+            if deadline > now {
+                deadline - now
+            } else {
+                Duration::from_secs(0)
+            }
+        })
+    }
+
+    /// Whether the client has already given up on the reply.
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Some(Duration::from_secs(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use protocol::{ThriftMessage, ThriftMessageType};
+    use header::Headers;
+
+    fn msg() -> ThriftMessage {
+        ThriftMessage {
+            name: "foo".to_string(),
+            ty: ThriftMessageType::Call,
+            seq: 4
+        }
+    }
+
+    #[test]
+    fn should_read_the_deadline() {
+        let mut headers = Headers::new();
+        headers.insert(TIMEOUT_HEADER.to_string(), "60000".to_string());
+
+        let ctx = Context::new(None, &msg(), headers);
+        assert_eq!(&*ctx.name, "foo");
+        assert_eq!(ctx.seq, 4);
+        assert!(ctx.remaining().unwrap() > Duration::from_secs(50));
+        assert!(!ctx.is_expired());
+    }
+
+    #[test]
+    fn should_expire() {
+        let mut headers = Headers::new();
+        headers.insert(TIMEOUT_HEADER.to_string(), "0".to_string());
+        assert!(Context::new(None, &msg(), headers).is_expired());
+
+        let ctx = Context::new(None, &msg(), Headers::new());
+        assert_eq!(ctx.deadline, None);
+        assert!(!ctx.is_expired());
+    }
+}
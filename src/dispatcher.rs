@@ -7,25 +7,84 @@ use mio::{self, Token};
 use result::{ThrustResult, ThrustError};
 use tangle::{Future, Async};
 use std::io::Cursor;
+use std::mem;
+use std::time::{Duration, Instant};
 use protocol::*;
 use binary_protocol::*;
-use reactor::{self, Dispatch, Message, Id};
+use reactor::{self, Dispatch, Message, Id, Framing, Compression, Limits, Addr, Peer, Security};
 use util;
 use runner::Runner;
+use client::{self, Filters};
+use server::Exception;
+use breaker::{Breaker, State};
+use header::{self, Header};
+use tracing::{self, TraceContext, Started};
+use metrics::{self, Side};
 
 pub enum Role {
     /// A server will be tasked with actually calling a user defined
     /// RPC method and dispatching the response back to the event loop.
-    Server(SocketAddr, Sender<(Token, Vec<u8>)>),
+    ///
+    /// The `Limits` bound the number of connections and the amount of buffered data
+    /// the server will accept. Calls are sent on with the peer they came from.
+    Server(Addr, Framing, Limits, Sender<(Token, Peer, Vec<u8>)>),
     /// A client is tasked with sending an initial RPC and dispatching a response.
     ///
-    Client(SocketAddr)
+    Client(Addr, Framing)
+}
+
+impl Role {
+    /// The message that binds or connects the `Reactor` for this role.
+    fn message(&self, compression: Compression, security: &Security, id_tx: Sender<ThrustResult<Id>>,
+               data_tx: Sender<Dispatch>) -> Message {
+        match *self {
+            Role::Server(ref addr, framing, limits, _) => match addr.clone() {
+                Addr::Tcp(addr) => Message::Bind(addr, framing, compression, limits, security.clone(), id_tx, data_tx),
+                Addr::Unix(path) => Message::BindUnix(path, framing, compression, limits, id_tx, data_tx)
+            },
+            Role::Client(ref addr, framing) => match addr.clone() {
+                Addr::Tcp(addr) => Message::Connect(addr, framing, compression, security.clone(), id_tx, data_tx),
+                Addr::Unix(path) => Message::ConnectUnix(path, framing, compression, id_tx, data_tx)
+            }
+        }
+    }
+
+    fn addr(&self) -> &Addr {
+        match *self {
+            Role::Server(ref addr, _, _, _) | Role::Client(ref addr, _) => addr
+        }
+    }
 }
 
+/// The header of a reply and the whole reply, header included, still encoded so the
+/// client can decode it with its own protocol.
+pub type Reply = (ThriftMessage, Vec<u8>);
+
 pub enum Incoming {
-    /// Method name, data buf, and response channel.
-    Call(String, Vec<u8>, Option<Sender<(ThriftMessage, BinaryDeserializer<Cursor<Vec<u8>>>)>>),
+    /// Method name, the sequence id the call was encoded with, data buf, response
+    /// channel, and the trace of the caller, which the call continues. Replies are
+    /// matched up with their call by the sequence id, see `client::next_seq`.
+    Call(String, i32, Vec<u8>, Option<Sender<Reply>>, Option<TraceContext>),
     Reply(Token, Vec<u8>),
+    /// A request read from the connection won't be replied to. See `Message::Release`.
+    Release(Token),
+    /// Ask for the address the dispatcher is bound or connected to. The answer is sent
+    /// once the `Reactor` has handled the bind or connect.
+    LocalAddr(Sender<Addr>),
+    /// Guard the calls of a client with a circuit breaker. Calls made while it's open
+    /// fail with `ThrustError::CircuitOpen` without being sent.
+    Guard(Breaker),
+    /// Cancel the outstanding call with the sequence id, telling the server as well if
+    /// the flag is set.
+    Cancel(i32, bool),
+    /// Fail the calls of a client that haven't been replied to after the duration with
+    /// `ThrustError::Timeout`, which counts as a failure for its circuit breaker.
+    Deadline(Duration),
+    /// Run the calls of a client through the filters, instead of the ones installed
+    /// with `client::add_filter`.
+    Filters(Filters),
+    /// Name the service a client calls, which its metrics are recorded under.
+    Service(String),
     Shutdown
 }
 
@@ -34,109 +93,489 @@ pub enum Incoming {
 ///
 /// Dispatchers run in their own thread and only expose a channel interface. This makes it
 /// extremely easy to do multi-threading by simply cloning the dispatcher.
-pub struct Dispatcher {
+pub struct Dispatcher<P: Protocol> {
     role: Role,
     /// The connection token as used and exposed by the event loop. This is required
     /// to know where to send and receive Rpc calls.
     token: Token,
-    data_rx: Receiver<Dispatch>,
+    /// For servers, the address the listener is actually bound to.
+    addr: Addr,
+    /// Kept around to reconnect a client, whose new connection reports to the same
+    /// channel.
+    data_tx: Sender<Dispatch>,
     /// The channel to communicate with the event loop.
     event_loop: mio::Sender<Message>,
-    rx: Receiver<Incoming>,
     /// The response queue that is used to match up outgoing requests with future
-    /// responses. Each response has it's own sender channel.
-    queue: HashMap<String, Sender<(ThriftMessage, BinaryDeserializer<Cursor<Vec<u8>>>)>>
+    /// responses, by their sequence id.
+    queue: HashMap<i32, Pending>,
+    /// The client filters every call and reply goes through.
+    filters: Filters,
+    breaker: Option<Breaker>,
+    deadline: Option<Duration>,
+    /// The `Reactor` sends the sequence ids of calls whose deadline passed here.
+    deadlines: Sender<i32>,
+    /// Replies are decoded with it.
+    protocol: P,
+    /// The name of the service, for metrics.
+    service: String,
+    /// Kept around to set up the new connection of a reconnecting client the same way.
+    compression: Compression,
+    security: Security
 }
 
-impl Dispatcher {
-    pub fn spawn(role: Role) -> ThrustResult<(JoinHandle<ThrustResult<()>>, Sender<Incoming>)> {
+/// An outgoing call waiting on its reply. The request is kept as it was encoded, before
+/// the filters ran, so it can be retried.
+struct Pending {
+    method: String,
+    request: Vec<u8>,
+    attempts: usize,
+    /// When the call was last sent.
+    sent: Instant,
+    /// When the call was first sent.
+    called: Instant,
+    span: Option<Started>,
+    tx: Sender<Reply>
+}
+
+impl<P: Protocol> Dispatcher<P> {
+    /// Spawn a dispatcher and wait until its listener is bound or its connection is
+    /// established, which requires the `Reactor` to be running. Any error while binding
+    /// or connecting is returned here.
+    pub fn spawn(role: Role, protocol: P) -> ThrustResult<(JoinHandle<ThrustResult<()>>, Sender<Incoming>)> {
+        Dispatcher::spawn_with(role, Compression::default(), Security::Plain, protocol)
+    }
+
+    /// Like `spawn`, but the listener or connection is compressed and secured as
+    /// `compression` and `security` ask. Only TCP sockets can be encrypted.
+    pub fn spawn_with(role: Role, compression: Compression, security: Security, protocol: P)
+                      -> ThrustResult<(JoinHandle<ThrustResult<()>>, Sender<Incoming>)> {
+        Dispatcher::spawn_on(SENDER.clone(), role, compression, security, protocol)
+    }
+
+    /// Like `spawn_with`, but on the event loop `event_loop_sender` talks to instead of
+    /// the one `Reactor::run` starts.
+    fn spawn_on(event_loop_sender: mio::Sender<Message>, role: Role, compression: Compression, security: Security, protocol: P)
+                -> ThrustResult<(JoinHandle<ThrustResult<()>>, Sender<Incoming>)> {
+        match *role.addr() {
+            Addr::Unix(_) if !security.is_plain() => {
+                return Err(ThrustError::Str("Only TCP sockets can be secured.".to_string()));
+            },
+            _ => {}
+        }
+
         let (ret_tx, ret_rx) = channel();
         let handle = thread::spawn(move || {
             let (sender, receiver) = channel();
-            ret_tx.send(sender);
 
             let (id_tx, id_rx) = channel();
-            let event_loop_sender = SENDER.clone();
             let (data_tx, data_rx) = channel();
+            let (deadline_tx, deadline_rx) = channel();
 
-            match &role {
-                &Role::Server(addr, ref method_dispatch) => {
-                    event_loop_sender.send(Message::Bind(addr, id_tx, data_tx))?;
-                },
-                &Role::Client(addr) => {
-                    event_loop_sender.send(Message::Connect(addr, id_tx, data_tx))?;
+            event_loop_sender.send(role.message(compression, &security, id_tx, data_tx.clone()))?;
+
+            let Id(token, addr) = match id_rx.recv()? {
+                Ok(id) => id,
+                Err(err) => {
+                    // The error is handed to the caller of `spawn` instead.
+                    ret_tx.send(Err(err));
+                    return Ok(());
                 }
-            }
+            };
 
-            let Id(token) = id_rx.recv()?;
+            ret_tx.send(Ok(sender));
 
             Dispatcher {
                 role: role,
                 token: token,
-                data_rx: data_rx,
+                addr: addr,
+                data_tx: data_tx,
                 event_loop: event_loop_sender,
-                rx: receiver,
-                queue: HashMap::new()
-            }.run()
+                queue: HashMap::new(),
+                filters: client::filters(),
+                breaker: None,
+                deadline: None,
+                deadlines: deadline_tx,
+                service: String::new(),
+                protocol: protocol,
+                compression: compression,
+                security: security
+            }.run(receiver, data_rx, deadline_rx)
         });
 
-        Ok((handle, ret_rx.recv()?))
+        let sender = ret_rx.recv()??;
+        Ok((handle, sender))
     }
 
-    pub fn run(mut self) -> ThrustResult<()> {
-        let rx = self.rx;
-        let event_loop_rx = self.data_rx;
-
+    pub fn run(mut self, rx: Receiver<Incoming>, event_loop_rx: Receiver<Dispatch>, deadline_rx: Receiver<i32>) -> ThrustResult<()> {
         loop {
             select! {
                 user_msg = rx.recv() => {
                     match user_msg {
                         Ok(Incoming::Shutdown) => break,
-                        Ok(Incoming::Call(method, buf, tx)) => {
-                            self.event_loop.send(Message::Rpc(self.token, buf));
-                            match tx {
-                                Some(tx) => {
-                                    self.queue.insert(method, tx);
-                                },
-                                None => {}
-                            }
+                        Ok(Incoming::Call(method, seq, buf, tx, parent)) => {
+                            self.call(method, seq, buf, tx, parent);
                         },
                         Ok(Incoming::Reply(token, buf)) => {
                             self.event_loop.send(Message::Rpc(token, buf));
                         },
+                        Ok(Incoming::Release(token)) => {
+                            self.event_loop.send(Message::Release(token));
+                        },
+                        Ok(Incoming::LocalAddr(tx)) => {
+                            tx.send(self.addr.clone());
+                        },
+                        Ok(Incoming::Guard(breaker)) => {
+                            self.breaker = Some(breaker);
+                        },
+                        Ok(Incoming::Cancel(seq, notify)) => {
+                            self.cancel(seq, notify);
+                        },
+                        Ok(Incoming::Deadline(deadline)) => {
+                            self.deadline = Some(deadline);
+                        },
+                        Ok(Incoming::Filters(filters)) => {
+                            self.filters = filters;
+                        },
+                        Ok(Incoming::Service(service)) => {
+                            self.service = service;
+                        },
                         // The sender-part of the channel has been disconnected.
                         Err(err) => break
                     }
                 },
                 event_loop_msg = event_loop_rx.recv() => {
                     match event_loop_msg {
-                        Ok(Dispatch::Data(token, buf)) => {
-                            println!("[dispatcher]: reading data from {:?}", token);
+                        Ok(Dispatch::Data(token, peer, buf)) => {
+                            trace!("reading data; token={:?} peer={:?}", token, peer);
                             match self.role {
                                 // Received an RPC call
-                                Role::Server(_, ref sender) => {
-                                    try!(sender.send((token, buf)));
+                                Role::Server(_, _, _, ref sender) => {
+                                    try!(sender.send((token, peer, buf)));
                                 },
                                 // Received a reply RPC call
-                                Role::Client(_) => {
-                                    let mut de = BinaryDeserializer::new(Cursor::new(buf));
-                                    let msg = de.read_message_begin()?;
-
-                                    match self.queue.remove(&msg.name) {
-                                        Some(tx) => {
-                                            println!("[dispatcher/client]: reply received.");
-                                            tx.send((msg, de))?;
-                                        },
-                                        None => { println!("Cannot find {:?} method name", msg.name); }
-                                    }
+                                Role::Client(..) => {
+                                    self.reply(buf);
+                                }
+                            }
+                        },
+                        Ok(Dispatch::Closed(token)) => {
+                            debug!("connection closed; token={:?}", token);
+                            match self.role {
+                                // Other connections to the server aren't affected.
+                                Role::Server(..) => {},
+                                Role::Client(..) => {
+                                    self.closed()?;
                                 }
                             }
                         },
                         Err(err) => break
                     }
+                },
+                deadline_msg = deadline_rx.recv() => {
+                    // We hold on to the sender, so this can't fail.
+                    if let Ok(seq) = deadline_msg {
+                        self.expire(seq);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send a call from the user. Calls without a reply channel are sent and
+    /// forgotten.
+    fn call(&mut self, method: String, seq: i32, buf: Vec<u8>, tx: Option<Sender<Reply>>, parent: Option<TraceContext>) {
+        metrics::started(Side::Client, &self.service, &method);
+        let called = Instant::now();
+
+        let allowed = match self.breaker {
+            Some(ref breaker) if tx.is_some() => breaker.allow(),
+            // One-way calls have no outcome to record, so they aren't used as trial calls.
+            Some(ref breaker) if breaker.state() == State::Open => Err(ThrustError::CircuitOpen),
+            _ => Ok(())
+        };
+
+        if let Err(err) = allowed {
+            // Dropping the reply channel fails the call.
+            self.filters.failed(&method, &err);
+            self.finish(&method, called, &mut None, Some(&err));
+            return;
+        }
+
+        let mut span = tracing::start_client(parent, &method);
+
+        match tx {
+            Some(tx) => {
+                if let Some(deadline) = self.deadline {
+                    self.event_loop.send(Message::Deadline(deadline, seq, self.deadlines.clone()));
+                }
+
+                self.send(seq, Pending {
+                    method: method,
+                    request: buf,
+                    attempts: 0,
+                    sent: called,
+                    called: called,
+                    span: span,
+                    tx: tx
+                });
+            },
+            None => {
+                match self.encode(&method, buf, span.as_ref().map(Started::trace)) {
+                    Ok(buf) => {
+                        self.event_loop.send(Message::Rpc(self.token, buf));
+                        self.finish(&method, called, &mut span, None);
+                    },
+                    Err(err) => {
+                        self.filters.failed(&method, &err);
+                        self.finish(&method, called, &mut span, Some(&err));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Forget about an outstanding call, so its reply is dropped once it arrives.
+    fn cancel(&mut self, seq: i32, notify: bool) {
+        // The reply might have arrived already.
+        let pending = match self.queue.remove(&seq) {
+            Some(pending) => pending,
+            None => return
+        };
+
+        let method = pending.method.clone();
+        debug!("cancelled a call; method={} seq={}", method, seq);
+        let err = ThrustError::Cancelled;
+        self.record(&pending, Some(&err));
+        self.failed(pending, &err);
+
+        if notify {
+            let buf = client::cancel_request(&self.protocol, &method, seq)
+                .map_err(ThrustError::from)
+                .and_then(|buf| self.wrap(&method, buf, None));
+
+            match buf {
+                Ok(buf) => { self.event_loop.send(Message::Rpc(self.token, buf)); },
+                Err(err) => warn!("error encoding a cancellation; method={} err={:?}", method, err)
+            }
+        }
+    }
+
+    /// The deadline of a call passed. Unless its reply arrived in the meantime, the
+    /// call fails.
+    fn expire(&mut self, seq: i32) {
+        let pending = match self.queue.remove(&seq) {
+            Some(pending) => pending,
+            None => return
+        };
+
+        warn!("call timed out; method={} seq={}", pending.method, seq);
+        let err = ThrustError::Timeout;
+        self.record(&pending, Some(&err));
+        self.failed(pending, &err);
+    }
+
+    /// Tell the circuit breaker, if there is one, how a call went.
+    fn record(&self, pending: &Pending, err: Option<&ThrustError>) {
+        if let Some(ref breaker) = self.breaker {
+            match err {
+                // A cancelled call says nothing about the server, but it might have
+                // been a trial call.
+                Some(&ThrustError::Cancelled) => breaker.release(),
+                // Only errors that hint at a struggling server count.
+                Some(&ThrustError::Timeout) => breaker.failure(),
+                Some(err) if client::is_retryable(err) => breaker.failure(),
+                _ => breaker.success(pending.sent.elapsed())
+            }
+        }
+    }
+
+    /// A call failed for good.
+    fn failed(&self, mut pending: Pending, err: &ThrustError) {
+        self.filters.failed(&pending.method, err);
+        self.finish(&pending.method, pending.called, &mut pending.span, Some(err));
+    }
+
+    /// Record the outcome of a call, made at `called`, in the metrics and its span.
+    fn finish(&self, method: &str, called: Instant, span: &mut Option<Started>, err: Option<&ThrustError>) {
+        metrics::finished(Side::Client, &self.service, method, called.elapsed(), err.is_some());
+        if let Some(span) = span.take() {
+            span.finish(err.map(|err| format!("{:?}", err)));
+        }
+    }
+
+    /// Run a request through the filters and wrap it for the connection, which is done
+    /// again for every attempt.
+    fn encode(&self, method: &str, mut buf: Vec<u8>, trace: Option<&TraceContext>) -> ThrustResult<Vec<u8>> {
+        self.filters.outgoing(method, &mut buf);
+        self.wrap(method, buf, trace)
+    }
+
+    /// Prefix an outgoing call with a THeader if the connection uses one. The filters
+    /// add the info headers, and `trace` the B3 ones.
+    fn wrap(&self, method: &str, buf: Vec<u8>, trace: Option<&TraceContext>) -> ThrustResult<Vec<u8>> {
+        match self.role {
+            Role::Client(_, Framing::Header) => {
+                let mut header = Header::new(self.protocol.header_id());
+                if let Some(trace) = trace {
+                    trace.to_headers(&mut header.info);
                 }
+
+                self.filters.headers(method, &mut header.info);
+                header::wrap(&header, &buf)
+            },
+            _ => Ok(buf)
+        }
+    }
+
+    /// Send a call, keeping it queued until its reply arrives.
+    fn send(&mut self, seq: i32, mut pending: Pending) {
+        let buf = {
+            let trace = pending.span.as_ref().map(Started::trace);
+            self.encode(&pending.method, pending.request.clone(), trace)
+        };
+
+        match buf {
+            Ok(buf) => {
+                pending.attempts += 1;
+                pending.sent = Instant::now();
+                self.event_loop.send(Message::Rpc(self.token, buf));
+                self.queue.insert(seq, pending);
+            },
+            Err(err) => {
+                // The call was never sent, so there's no outcome for the breaker.
+                if let Some(ref breaker) = self.breaker {
+                    breaker.release();
+                }
+
+                self.failed(pending, &err);
             }
         }
+    }
+
+    /// Hand a reply to the call waiting on it, or retry the call if the server replied
+    /// with an exception the filters consider retryable. A malformed reply is dropped,
+    /// and fails the call it belongs to if that can be told, without taking the rest of
+    /// the connection's calls down with it.
+    fn reply(&mut self, buf: Vec<u8>) {
+        let (header, mut buf) = match header::unwrap(buf) {
+            Ok(unwrapped) => unwrapped,
+            Err(err) => {
+                warn!("dropping a reply with a malformed THeader; err={:?}", err);
+                return;
+            }
+        };
+
+        let msg = match self.protocol.read_message(&buf) {
+            Ok(msg) => msg,
+            Err(err) => {
+                warn!("dropping a malformed reply; err={:?}", err);
+                return;
+            }
+        };
+
+        let mut pending = match self.queue.remove(&msg.seq) {
+            Some(pending) => pending,
+            None => {
+                warn!("dropping a reply no call is waiting on; method={} seq={}", msg.name, msg.seq);
+                return;
+            }
+        };
+
+        if let Some(ref header) = header {
+            self.filters.reply_headers(&pending.method, &header.info);
+        }
+
+        let msg = if msg.ty == ThriftMessageType::Exception {
+            let err = match self.read_exception(buf.clone()) {
+                Ok(exception) => ThrustError::Exception(exception),
+                Err(err) => err
+            };
+            self.record(&pending, Some(&err));
+
+            if self.filters.retry(&pending.method, &err, pending.attempts) {
+                info!("retrying a call; method={} attempts={} err={:?}", pending.method, pending.attempts, err);
+                self.send(msg.seq, pending);
+                return;
+            }
+
+            self.filters.failed(&pending.method, &err);
+            self.finish(&pending.method, pending.called, &mut pending.span, Some(&err));
+            msg
+        } else {
+            self.record(&pending, None);
+            self.filters.incoming(&pending.method, &mut buf);
+
+            // The filters might have rewritten the reply.
+            match self.protocol.read_message(&buf) {
+                Ok(msg) => {
+                    self.finish(&pending.method, pending.called, &mut pending.span, None);
+                    msg
+                },
+                Err(err) => {
+                    warn!("dropping a reply the filters broke; method={} err={:?}", pending.method, err);
+                    self.failed(pending, &ThrustError::from(err));
+                    return;
+                }
+            }
+        };
+
+        trace!("reply received; method={} seq={}", pending.method, msg.seq);
+        if pending.tx.send((msg, buf)).is_err() {
+            debug!("dropping a reply its caller no longer waits for; method={}", pending.method);
+        }
+    }
+
+    /// Read the exception a server replied with.
+    fn read_exception(&self, buf: Vec<u8>) -> ThrustResult<Exception> {
+        let mut de = self.protocol.deserializer(buf);
+        de.read_message_begin()?;
+        Ok(Exception::read(&mut de)?)
+    }
+
+    /// Our only connection is gone. Calls the filters want retried are sent again on a
+    /// new connection. Otherwise the client shuts down, and dropping the queue drops
+    /// the reply channels of any outstanding calls.
+    fn closed(&mut self) -> ThrustResult<()> {
+        let err = ThrustError::Disconnected;
+        let queue = mem::replace(&mut self.queue, HashMap::new());
+        let mut retries = Vec::new();
+
+        for (seq, pending) in queue.into_iter() {
+            self.record(&pending, Some(&err));
+            if self.filters.retry(&pending.method, &err, pending.attempts) {
+                retries.push((seq, pending));
+            } else {
+                self.failed(pending, &err);
+            }
+        }
+
+        if retries.is_empty() {
+            return Err(err);
+        }
+
+        let (id_tx, id_rx) = channel();
+        self.event_loop.send(self.role.message(self.compression, &self.security, id_tx, self.data_tx.clone()))?;
+
+        let Id(token, addr) = match id_rx.recv()? {
+            Ok(id) => id,
+            Err(reconnect_err) => {
+                warn!("failed to reconnect; addr={:?} err={:?}", self.addr, reconnect_err);
+                for (_, pending) in retries {
+                    self.failed(pending, &err);
+                }
+
+                return Err(err);
+            }
+        };
+
+        self.token = token;
+        self.addr = addr;
+
+        for (seq, pending) in retries {
+            self.send(seq, pending);
+        }
 
         Ok(())
     }
@@ -147,56 +586,113 @@ mod tests {
     use super::*;
     use tangle::{Future, Async};
     use std::net::SocketAddr;
-    use std::io::Cursor;
-    use reactor::{Reactor, Message};
-    use event_loop::SENDER;
+    use mio::EventLoop;
+    use reactor::{Reactor, Message, Framing, Limits};
     use protocol::{ThriftMessage, ThriftMessageType};
-    use binary_protocol::BinaryDeserializer;
+    use binary_protocol::Binary;
     use std::sync::mpsc::channel;
     use util;
     use std::thread;
-    use std::time::Duration;
+
+    /// The address a server dispatcher bound to port 0 actually listens on.
+    fn local_addr(dispatcher: &Sender<Incoming>) -> Addr {
+        let (tx, rx) = channel();
+        dispatcher.send(Incoming::LocalAddr(tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
+    /// An event loop of the test's own, so tests don't shut down each other's. Send it
+    /// `Message::Shutdown` and join the handle to stop it.
+    fn reactor() -> (mio::Sender<Message>, JoinHandle<()>) {
+        let mut reactor = Reactor::new();
+        let mut event_loop = EventLoop::new().expect("[test]: EventLoop failed to create.");
+        let sender = event_loop.channel();
+
+        let handle = thread::spawn(move || {
+            event_loop.run(&mut reactor);
+        });
+
+        (sender, handle)
+    }
+
+    fn spawn(sender: &mio::Sender<Message>, role: Role) -> ThrustResult<(JoinHandle<ThrustResult<()>>, Sender<Incoming>)> {
+        Dispatcher::spawn_on(sender.clone(), role, Compression::default(), Security::Plain, Binary)
+    }
 
     #[test]
     fn should_create_server_dispatcher() {
-        let addr = "127.0.0.1:5495".parse().unwrap();
+        let (sender, reactor) = reactor();
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
         let (tx, rx) = channel();
-        let (handle, tx) = Dispatcher::spawn(Role::Server(addr, tx)).unwrap();
+        let (handle, tx) = spawn(&sender, Role::Server(addr.into(), Framing::Framed, Limits::default(), tx)).unwrap();
+        let addr = local_addr(&tx);
+
+        // The address is already in use.
+        let (tx, rx) = channel();
+        assert!(spawn(&sender, Role::Server(addr, Framing::Framed, Limits::default(), tx)).is_err());
+
+        sender.send(Message::Shutdown);
+        reactor.join().unwrap();
     }
 
     #[test]
     fn should_start_server() {
-        let addr: SocketAddr = "127.0.0.1:5955".parse().unwrap();
+        let (sender, reactor) = reactor();
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
         let (method_dispatch_tx, method_dispatch_rx) = channel();
-        let (handle_server, server) = Dispatcher::spawn(Role::Server(addr.clone(), method_dispatch)).unwrap();
-        thread::sleep(Duration::from_millis(30));
-        let (handle_client, client) = Dispatcher::spawn(Role::Client(addr.clone())).unwrap();
+        let (handle_server, server) = spawn(&sender, Role::Server(addr.into(), Framing::Framed, Limits::default(), method_dispatch_tx)).unwrap();
+        let addr = local_addr(&server);
+        let (handle_client, client) = spawn(&sender, Role::Client(addr, Framing::Framed)).unwrap();
 
         let buf = util::create_empty_thrift_message("foobar123", ThriftMessageType::Call);
+        let (res, future) = Future::<Reply>::channel();
+        client.send(Incoming::Call("foobar123".to_string(), 0, buf, Some(res), None)).unwrap();
 
-        let (res, future) = Future::<(ThriftMessage, BinaryDeserializer<Cursor<Vec<u8>>>)>::channel();
-        client.send(Incoming::Call("foobar123".to_string(), buf, Some(res))).unwrap();
+        let (token, _, buf) = method_dispatch_rx.recv().unwrap();
+        assert_eq!(Binary.read_message(&buf).unwrap().name, "foobar123");
+        server.send(Incoming::Reply(token, util::create_empty_thrift_message("foobar123", ThriftMessageType::Reply))).unwrap();
 
         let (res_tx, res_rx) = channel();
-        let cloned = res_tx.clone();
-        future.and_then(move |(msg, de)| {
-            println!("[test]: Received: {:?}", msg);
-            SENDER.clone().send(Message::Shutdown);
-            res_tx.send(0);
+        future.and_then(move |(msg, _)| {
+            res_tx.send(msg);
             Async::Ok(())
         });
 
-        // Ensure that the test exists after at least 3 seconds if the response was not
-        // received.
-        thread::spawn(move || -> Result<(), ()> {
-            thread::sleep(Duration::from_millis(3000));
-            SENDER.clone().send(Message::Shutdown);
-            panic!("Test timeout was hit. This means the Reactor did not shutdown and a response was not received.");
-            cloned.send(1);
+        let msg = res_rx.recv().unwrap();
+        assert_eq!((&*msg.name, msg.ty), ("foobar123", ThriftMessageType::Reply));
+
+        sender.send(Message::Shutdown);
+        reactor.join().unwrap();
+    }
+
+    #[test]
+    fn should_survive_malformed_replies() {
+        let (sender, reactor) = reactor();
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let (method_dispatch_tx, method_dispatch_rx) = channel();
+        let (handle_server, server) = spawn(&sender, Role::Server(addr.into(), Framing::Framed, Limits::default(), method_dispatch_tx)).unwrap();
+        let addr = local_addr(&server);
+        let (handle_client, client) = spawn(&sender, Role::Client(addr, Framing::Framed)).unwrap();
+
+        let buf = util::create_empty_thrift_message("foobar123", ThriftMessageType::Call);
+        let (res, future) = Future::<Reply>::channel();
+        client.send(Incoming::Call("foobar123".to_string(), 0, buf, Some(res), None)).unwrap();
+
+        // Garbage first, and then the real reply on the same connection.
+        let (token, _, _) = method_dispatch_rx.recv().unwrap();
+        server.send(Incoming::Reply(token, vec![0, 1, 2, 3])).unwrap();
+        server.send(Incoming::Reply(token, util::create_empty_thrift_message("foobar123", ThriftMessageType::Reply))).unwrap();
+
+        let (res_tx, res_rx) = channel();
+        future.and_then(move |(msg, _)| {
+            res_tx.send(msg);
+            Async::Ok(())
         });
 
-        Reactor::run().join();
+        let msg = res_rx.recv().unwrap();
+        assert_eq!((&*msg.name, msg.ty), ("foobar123", ThriftMessageType::Reply));
 
-        assert_eq!(res_rx.recv().unwrap(), 0);
+        sender.send(Message::Shutdown);
+        reactor.join().unwrap();
     }
 }
@@ -0,0 +1,458 @@
+//! The THeader format used by fbthrift and Finagle. Every message is framed like with
+//! `Framing::Framed`, but starts with a header carrying the protocol the payload is
+//! encoded with, the transforms (like zlib) applied to it, and string key/value info
+//! headers.
+//!
+//! ```notrust
+//! magic (0x0fff, 2 bytes) | flags (2 bytes) | sequence id (4 bytes)
+//! header size / 4 (2 bytes) | header | payload
+//! ```
+//!
+//! Connections using `Framing::Header` also accept plain framed messages, and reply to
+//! them in kind.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use result::{ThrustResult, ThrustError};
+use reactor::Limits;
+#[cfg(feature = "zstd")]
+use zstd;
+#[cfg(feature = "lz4")]
+use lz4;
+
+pub const HEADER_MAGIC: u16 = 0x0fff;
+
+/// The protocol id of binary payloads.
+pub const BINARY_PROTOCOL: u32 = 0;
+
+const ZLIB_TRANSFORM: u32 = 1;
+/// The id fbthrift uses.
+#[cfg(feature = "zstd")]
+const ZSTD_TRANSFORM: u32 = 5;
+/// Not a standard id, so only thrust peers understand it.
+#[cfg(feature = "lz4")]
+const LZ4_TRANSFORM: u32 = 0x100;
+const INFO_PADDING: u32 = 0;
+const INFO_KEYVALUE: u32 = 1;
+
+/// String key/value info headers.
+pub type Headers = HashMap<String, String>;
+
+/// A transform applied to the payload of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    Zlib,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "lz4")]
+    Lz4
+}
+
+impl Transform {
+    fn id(&self) -> u32 {
+        match *self {
+            Transform::Zlib => ZLIB_TRANSFORM,
+            #[cfg(feature = "zstd")]
+            Transform::Zstd => ZSTD_TRANSFORM,
+            #[cfg(feature = "lz4")]
+            Transform::Lz4 => LZ4_TRANSFORM
+        }
+    }
+
+    fn from_id(id: u32) -> ThrustResult<Transform> {
+        match id {
+            ZLIB_TRANSFORM => Ok(Transform::Zlib),
+            #[cfg(feature = "zstd")]
+            ZSTD_TRANSFORM => Ok(Transform::Zstd),
+            #[cfg(feature = "lz4")]
+            LZ4_TRANSFORM => Ok(Transform::Lz4),
+            id => Err(ThrustError::Str(format!("Unsupported THeader transform {}.", id)))
+        }
+    }
+
+    fn apply(&self, data: &[u8]) -> ThrustResult<Vec<u8>> {
+        match *self {
+            Transform::Zlib => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            },
+            #[cfg(feature = "zstd")]
+            Transform::Zstd => Ok(zstd::encode_all(data, 0)?),
+            #[cfg(feature = "lz4")]
+            Transform::Lz4 => {
+                let mut encoder = lz4::EncoderBuilder::new().build(Vec::new())?;
+                encoder.write_all(data)?;
+                let (buf, res) = encoder.finish();
+                res?;
+                Ok(buf)
+            }
+        }
+    }
+
+    /// Undo the transform, failing once the result grows past `limit` bytes instead of
+    /// inflating whatever the peer sent.
+    fn undo(&self, data: &[u8], limit: usize) -> ThrustResult<Vec<u8>> {
+        // Reading one byte past the limit tells a payload that's too large from one
+        // that's exactly at it.
+        let take = limit as u64 + 1;
+        let mut buf = Vec::new();
+        match *self {
+            Transform::Zlib => {
+                ZlibDecoder::new(data).take(take).read_to_end(&mut buf)?;
+            },
+            #[cfg(feature = "zstd")]
+            Transform::Zstd => {
+                zstd::Decoder::new(data)?.take(take).read_to_end(&mut buf)?;
+            },
+            #[cfg(feature = "lz4")]
+            Transform::Lz4 => {
+                lz4::Decoder::new(data)?.take(take).read_to_end(&mut buf)?;
+            }
+        }
+
+        if buf.len() > limit {
+            return Err(ThrustError::Str(format!("A THeader payload is larger than the limit of {} bytes once decompressed.", limit)));
+        }
+
+        Ok(buf)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub flags: u16,
+    pub seq: u32,
+    /// The id of the protocol the payload is encoded with.
+    pub protocol: u32,
+    /// Applied to the payload in order when writing, and undone in reverse when reading.
+    pub transforms: Vec<Transform>,
+    pub info: Headers
+}
+
+impl Header {
+    pub fn new(protocol: u32) -> Header {
+        Header {
+            flags: 0,
+            seq: 0,
+            protocol: protocol,
+            transforms: Vec::new(),
+            info: Headers::new()
+        }
+    }
+
+    /// The header of a reply to a message with this header. The reply uses the same
+    /// protocol and transforms, without any info headers.
+    pub fn reply(&self) -> Header {
+        Header {
+            flags: self.flags,
+            seq: self.seq,
+            protocol: self.protocol,
+            transforms: self.transforms.clone(),
+            info: Headers::new()
+        }
+    }
+}
+
+/// Whether the frame starts with a THeader.
+pub fn is_header(frame: &[u8]) -> bool {
+    frame.len() >= 2 && BigEndian::read_u16(&frame[..2]) == HEADER_MAGIC
+}
+
+/// Split a frame into its header and the payload with its transforms undone. Frames
+/// without a header are plain messages and are returned as they are.
+///
+/// Payloads are capped at the default `Limits::max_frame_size` once decompressed. The
+/// frames of a `Framing::Header` connection have been decompressed under its own
+/// limits by the time they're unwrapped.
+pub fn unwrap(frame: Vec<u8>) -> ThrustResult<(Option<Header>, Vec<u8>)> {
+    if !is_header(&frame) {
+        return Ok((None, frame));
+    }
+
+    let (header, offset) = parse(&frame)?;
+    let payload = undo_transforms(&header, &frame[offset..], Limits::default().max_frame_size)?;
+    Ok((Some(header), payload))
+}
+
+/// Compress the payload of a THeader frame with `transform`, if it's at least
+/// `threshold` bytes long. Plain frames and frames that already have a transform are
+/// returned as they are.
+pub fn compress(frame: Vec<u8>, transform: Transform, threshold: usize) -> ThrustResult<Vec<u8>> {
+    if !is_header(&frame) {
+        return Ok(frame);
+    }
+
+    let (mut header, offset) = parse(&frame)?;
+    if !header.transforms.is_empty() || frame.len() - offset < threshold {
+        return Ok(frame);
+    }
+
+    header.transforms.push(transform);
+    wrap(&header, &frame[offset..])
+}
+
+/// Undo the transforms of a THeader frame, keeping its header. The first transform is
+/// returned along with the frame, which is how the peer compressed it. Payloads that
+/// grow past `limit` bytes are an error.
+pub fn decompress(frame: Vec<u8>, limit: usize) -> ThrustResult<(Vec<u8>, Option<Transform>)> {
+    if !is_header(&frame) {
+        return Ok((frame, None));
+    }
+
+    let (mut header, offset) = parse(&frame)?;
+    let transform = match header.transforms.first() {
+        Some(&transform) => transform,
+        None => return Ok((frame, None))
+    };
+
+    let payload = undo_transforms(&header, &frame[offset..], limit)?;
+    header.transforms.clear();
+    Ok((wrap(&header, &payload)?, Some(transform)))
+}
+
+fn undo_transforms(header: &Header, data: &[u8], limit: usize) -> ThrustResult<Vec<u8>> {
+    let mut payload = data.to_vec();
+    for transform in header.transforms.iter().rev() {
+        payload = transform.undo(&payload, limit)?;
+    }
+
+    Ok(payload)
+}
+
+/// Read the header at the start of a THeader frame, along with where its payload
+/// starts.
+fn parse(frame: &[u8]) -> ThrustResult<(Header, usize)> {
+    if frame.len() < 10 {
+        return Err(ThrustError::Str("The THeader is truncated.".to_string()));
+    }
+
+    let flags = BigEndian::read_u16(&frame[2..4]);
+    let seq = BigEndian::read_u32(&frame[4..8]);
+    let size = BigEndian::read_u16(&frame[8..10]) as usize * 4;
+    if frame.len() < 10 + size {
+        return Err(ThrustError::Str("The THeader is truncated.".to_string()));
+    }
+
+    let mut rd = &frame[10..10 + size];
+    let mut header = Header::new(read_varint(&mut rd)?);
+    header.flags = flags;
+    header.seq = seq;
+
+    for _ in 0..read_varint(&mut rd)? {
+        header.transforms.push(Transform::from_id(read_varint(&mut rd)?)?);
+    }
+
+    while !rd.is_empty() {
+        match read_varint(&mut rd)? {
+            INFO_KEYVALUE => {
+                for _ in 0..read_varint(&mut rd)? {
+                    let key = read_string(&mut rd)?;
+                    let value = read_string(&mut rd)?;
+                    header.info.insert(key, value);
+                }
+            },
+            // Only padding is left.
+            INFO_PADDING => break,
+            id => return Err(ThrustError::Str(format!("Unsupported THeader info type {}.", id)))
+        }
+    }
+
+    Ok((header, 10 + size))
+}
+
+/// Prefix the payload with `header`, applying its transforms. The result still has to
+/// be framed.
+pub fn wrap(header: &Header, payload: &[u8]) -> ThrustResult<Vec<u8>> {
+    let mut data = payload.to_vec();
+    for transform in header.transforms.iter() {
+        data = transform.apply(&data)?;
+    }
+
+    let mut head = Vec::new();
+    write_varint(&mut head, header.protocol);
+    write_varint(&mut head, header.transforms.len() as u32);
+    for transform in header.transforms.iter() {
+        write_varint(&mut head, transform.id());
+    }
+
+    if !header.info.is_empty() {
+        write_varint(&mut head, INFO_KEYVALUE);
+        write_varint(&mut head, header.info.len() as u32);
+        for (key, value) in header.info.iter() {
+            write_string(&mut head, key);
+            write_string(&mut head, value);
+        }
+    }
+
+    // The header's size is counted in words.
+    while head.len() % 4 != 0 {
+        head.push(0);
+    }
+
+    if head.len() / 4 > u16::max_value() as usize {
+        return Err(ThrustError::Str("The THeader is too large.".to_string()));
+    }
+
+    let mut buf = Vec::with_capacity(10 + head.len() + data.len());
+    buf.write_u16::<BigEndian>(HEADER_MAGIC)?;
+    buf.write_u16::<BigEndian>(header.flags)?;
+    buf.write_u32::<BigEndian>(header.seq)?;
+    buf.write_u16::<BigEndian>((head.len() / 4) as u16)?;
+    buf.extend_from_slice(&head);
+    buf.extend_from_slice(&data);
+    Ok(buf)
+}
+
+fn read_varint(rd: &mut &[u8]) -> ThrustResult<u32> {
+    let mut val = 0u32;
+    let mut shift = 0;
+
+    loop {
+        if rd.is_empty() || shift > 28 {
+            return Err(ThrustError::Str("Malformed varint in the THeader.".to_string()));
+        }
+
+        let byte = rd[0];
+        *rd = &rd[1..];
+        val |= ((byte & 0x7f) as u32) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(val);
+        }
+
+        shift += 7;
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut val: u32) {
+    while val >= 0x80 {
+        buf.push((val as u8 & 0x7f) | 0x80);
+        val >>= 7;
+    }
+
+    buf.push(val as u8);
+}
+
+fn read_string(rd: &mut &[u8]) -> ThrustResult<String> {
+    let len = read_varint(rd)? as usize;
+    if rd.len() < len {
+        return Err(ThrustError::Str("The THeader is truncated.".to_string()));
+    }
+
+    let val = String::from_utf8(rd[..len].to_vec())
+        .map_err(|_| ThrustError::Str("THeader info isn't valid UTF-8.".to_string()))?;
+    *rd = &rd[len..];
+    Ok(val)
+}
+
+fn write_string(buf: &mut Vec<u8>, val: &str) {
+    write_varint(buf, val.len() as u32);
+    buf.extend_from_slice(val.as_bytes());
+}
+
+thread_local! {
+    static REQUEST_HEADERS: RefCell<Headers> = RefCell::new(Headers::new())
+}
+
+/// The info headers of the request being run on this thread. Server handlers and
+/// interceptors can use it, since they're run on the worker thread that took the
+/// request. Requests without a THeader have none.
+pub fn request_headers() -> Headers {
+    REQUEST_HEADERS.with(|headers| headers.borrow().clone())
+}
+
+/// Run `f` with `headers` as the request headers of this thread.
+pub fn with_request_headers<F, T>(headers: Headers, f: F) -> T
+    where F: FnOnce() -> T
+{
+    REQUEST_HEADERS.with(|current| *current.borrow_mut() = headers);
+    let ret = f();
+    REQUEST_HEADERS.with(|current| current.borrow_mut().clear());
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{read_varint, write_varint};
+
+    #[test]
+    fn should_round_trip_varints() {
+        for &val in [0, 1, 127, 128, 300, u32::max_value()].iter() {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, val);
+            assert_eq!(read_varint(&mut &buf[..]).unwrap(), val);
+        }
+    }
+
+    #[test]
+    fn should_round_trip_headers() {
+        let mut header = Header::new(BINARY_PROTOCOL);
+        header.seq = 7;
+        header.transforms.push(Transform::Zlib);
+        header.info.insert("trace".to_string(), "abc".to_string());
+
+        let payload = vec![1u8; 100];
+        let frame = wrap(&header, &payload).unwrap();
+        assert!(is_header(&frame));
+
+        let (decoded, decoded_payload) = unwrap(frame).unwrap();
+        assert_eq!(decoded, Some(header));
+        assert_eq!(decoded_payload, payload);
+    }
+
+    #[test]
+    fn should_compress_large_payloads() {
+        let mut header = Header::new(BINARY_PROTOCOL);
+        header.info.insert("user".to_string(), "foo".to_string());
+
+        let small = wrap(&header, &[1u8; 10]).unwrap();
+        assert_eq!(compress(small.clone(), Transform::Zlib, 100).unwrap(), small);
+
+        let payload = vec![1u8; 1000];
+        let frame = compress(wrap(&header, &payload).unwrap(), Transform::Zlib, 100).unwrap();
+        assert!(frame.len() < payload.len());
+
+        let (frame, transform) = decompress(frame, 1000).unwrap();
+        assert_eq!(transform, Some(Transform::Zlib));
+        assert_eq!(unwrap(frame).unwrap(), (Some(header), payload));
+    }
+
+    #[test]
+    fn should_cap_decompressed_payloads() {
+        let header = Header::new(BINARY_PROTOCOL);
+        let frame = compress(wrap(&header, &[1u8; 1000]).unwrap(), Transform::Zlib, 100).unwrap();
+        assert!(frame.len() < 100);
+        assert!(decompress(frame, 999).is_err());
+    }
+
+    #[test]
+    fn should_pass_plain_frames_through() {
+        let frame = vec![0x80, 1, 0, 1];
+        assert_eq!(unwrap(frame.clone()).unwrap(), (None, frame));
+    }
+
+    #[test]
+    fn should_reject_unknown_transforms() {
+        let mut frame = wrap(&Header::new(BINARY_PROTOCOL), &[]).unwrap();
+        // One transform, with the id 9.
+        frame[11] = 1;
+        frame[12] = 9;
+        assert!(unwrap(frame).is_err());
+    }
+
+    #[test]
+    fn should_expose_request_headers() {
+        let mut headers = Headers::new();
+        headers.insert("user".to_string(), "foo".to_string());
+
+        let user = with_request_headers(headers, || request_headers().get("user").cloned());
+        assert_eq!(user, Some("foo".to_string()));
+        assert!(request_headers().is_empty());
+    }
+}
@@ -3,6 +3,8 @@
 
 #[macro_use]
 extern crate lazy_static;
+#[macro_use]
+extern crate log;
 extern crate mio;
 extern crate byteorder;
 extern crate tangle;
@@ -11,6 +13,13 @@ extern crate slab;
 extern crate bytes;
 extern crate num_cpus;
 extern crate libc;
+extern crate flate2;
+#[cfg(feature = "tls")]
+extern crate openssl;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+#[cfg(feature = "lz4")]
+extern crate lz4;
 
 use std::str;
 use std::convert;
@@ -25,9 +34,23 @@ pub mod binary_protocol;
 mod runner;
 pub mod dispatcher;
 mod result;
-mod transport;
+pub mod transport;
+pub mod blocking;
+pub mod workers;
+pub mod server;
+pub mod client;
+pub mod pool;
+pub mod breaker;
+pub mod header;
+pub mod context;
+pub mod tracing;
+pub mod metrics;
+#[cfg(feature = "tls")]
+pub mod tls;
 
 pub use reactor::Reactor;
 pub use runner::Runner;
+pub use context::Context;
 pub use result::{ThrustResult, ThrustError};
+pub use transport::Transport;
 pub use protocol::{Serializer, Serialize, Deserialize, ThriftSerializer, ThriftDeserializer};
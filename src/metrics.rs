@@ -0,0 +1,462 @@
+//! Request counts, latencies and errors per method, for both servers and clients, and
+//! the traffic of every `Reactor` connection. Metrics are always collected; read them
+//! with `snapshot`, or serve them to Prometheus with `serve_prometheus`.
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::Duration;
+use reactor::Addr;
+use result::ThrustResult;
+
+/// The upper bounds of the latency buckets, in seconds.
+pub const LATENCY_BUCKETS: [f64; 12] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// The label calls to methods a server doesn't know are recorded under, so peers can't
+/// grow the metrics with every name they make up. No Thrift method can have it as its
+/// name.
+pub const UNKNOWN_METHOD: &'static str = "<unknown>";
+
+/// How long the exporter waits on a scraper before giving up on it.
+const SCRAPE_TIMEOUT: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Side {
+    Client,
+    Server
+}
+
+impl Side {
+    fn label(&self) -> &'static str {
+        match *self {
+            Side::Client => "client",
+            Side::Server => "server"
+        }
+    }
+}
+
+/// Identifies the metrics of a method. Services that haven't been named have an empty
+/// name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Key {
+    pub side: Side,
+    pub service: String,
+    pub method: String
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// The number of observations at or below each of `LATENCY_BUCKETS`.
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    /// The sum of every observation, in seconds.
+    pub sum: f64
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            count: 0,
+            sum: 0.0
+        }
+    }
+}
+
+fn secs(elapsed: Duration) -> f64 {
+    elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MethodStats {
+    pub requests: u64,
+    /// Calls that failed, including exceptions sent as replies.
+    pub errors: u64,
+    /// Calls that have started but haven't finished yet.
+    pub in_flight: u64,
+    pub latency: Histogram
+}
+
+/// The bytes read from and written to a connection, updated by the `Reactor`.
+pub struct Traffic {
+    id: usize,
+    read: AtomicUsize,
+    written: AtomicUsize
+}
+
+impl Traffic {
+    pub fn read(&self, bytes: usize) {
+        self.read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn written(&self, bytes: usize) {
+        self.written.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionStats {
+    pub peer: Addr,
+    /// Whether a listener accepted the connection, as opposed to a client opening it.
+    pub accepted: bool,
+    pub bytes_read: u64,
+    pub bytes_written: u64
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub methods: HashMap<Key, MethodStats>,
+    /// The connections that are currently open.
+    pub connections: Vec<ConnectionStats>,
+    /// The number of connections opened so far, including the closed ones.
+    pub connections_opened: u64,
+    /// The bytes read and written so far, including those of closed connections.
+    pub bytes_read: u64,
+    pub bytes_written: u64
+}
+
+/// The live counters of a method, which calls update without taking any lock.
+#[derive(Default)]
+struct Counters {
+    requests: AtomicUsize,
+    finished: AtomicUsize,
+    errors: AtomicUsize,
+    buckets: [AtomicUsize; 12],
+    /// The sum of every observation, in microseconds.
+    sum: AtomicUsize
+}
+
+impl Counters {
+    fn stats(&self) -> MethodStats {
+        let requests = self.requests.load(Ordering::Relaxed) as u64;
+        let finished = self.finished.load(Ordering::Relaxed) as u64;
+
+        MethodStats {
+            requests: requests,
+            errors: self.errors.load(Ordering::Relaxed) as u64,
+            in_flight: requests.saturating_sub(finished),
+            latency: Histogram {
+                buckets: self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed) as u64).collect(),
+                count: finished,
+                sum: self.sum.load(Ordering::Relaxed) as f64 / 1e6
+            }
+        }
+    }
+}
+
+struct Registry {
+    connections: HashMap<usize, (Addr, bool, Arc<Traffic>)>,
+    opened: u64,
+    /// The traffic of connections that have been closed.
+    closed_read: u64,
+    closed_written: u64
+}
+
+lazy_static! {
+    /// Only taken for writing the first time a method is called.
+    static ref METHODS: RwLock<HashMap<Key, Arc<Counters>>> = RwLock::new(HashMap::new());
+
+    static ref METRICS: Mutex<Registry> = Mutex::new(Registry {
+        connections: HashMap::new(),
+        opened: 0,
+        closed_read: 0,
+        closed_written: 0
+    });
+}
+
+static NEXT_CONNECTION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn counters(side: Side, service: &str, method: &str) -> Arc<Counters> {
+    let key = Key {
+        side: side,
+        service: service.to_string(),
+        method: method.to_string()
+    };
+
+    if let Some(counters) = METHODS.read().expect("Failed to take the `METHODS` lock.").get(&key) {
+        return counters.clone();
+    }
+
+    let mut methods = METHODS.write().expect("Failed to take the `METHODS` lock.");
+    methods.entry(key).or_insert_with(|| Arc::new(Counters::default())).clone()
+}
+
+/// The method to record a call to `method` under: `method` itself if it's one of
+/// `known`, and `UNKNOWN_METHOD` otherwise.
+pub fn method_label<'a>(known: &[&str], method: &'a str) -> &'a str {
+    if known.iter().any(|known| *known == method) {
+        method
+    } else {
+        UNKNOWN_METHOD
+    }
+}
+
+/// Count a call that's starting.
+pub fn started(side: Side, service: &str, method: &str) {
+    counters(side, service, method).requests.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record a call that was `started` and took `elapsed`.
+pub fn finished(side: Side, service: &str, method: &str, elapsed: Duration, failed: bool) {
+    let counters = counters(side, service, method);
+    let secs = secs(elapsed);
+    for (bucket, &le) in counters.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+        if secs <= le {
+            bucket.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    counters.sum.fetch_add((secs * 1e6) as usize, Ordering::Relaxed);
+    if failed {
+        counters.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    counters.finished.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Start tracking the traffic of a connection to `peer`.
+pub fn opened(peer: &Addr, accepted: bool) -> Arc<Traffic> {
+    let traffic = Arc::new(Traffic {
+        id: NEXT_CONNECTION.fetch_add(1, Ordering::SeqCst),
+        read: AtomicUsize::new(0),
+        written: AtomicUsize::new(0)
+    });
+
+    let mut registry = METRICS.lock().expect("Failed to take the `METRICS` lock.");
+    registry.opened += 1;
+    registry.connections.insert(traffic.id, (peer.clone(), accepted, traffic.clone()));
+    traffic
+}
+
+/// Stop tracking a connection, keeping its traffic in the totals.
+pub fn closed(traffic: &Traffic) {
+    let mut registry = METRICS.lock().expect("Failed to take the `METRICS` lock.");
+    if registry.connections.remove(&traffic.id).is_some() {
+        registry.closed_read += traffic.read.load(Ordering::Relaxed) as u64;
+        registry.closed_written += traffic.written.load(Ordering::Relaxed) as u64;
+    }
+}
+
+pub fn snapshot() -> Snapshot {
+    let methods = METHODS.read().expect("Failed to take the `METHODS` lock.").iter().map(|(key, counters)| {
+        (key.clone(), counters.stats())
+    }).collect();
+
+    let registry = METRICS.lock().expect("Failed to take the `METRICS` lock.");
+    let connections: Vec<ConnectionStats> = registry.connections.values().map(|&(ref peer, accepted, ref traffic)| {
+        ConnectionStats {
+            peer: peer.clone(),
+            accepted: accepted,
+            bytes_read: traffic.read.load(Ordering::Relaxed) as u64,
+            bytes_written: traffic.written.load(Ordering::Relaxed) as u64
+        }
+    }).collect();
+
+    Snapshot {
+        methods: methods,
+        connections_opened: registry.opened,
+        bytes_read: registry.closed_read + connections.iter().map(|conn| conn.bytes_read).sum::<u64>(),
+        bytes_written: registry.closed_written + connections.iter().map(|conn| conn.bytes_written).sum::<u64>(),
+        connections: connections
+    }
+}
+
+fn escape(val: &str) -> String {
+    val.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Snapshot {
+    /// Render the snapshot in the Prometheus text format. Per connection traffic is left
+    /// out, only the totals are.
+    pub fn to_prometheus(&self) -> String {
+        let mut keys: Vec<&Key> = self.methods.keys().collect();
+        keys.sort();
+
+        let mut out = String::new();
+        let labels = |key: &Key| {
+            format!("side=\"{}\",service=\"{}\",method=\"{}\"", key.side.label(), escape(&key.service), escape(&key.method))
+        };
+
+        out.push_str("# TYPE thrust_requests_total counter\n");
+        for key in keys.iter() {
+            write!(out, "thrust_requests_total{{{}}} {}\n", labels(key), self.methods[*key].requests);
+        }
+
+        out.push_str("# TYPE thrust_errors_total counter\n");
+        for key in keys.iter() {
+            write!(out, "thrust_errors_total{{{}}} {}\n", labels(key), self.methods[*key].errors);
+        }
+
+        out.push_str("# TYPE thrust_in_flight gauge\n");
+        for key in keys.iter() {
+            write!(out, "thrust_in_flight{{{}}} {}\n", labels(key), self.methods[*key].in_flight);
+        }
+
+        out.push_str("# TYPE thrust_request_duration_seconds histogram\n");
+        for key in keys.iter() {
+            let latency = &self.methods[*key].latency;
+            for (count, le) in latency.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+                write!(out, "thrust_request_duration_seconds_bucket{{{},le=\"{}\"}} {}\n", labels(key), le, count);
+            }
+            write!(out, "thrust_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}\n", labels(key), latency.count);
+            write!(out, "thrust_request_duration_seconds_sum{{{}}} {}\n", labels(key), latency.sum);
+            write!(out, "thrust_request_duration_seconds_count{{{}}} {}\n", labels(key), latency.count);
+        }
+
+        let accepted = self.connections.iter().filter(|conn| conn.accepted).count();
+        out.push_str("# TYPE thrust_connections gauge\n");
+        write!(out, "thrust_connections{{side=\"server\"}} {}\n", accepted);
+        write!(out, "thrust_connections{{side=\"client\"}} {}\n", self.connections.len() - accepted);
+
+        out.push_str("# TYPE thrust_connections_opened_total counter\n");
+        write!(out, "thrust_connections_opened_total {}\n", self.connections_opened);
+        out.push_str("# TYPE thrust_read_bytes_total counter\n");
+        write!(out, "thrust_read_bytes_total {}\n", self.bytes_read);
+        out.push_str("# TYPE thrust_written_bytes_total counter\n");
+        write!(out, "thrust_written_bytes_total {}\n", self.bytes_written);
+
+        out
+    }
+}
+
+/// Answer every HTTP request on `listener` with the current metrics in the Prometheus
+/// text format, whatever the path. Requests are served one at a time, forever.
+pub fn serve_prometheus(listener: TcpListener) -> ThrustResult<()> {
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+
+        // Requests are served one at a time, so a scraper that stalls can't be waited
+        // on forever.
+        let timeout = Some(Duration::from_secs(SCRAPE_TIMEOUT));
+        if let Err(err) = stream.set_read_timeout(timeout).and_then(|_| stream.set_write_timeout(timeout)) {
+            warn!("error setting the scrape timeouts; err={:?}", err);
+            continue;
+        }
+
+        // The request itself doesn't matter, but it's read so the client doesn't see
+        // its connection reset.
+        let mut request = [0u8; 4096];
+        if let Err(err) = stream.read(&mut request) {
+            warn!("error reading a request; err={:?}", err);
+            continue;
+        }
+
+        let body = snapshot().to_prometheus();
+        let res = write!(stream, "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                         body.len(), body);
+        if let Err(err) = res {
+            warn!("error writing a response; err={:?}", err);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+    use reactor::Addr;
+
+    fn key(service: &str, method: &str) -> Key {
+        Key {
+            side: Side::Server,
+            service: service.to_string(),
+            method: method.to_string()
+        }
+    }
+
+    #[test]
+    fn should_fill_histogram_buckets() {
+        started(Side::Server, "Metrics", "histogram");
+        finished(Side::Server, "Metrics", "histogram", Duration::from_millis(20), false);
+
+        let histogram = snapshot().methods[&key("Metrics", "histogram")].latency.clone();
+        assert_eq!(histogram.count, 1);
+        // 0.025 is the first bucket 20ms falls into.
+        assert_eq!(&histogram.buckets[..4], &[0, 0, 0, 1]);
+        assert_eq!(histogram.buckets[LATENCY_BUCKETS.len() - 1], 1);
+    }
+
+    #[test]
+    fn should_count_calls() {
+        started(Side::Server, "Metrics", "count");
+        started(Side::Server, "Metrics", "count");
+        assert_eq!(snapshot().methods[&key("Metrics", "count")].in_flight, 2);
+
+        finished(Side::Server, "Metrics", "count", Duration::from_millis(1), false);
+        finished(Side::Server, "Metrics", "count", Duration::from_millis(1), true);
+
+        let stats = snapshot().methods[&key("Metrics", "count")].clone();
+        assert_eq!(stats.requests, 2);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.in_flight, 0);
+        assert_eq!(stats.latency.count, 2);
+    }
+
+    #[test]
+    fn should_label_unknown_methods() {
+        assert_eq!(method_label(&["foo", "bar"], "bar"), "bar");
+        assert_eq!(method_label(&["foo", "bar"], "baz"), UNKNOWN_METHOD);
+        assert_eq!(method_label(&[], "foo"), UNKNOWN_METHOD);
+    }
+
+    #[test]
+    fn should_track_connections() {
+        let peer = Addr::Tcp("127.0.0.1:4001".parse().unwrap());
+        let traffic = opened(&peer, true);
+        traffic.read(10);
+        traffic.written(5);
+
+        let conn = snapshot().connections.into_iter().find(|conn| conn.peer == peer).unwrap();
+        assert_eq!((conn.bytes_read, conn.bytes_written), (10, 5));
+
+        closed(&traffic);
+        assert!(snapshot().connections.iter().all(|conn| conn.peer != peer));
+        assert!(snapshot().bytes_read >= 10);
+    }
+
+    #[test]
+    fn should_give_up_on_stalled_scrapers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            serve_prometheus(listener);
+        });
+
+        // Connects but never sends a request, which can't hold the next scrape up
+        // for longer than the timeout.
+        let _stalled = TcpStream::connect(addr).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        assert!(res.starts_with("HTTP/1.0 200 OK"));
+    }
+
+    #[test]
+    fn should_serve_prometheus() {
+        started(Side::Server, "Metrics", "scrape");
+        finished(Side::Server, "Metrics", "scrape", Duration::from_millis(1), false);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            serve_prometheus(listener);
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+
+        assert!(res.starts_with("HTTP/1.0 200 OK"));
+        assert!(res.contains("thrust_requests_total{side=\"server\",service=\"Metrics\",method=\"scrape\"} 1"));
+    }
+}
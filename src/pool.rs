@@ -0,0 +1,449 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use rand::{self, Rng};
+use tangle::{Future, Async};
+use protocol::Protocol;
+use binary_protocol::Binary;
+use dispatcher::{self, Dispatcher, Incoming, Reply};
+use reactor::{Addr, Framing, Compression, Security};
+use result::{ThrustResult, ThrustError};
+use breaker::{self, Breaker, BreakerConfig};
+use client::Filters;
+use tracing;
+
+/// How a `Pool` picks the connection for the next call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Balance {
+    /// Take turns going through every connection.
+    RoundRobin,
+    /// Use the connection with the fewest calls waiting on a reply.
+    LeastOutstanding,
+    /// Pick two connections at random and use the one with fewer calls waiting on a
+    /// reply.
+    PowerOfTwoChoices
+}
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// The number of connections kept open to every endpoint.
+    pub connections: usize,
+    pub balance: Balance,
+    pub framing: Framing,
+    /// How long an endpoint that failed is left alone before reconnecting to it.
+    pub retry_after: Duration,
+    /// An endpoint is marked dead once this many calls to it in a row went without a
+    /// reply, because they failed, timed out or were cancelled.
+    pub max_failures: usize,
+    /// Guard every endpoint with its own circuit breaker. Endpoints whose breaker is
+    /// open are skipped.
+    pub breaker: Option<BreakerConfig>,
+    /// Calls that haven't been replied to after this long fail with
+    /// `ThrustError::Timeout`.
+    pub deadline: Option<Duration>,
+    /// The filters the calls on every connection go through, instead of the ones
+    /// installed with `client::add_filter`.
+    pub filters: Option<Filters>,
+    /// How the calls sent on every connection are compressed, which needs
+    /// `Framing::Header`.
+    pub compression: Compression,
+    /// How every connection is secured, which can be TLS with the `tls` feature.
+    pub security: Security
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            connections: 2,
+            balance: Balance::PowerOfTwoChoices,
+            framing: Framing::Framed,
+            retry_after: Duration::from_secs(5),
+            max_failures: 5,
+            breaker: None,
+            deadline: None,
+            filters: None,
+            compression: Compression::default(),
+            security: Security::Plain
+        }
+    }
+}
+
+struct Connection {
+    dispatcher: Sender<Incoming>,
+    handle: JoinHandle<ThrustResult<()>>,
+    /// The number of calls sent on this connection that haven't been replied to.
+    outstanding: Arc<AtomicUsize>
+}
+
+struct Endpoint {
+    addr: Addr,
+    connections: Vec<Connection>,
+    /// Set while the endpoint is considered dead.
+    dead_until: Option<Instant>,
+    /// Shared by all connections to the endpoint, and kept across reconnects.
+    breaker: Option<Breaker>,
+    /// The calls to the endpoint that went without a reply in a row.
+    failures: Arc<AtomicUsize>
+}
+
+impl Endpoint {
+    fn connect<P: Protocol>(&mut self, config: &PoolConfig, protocol: &P, service: &str) -> ThrustResult<()> {
+        let mut connections = Vec::new();
+        for _ in 0..config.connections {
+            let role = dispatcher::Role::Client(self.addr.clone(), config.framing);
+            let (handle, tx) = Dispatcher::spawn_with(role, config.compression, config.security.clone(), protocol.clone())?;
+            if let Some(ref breaker) = self.breaker {
+                tx.send(Incoming::Guard(breaker.clone()))?;
+            }
+
+            if let Some(deadline) = config.deadline {
+                tx.send(Incoming::Deadline(deadline))?;
+            }
+
+            if let Some(ref filters) = config.filters {
+                tx.send(Incoming::Filters(filters.clone()))?;
+            }
+
+            tx.send(Incoming::Service(service.to_string()))?;
+
+            connections.push(Connection {
+                dispatcher: tx,
+                handle: handle,
+                outstanding: Arc::new(AtomicUsize::new(0))
+            });
+        }
+
+        self.connections = connections;
+        self.dead_until = None;
+        self.failures.store(0, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Whether calls can be sent to the endpoint.
+    fn is_available(&self) -> bool {
+        let open = match self.breaker {
+            Some(ref breaker) => breaker.state() == breaker::State::Open,
+            None => false
+        };
+
+        self.dead_until.is_none() && !open
+    }
+
+    fn mark_dead(&mut self, retry_after: Duration) {
+        warn!("marking an endpoint as dead; addr={:?}", self.addr);
+        self.connections.clear();
+        self.dead_until = Some(Instant::now() + retry_after);
+    }
+}
+
+/// Counts a call as outstanding on its connection until it's done, whether it was
+/// replied to or not. Calls that weren't count towards the failures of the endpoint.
+struct InFlight {
+    outstanding: Arc<AtomicUsize>,
+    failures: Arc<AtomicUsize>,
+    replied: bool
+}
+
+impl InFlight {
+    fn new(outstanding: Arc<AtomicUsize>, failures: Arc<AtomicUsize>) -> InFlight {
+        outstanding.fetch_add(1, Ordering::SeqCst);
+        InFlight {
+            outstanding: outstanding,
+            failures: failures,
+            replied: false
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.outstanding.fetch_sub(1, Ordering::SeqCst);
+        if self.replied {
+            self.failures.store(0, Ordering::SeqCst);
+        } else {
+            self.failures.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Calls spread over a set of connections to several endpoints. Generated pooled
+/// clients send every call through a `Pool`.
+///
+/// An endpoint is marked dead once a call can't be sent to it, or once
+/// `PoolConfig::max_failures` calls to it in a row went without a reply. It's
+/// reconnected to after `PoolConfig::retry_after`.
+pub struct Pool<P: Protocol = Binary> {
+    config: PoolConfig,
+    protocol: P,
+    endpoints: Vec<Endpoint>,
+    /// The position of the round robin.
+    next: usize,
+    /// The name of the service, for metrics.
+    service: String
+}
+
+impl Pool<Binary> {
+    /// Connect to every endpoint. Endpoints that can't be reached start out dead, but
+    /// at least one of them has to be up.
+    pub fn new(endpoints: Vec<Addr>, config: PoolConfig) -> ThrustResult<Pool<Binary>> {
+        Pool::with_protocol(endpoints, config, Binary)
+    }
+}
+
+impl<P: Protocol> Pool<P> {
+    /// Like `new`, but speaking `protocol`.
+    pub fn with_protocol(endpoints: Vec<Addr>, config: PoolConfig, protocol: P) -> ThrustResult<Pool<P>> {
+        if config.connections == 0 {
+            return Err(ThrustError::Str("A pool needs at least one connection to every endpoint.".to_string()));
+        }
+
+        let breaker = config.breaker;
+        let mut pool = Pool {
+            config: config,
+            protocol: protocol,
+            endpoints: endpoints.into_iter().map(|addr| {
+                Endpoint {
+                    addr: addr,
+                    connections: Vec::new(),
+                    dead_until: None,
+                    breaker: breaker.map(Breaker::new),
+                    failures: Arc::new(AtomicUsize::new(0))
+                }
+            }).collect(),
+            next: 0,
+            service: String::new()
+        };
+
+        for endpoint in pool.endpoints.iter_mut() {
+            if let Err(err) = endpoint.connect(&pool.config, &pool.protocol, &pool.service) {
+                warn!("failed to connect; addr={:?} err={:?}", endpoint.addr, err);
+                endpoint.mark_dead(pool.config.retry_after);
+            }
+        }
+
+        if pool.alive() == 0 {
+            return Err(ThrustError::Str("None of the endpoints could be reached.".to_string()));
+        }
+
+        Ok(pool)
+    }
+
+    /// Name the service the pool calls, which the metrics of its connections are
+    /// recorded under.
+    pub fn named(mut self, service: &str) -> Pool<P> {
+        self.service = service.to_string();
+        for endpoint in self.endpoints.iter() {
+            for conn in endpoint.connections.iter() {
+                conn.dispatcher.send(Incoming::Service(self.service.clone()));
+            }
+        }
+
+        self
+    }
+
+    /// The protocol calls are to be encoded with.
+    pub fn protocol(&self) -> &P {
+        &self.protocol
+    }
+
+    /// The number of endpoints that are currently considered alive.
+    pub fn alive(&self) -> usize {
+        self.endpoints.iter().filter(|endpoint| endpoint.dead_until.is_none()).count()
+    }
+
+    /// Send a call, encoded with the sequence id `seq`, on one of the connections. The
+    /// reply is sent to `res`, just like with `Incoming::Call`, and the call continues
+    /// the current trace.
+    pub fn call(&mut self, method: String, seq: i32, buf: Vec<u8>, res: Sender<Reply>) -> ThrustResult<()> {
+        self.bury();
+        self.revive();
+
+        let (tx, future) = Future::<Reply>::channel();
+        let mut call = Incoming::Call(method, seq, buf, Some(tx), tracing::current());
+
+        loop {
+            let (e, c) = match self.pick() {
+                Some(pos) => pos,
+                None if self.alive() > 0 => return Err(ThrustError::CircuitOpen),
+                None => return Err(ThrustError::Str("No endpoints are available.".to_string()))
+            };
+
+            let sent = {
+                let endpoint = &self.endpoints[e];
+                let conn = &endpoint.connections[c];
+                match conn.dispatcher.send(call) {
+                    Ok(_) => Ok(InFlight::new(conn.outstanding.clone(), endpoint.failures.clone())),
+                    Err(err) => Err(err.0)
+                }
+            };
+
+            match sent {
+                Ok(mut in_flight) => {
+                    // The call is done once the reply arrives, or once its reply
+                    // channel, and this closure with it, is dropped.
+                    future.and_then(move |reply| {
+                        in_flight.replied = true;
+                        res.send(reply);
+                        Async::Ok(())
+                    });
+
+                    return Ok(());
+                },
+                // The dispatcher is gone, so its connection is too. Try another endpoint.
+                Err(unsent) => call = unsent
+            }
+
+            let retry_after = self.config.retry_after;
+            self.endpoints[e].mark_dead(retry_after);
+        }
+    }
+
+    /// Mark endpoints dead whose calls keep going without a reply, like the ones whose
+    /// connections are gone.
+    fn bury(&mut self) {
+        let config = &self.config;
+        for endpoint in self.endpoints.iter_mut() {
+            if endpoint.dead_until.is_none() && endpoint.failures.load(Ordering::SeqCst) >= config.max_failures {
+                endpoint.mark_dead(config.retry_after);
+            }
+        }
+    }
+
+    /// Reconnect to dead endpoints that have been left alone for long enough.
+    fn revive(&mut self) {
+        let now = Instant::now();
+        let config = &self.config;
+        let protocol = &self.protocol;
+        let service = &self.service;
+
+        for endpoint in self.endpoints.iter_mut() {
+            match endpoint.dead_until {
+                Some(until) if until <= now => {},
+                _ => continue
+            }
+
+            info!("reconnecting; addr={:?}", endpoint.addr);
+            if let Err(err) = endpoint.connect(config, protocol, service) {
+                warn!("failed to reconnect; addr={:?} err={:?}", endpoint.addr, err);
+                endpoint.mark_dead(config.retry_after);
+            }
+        }
+    }
+
+    /// Pick a connection of an available endpoint, as `(endpoint, connection)` indices.
+    fn pick(&mut self) -> Option<(usize, usize)> {
+        let mut candidates = Vec::new();
+        let mut loads = Vec::new();
+
+        for (e, endpoint) in self.endpoints.iter().enumerate() {
+            if !endpoint.is_available() {
+                continue;
+            }
+
+            for (c, conn) in endpoint.connections.iter().enumerate() {
+                candidates.push((e, c));
+                loads.push(conn.outstanding.load(Ordering::SeqCst));
+            }
+        }
+
+        choose(self.config.balance, &loads, &mut self.next).map(|i| candidates[i])
+    }
+}
+
+/// Choose one of the connections, given how many calls each of them is waiting on.
+fn choose(balance: Balance, loads: &[usize], next: &mut usize) -> Option<usize> {
+    if loads.is_empty() {
+        return None;
+    }
+
+    let i = match balance {
+        Balance::RoundRobin => {
+            *next = next.wrapping_add(1);
+            *next % loads.len()
+        },
+        Balance::LeastOutstanding => {
+            (0..loads.len()).min_by_key(|&i| loads[i]).unwrap()
+        },
+        Balance::PowerOfTwoChoices => {
+            if loads.len() == 1 {
+                return Some(0);
+            }
+
+            let mut rng = rand::thread_rng();
+            let a = rng.gen_range(0, loads.len());
+            // Pick a second connection that's different from the first.
+            let b = (a + rng.gen_range(1, loads.len())) % loads.len();
+            if loads[b] < loads[a] { b } else { a }
+        }
+    };
+
+    Some(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{choose, Balance, InFlight, Pool, PoolConfig};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn should_round_robin() {
+        let mut next = 0;
+        let picks: Vec<_> = (0..4).map(|_| choose(Balance::RoundRobin, &[0, 0, 0], &mut next).unwrap()).collect();
+        assert_eq!(picks, vec![1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn should_pick_least_outstanding() {
+        let mut next = 0;
+        assert_eq!(choose(Balance::LeastOutstanding, &[3, 1, 2], &mut next), Some(1));
+    }
+
+    #[test]
+    fn should_pick_less_loaded_of_two() {
+        let mut next = 0;
+        for _ in 0..20 {
+            // Whichever two are picked, the busiest connection never wins.
+            assert!(choose(Balance::PowerOfTwoChoices, &[0, 9, 0], &mut next) != Some(1));
+            assert_eq!(choose(Balance::PowerOfTwoChoices, &[5, 0], &mut next), Some(1));
+        }
+    }
+
+    #[test]
+    fn should_not_pick_without_connections() {
+        let mut next = 0;
+        assert_eq!(choose(Balance::RoundRobin, &[], &mut next), None);
+    }
+
+    #[test]
+    fn should_count_calls_without_a_reply_as_failures() {
+        let outstanding = Arc::new(AtomicUsize::new(0));
+        let failures = Arc::new(AtomicUsize::new(0));
+
+        let unanswered = InFlight::new(outstanding.clone(), failures.clone());
+        assert_eq!(outstanding.load(Ordering::SeqCst), 1);
+        drop(unanswered);
+        assert_eq!(outstanding.load(Ordering::SeqCst), 0);
+        assert_eq!(failures.load(Ordering::SeqCst), 1);
+
+        let mut answered = InFlight::new(outstanding.clone(), failures.clone());
+        answered.replied = true;
+        drop(answered);
+        assert_eq!(outstanding.load(Ordering::SeqCst), 0);
+        assert_eq!(failures.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn should_reject_pools_without_connections() {
+        let config = PoolConfig {
+            connections: 0,
+            ..PoolConfig::default()
+        };
+
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        assert!(Pool::new(vec![addr.into()], config).is_err());
+    }
+}
@@ -9,7 +9,15 @@ pub enum Error {
     Io(io::Error),
     Utf8Error(FromUtf8Error),
     BadVersion,
-    ProtocolVersionMissing
+    ProtocolVersionMissing,
+    /// An unknown `ThriftType` was found in the stream.
+    UnknownType(i8),
+    NegativeSize,
+    NestingTooDeep,
+    /// A container claimed to hold void elements.
+    VoidElement,
+    /// A message was longer than the limit of this many bytes.
+    TooLarge(usize)
 }
 
 impl convert::From<byteorder::Error> for Error {
@@ -70,7 +78,7 @@ impl convert::From<i8> for ThriftType {
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum ThriftMessageType {
     Call = 1,
     Reply = 2,
@@ -132,6 +140,12 @@ pub trait ThriftSerializer {
         Ok(())
     }
 
+    /// Like `write_message_begin`, but with the sequence id that matches a reply up with
+    /// its call.
+    fn write_message_begin_seq(&mut self, name: &str, message_type: ThriftMessageType, seq: i32) -> Result<(), Error> {
+        self.write_message_begin(name, message_type)
+    }
+
     fn write_struct_begin(&mut self, name: &str) -> Result<(), Error> {
         Ok(())
     }
@@ -157,11 +171,11 @@ pub trait ThriftSerializer {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ThriftMessage {
     pub name: String,
     pub ty: ThriftMessageType,
-    pub seq: i16
+    pub seq: i32
 }
 #[derive(Debug)]
 pub struct ThriftField {
@@ -179,6 +193,33 @@ pub trait ThriftDeserializer {
     fn read_field_end(&mut self) -> Result<(), Error>;
 }
 
+/// Creates the serializers and deserializers of a protocol. Generated servers and
+/// clients are generic over it, so the same service can be spoken over binary, compact,
+/// JSON... by choosing the protocol when they're created.
+pub trait Protocol: 'static + Clone + Send {
+    type Serializer: Serializer + ThriftSerializer;
+    type Deserializer: Deserializer + ThriftDeserializer;
+
+    /// A serializer writing to a new buffer.
+    fn serializer(&self) -> Self::Serializer;
+
+    /// The buffer written by a serializer.
+    fn finish(&self, se: Self::Serializer) -> Vec<u8>;
+
+    fn deserializer(&self, buf: Vec<u8>) -> Self::Deserializer;
+
+    /// Read only the message header at the start of `buf`.
+    fn read_message(&self, buf: &[u8]) -> Result<ThriftMessage, Error>;
+
+    /// Read the next whole message off a blocking stream. Nothing past the end of the
+    /// message is consumed, so the next one is left for the next call. A message that
+    /// grows past `limit` bytes is an error.
+    fn read_from<R: Read>(&self, rd: &mut R, limit: usize) -> Result<Vec<u8>, Error>;
+
+    /// The id of the protocol in a THeader.
+    fn header_id(&self) -> u32;
+}
+
 pub trait Deserialize: Sized {
     fn deserialize<D>(de: &mut D) -> Result<Self, Error> where D: Deserializer + ThriftDeserializer;
 }
@@ -200,6 +241,14 @@ impl Deserialize for u8 {
     }
 }
 
+impl Deserialize for () {
+    fn deserialize<D>(de: &mut D) -> Result<Self, Error>
+        where D: Deserializer + ThriftDeserializer
+    {
+        Ok(())
+    }
+}
+
 impl Deserialize for bool {
     fn deserialize<D>(de: &mut D) -> Result<Self, Error>
         where D: Deserializer + ThriftDeserializer
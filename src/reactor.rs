@@ -1,23 +1,41 @@
 use mio::tcp::*;
+use mio::unix::{UnixListener, UnixStream};
 use mio;
 use mio::{Token, Handler, EventLoop, EventSet, PollOpt, TryRead, TryWrite, Evented};
 use std::io::{self, Cursor, Write, Read};
 use std::net::{self, SocketAddr};
+use std::path::PathBuf;
 use std::time::Duration;
 use std::mem;
 use std::iter;
 use std::thread::{self, JoinHandle};
-use event_loop::EVENT_LOOP;
+use event_loop::{EVENT_LOOP, SENDER};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use std::sync::mpsc::{Receiver, Sender, channel};
+use metrics::{self, Traffic};
 use result::{ThrustResult, ThrustError};
 use tangle::{Future, Async};
 use bytes::buf::Buf;
 use std::collections::HashMap;
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use slab::Slab;
+use byteorder::{self, ByteOrder, BigEndian, ReadBytesExt, WriteBytesExt};
 use libc;
+use binary_protocol;
+use header::{self, Transform};
 use std::os::unix::io::AsRawFd;
+#[cfg(feature = "tls")]
+use tls::{Tls, TlsStream};
 
-pub struct Id(pub Token);
+/// Sent back once a `Bind` or `Connect` message has been handled. The `Addr` is the
+/// address the listener is actually bound to, which tells you which port the OS picked
+/// when binding to port 0. For connections it's the remote address.
+pub struct Id(pub Token, pub Addr);
+
+/// Listeners take the first tokens and connections are allocated after them. Only the
+/// connection slab ever grows, so the two token ranges never overlap.
+pub const MAX_LISTENERS: usize = 128;
+const INITIAL_CONNECTIONS: usize = 1024;
 
 /// Communication into the Mio event loop happens with a `Message`. For each new Mio
 /// event loop, a mio-specific `Sender<Message>` is returned.
@@ -27,19 +45,39 @@ pub enum Message {
     /// `Sender` channel part is used to communicate back with the initiator on
     /// certain socket events.
     ///
-    /// The first `Sender` is used to communicate back the assigned `Token`.
-    Connect(SocketAddr, Sender<Id>, Sender<Dispatch>),
-    /// To give a tighter feedback loop, a `Bind` message will accept a normal
-    /// Rust blocking net::TcpListener. This allows the user to more easily handle
-    /// binding errors before sending it into the event loop where you need to
-    /// handle any errors asynchronously.
-    Bind(SocketAddr, Sender<Id>, Sender<Dispatch>),
+    /// The first `Sender` is used to communicate back the assigned `Token`, or the
+    /// error if the connection couldn't be established.
+    ///
+    /// The `Compression` and `Security` decide whether the connection is compressed
+    /// and encrypted.
+    Connect(SocketAddr, Framing, Compression, Security, Sender<ThrustResult<Id>>, Sender<Dispatch>),
+    /// Bind a new listener. Whether binding worked is sent back through the first
+    /// `Sender`, so errors such as the address already being in use are handled by
+    /// the initiator instead of the event loop.
+    ///
+    /// The `Framing`, `Compression`, `Limits` and `Security` apply to every connection
+    /// the listener accepts.
+    Bind(SocketAddr, Framing, Compression, Limits, Security, Sender<ThrustResult<Id>>, Sender<Dispatch>),
+    /// The same as `Connect`, but for a Unix domain socket at the given path, which
+    /// can't be encrypted.
+    ConnectUnix(PathBuf, Framing, Compression, Sender<ThrustResult<Id>>, Sender<Dispatch>),
+    /// The same as `Bind`, but listens on a Unix domain socket at the given path. The
+    /// path must not exist yet.
+    BindUnix(PathBuf, Framing, Compression, Limits, Sender<ThrustResult<Id>>, Sender<Dispatch>),
     /// Initiate an `Rpc` request. Each request needs to know which `Token` the respective
     /// `Connection` is associated with. The `Reactor` also knows nothing about Thrift
     /// and simply works at the binary level.
     ///
     /// An `Rpc` message is also used for replying to an RPC call.
     Rpc(Token, Vec<u8>),
+    /// One of the requests read from the connection won't be replied to, like a one-way
+    /// call or a malformed frame. This frees the slot the request took up under
+    /// `Limits::max_in_flight`, just like a reply would.
+    Release(Token),
+    /// Send the sequence id back through the channel once the duration has passed.
+    /// Dispatchers have no timers of their own, so the `Reactor` keeps the ones for
+    /// the deadlines of their calls.
+    Deadline(Duration, i32, Sender<i32>),
     /// Completely shutdown the `Reactor` and event loop. All current listeners
     /// and connections will be dropped.
     Shutdown
@@ -52,229 +90,725 @@ pub enum Dispatch {
     /// When a socket has been read, the `Reactor` will send the `Dispatch::Data` message
     /// to the associating channel.
     ///
-    /// We also associate any incoming data with the Token of the responsible socket.
-    Data(Token, Vec<u8>)
+    /// We also associate any incoming data with the Token of the responsible socket,
+    /// and the peer it came from.
+    Data(Token, Peer, Vec<u8>),
+    /// The connection has been torn down, either because the peer hung up or an error
+    /// occurred on the socket. The `Token` won't be used for this connection again.
+    Closed(Token)
+}
+
+/// Where a listener is bound or a connection is connected to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Addr {
+    Tcp(SocketAddr),
+    Unix(PathBuf)
+}
+
+impl From<SocketAddr> for Addr {
+    fn from(addr: SocketAddr) -> Addr {
+        Addr::Tcp(addr)
+    }
+}
+
+impl From<PathBuf> for Addr {
+    fn from(path: PathBuf) -> Addr {
+        Addr::Unix(path)
+    }
+}
+
+/// The other end of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub addr: Addr,
+    /// The common name of the certificate the peer presented over TLS, if it did.
+    pub identity: Option<String>
+}
+
+/// How the bytes of a connection are protected on the wire.
+#[derive(Debug, Clone)]
+pub enum Security {
+    Plain,
+    /// Encrypt the connection with TLS, which is only supported over TCP. Whether the
+    /// handshake is accepted or started depends on the kind of `Tls`, so listeners
+    /// need a `Tls::Server` and connections a `Tls::Client`.
+    #[cfg(feature = "tls")]
+    Tls(Tls)
+}
+
+impl Security {
+    /// Whether connections are sent in the clear.
+    pub fn is_plain(&self) -> bool {
+        match *self {
+            Security::Plain => true,
+            #[cfg(feature = "tls")]
+            Security::Tls(_) => false
+        }
+    }
+}
+
+impl Default for Security {
+    fn default() -> Security {
+        Security::Plain
+    }
+}
+
+/// A non-blocking socket that a `Connection` reads from and writes to.
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(TlsStream)
+}
+
+impl Stream {
+    pub fn connect(addr: &Addr) -> ThrustResult<Stream> {
+        match *addr {
+            Addr::Tcp(ref addr) => Ok(Stream::Tcp(TcpStream::connect(addr)?)),
+            Addr::Unix(ref path) => Ok(Stream::Unix(UnixStream::connect(path)?))
+        }
+    }
+
+    /// Wrap the socket as `security` asks.
+    pub fn secure(self, security: &Security) -> ThrustResult<Stream> {
+        match *security {
+            Security::Plain => Ok(self),
+            #[cfg(feature = "tls")]
+            Security::Tls(ref tls) => match self {
+                Stream::Tcp(stream) => Ok(Stream::Tls(TlsStream::new(tls, stream)?)),
+                _ => Err(ThrustError::Str("TLS is only supported over TCP.".to_string()))
+            }
+        }
+    }
+
+    /// The common name of the certificate the peer presented.
+    fn identity(&self) -> Option<String> {
+        match *self {
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref stream) => stream.identity().map(|identity| identity.to_string()),
+            _ => None
+        }
+    }
+
+    /// Move a TLS handshake along, which might be waiting on the socket to become
+    /// writable without anything else to write.
+    fn handshake(&mut self) -> io::Result<()> {
+        match *self {
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut stream) => stream.handshake(),
+            _ => Ok(())
+        }
+    }
+
+    /// Whether the TLS handshake is still going on.
+    fn is_handshaking(&self) -> bool {
+        match *self {
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref stream) => !stream.is_established(),
+            _ => false
+        }
+    }
+
+    fn wants_write(&self) -> bool {
+        match *self {
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref stream) => stream.wants_write(),
+            _ => false
+        }
+    }
+
+    fn evented(&self) -> &Evented {
+        match *self {
+            Stream::Tcp(ref stream) => stream,
+            Stream::Unix(ref stream) => stream,
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref stream) => stream.get_ref()
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.read(buf),
+            Stream::Unix(ref mut stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut stream) => stream.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.write(buf),
+            Stream::Unix(ref mut stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.flush(),
+            Stream::Unix(ref mut stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut stream) => stream.flush()
+        }
+    }
+}
+
+/// A non-blocking listening socket.
+pub enum ListenerSocket {
+    Tcp(TcpListener),
+    /// Unix sockets accept peers without an address, so the listener's own path is
+    /// kept to describe accepted connections.
+    Unix(UnixListener, PathBuf)
+}
+
+impl ListenerSocket {
+    pub fn bind(addr: &Addr) -> ThrustResult<ListenerSocket> {
+        match *addr {
+            Addr::Tcp(ref addr) => Ok(ListenerSocket::Tcp(TcpListener::bind(addr)?)),
+            Addr::Unix(ref path) => Ok(ListenerSocket::Unix(UnixListener::bind(path)?, path.clone()))
+        }
+    }
+
+    /// The address the listener is bound to.
+    pub fn local_addr(&self) -> ThrustResult<Addr> {
+        match *self {
+            ListenerSocket::Tcp(ref lis) => Ok(Addr::Tcp(lis.local_addr()?)),
+            ListenerSocket::Unix(_, ref path) => Ok(Addr::Unix(path.clone()))
+        }
+    }
+
+    /// Accept a pending socket, if there is one.
+    pub fn accept(&self) -> ThrustResult<Option<(Stream, Addr)>> {
+        match *self {
+            ListenerSocket::Tcp(ref lis) => {
+                Ok(lis.accept()?.map(|(stream, addr)| (Stream::Tcp(stream), Addr::Tcp(addr))))
+            },
+            ListenerSocket::Unix(ref lis, ref path) => {
+                Ok(lis.accept()?.map(|stream| (Stream::Unix(stream), Addr::Unix(path.clone()))))
+            }
+        }
+    }
+
+    fn evented(&self) -> &Evented {
+        match *self {
+            ListenerSocket::Tcp(ref lis) => lis,
+            ListenerSocket::Unix(ref lis, _) => lis
+        }
+    }
 }
 
 pub enum Timeout {
-    Reconnect(Token)
+    Deadline(i32, Sender<i32>),
+    /// The TLS handshake of a connection is due, identified by the connection's token
+    /// and the id of the handshake, which tells it apart from the handshake of a new
+    /// connection reusing the token.
+    Handshake(Token, usize)
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum State {
-    /// The length that has been read so far.
-    ReadingFrame(usize),
-    Reading,
-    Writing,
-    Closed
+static NEXT_HANDSHAKE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Resource limits for a listener and the connections it accepts. Once a limit is hit
+/// the `Reactor` stops accepting or reading until things drain, which pushes the
+/// backpressure onto the peer through TCP instead of buffering without bound.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// The maximum number of open connections accepted by a single listener.
+    pub max_connections: usize,
+    /// The maximum number of requests read from a connection that haven't been
+    /// replied to or released with `Message::Release` yet.
+    pub max_in_flight: usize,
+    /// Once this many bytes are waiting to be written to a connection, we stop reading
+    /// from it until the write buffer drains.
+    pub write_high_water: usize,
+    /// The largest frame a connection reads, or the largest message on an unframed
+    /// connection. A peer sending a larger one is disconnected instead of having it
+    /// buffered.
+    pub max_frame_size: usize,
+    /// How long a TLS connection gets to finish its handshake before it's closed, so
+    /// peers that stall in it don't hold on to a connection slot.
+    pub handshake_timeout: Duration
 }
 
-#[derive(Debug)]
-pub enum FrameState {
-    Reading(u32),
-    Writing
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_connections: 1024,
+            max_in_flight: 128,
+            write_high_water: 4 * 1024 * 1024,
+            max_frame_size: 16 * 1024 * 1024,
+            handshake_timeout: Duration::from_secs(10)
+        }
+    }
+}
+
+/// How a connection compresses the frames it writes. Compressed frames are marked by a
+/// transform in their THeader, which other THeader implementations understand as well,
+/// so only connections using `Framing::Header` compress.
+///
+/// Frames the peer compressed are decompressed before they're dispatched, whether or
+/// not compression is configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    /// The transform frames are compressed with. Without one, a connection starts
+    /// compressing once its peer has sent a compressed frame, using the same transform.
+    pub transform: Option<Transform>,
+    /// Frames whose payload is smaller than this many bytes are written as they are.
+    pub threshold: usize
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression {
+            transform: None,
+            threshold: 1024
+        }
+    }
+}
+
+/// A `Codec` finds the message boundaries in a stream of bytes. Each `Connection` owns a
+/// codec that splits incoming data into frames and wraps outgoing frames.
+pub trait Codec: Send {
+    /// Decode the next complete frame from the front of `buf`, removing the bytes that
+    /// were consumed. Returns `None` if `buf` doesn't contain a whole frame yet.
+    fn decode(&mut self, buf: &mut Vec<u8>) -> ThrustResult<Option<Vec<u8>>>;
+
+    /// Append `data` as a single frame to `buf`.
+    fn encode(&mut self, data: &[u8], buf: &mut Vec<u8>) -> ThrustResult<()>;
 }
 
-/// Wrap a `TcpStream` to handle reading and writing frames. Frames are simply some encoded thrift
-/// protocol byte buffer preceeded by a 32-bit unsigned length.
+/// Read everything that's currently available on the socket into `buf`. Returns `false`
+/// once the peer has closed its end.
+fn read_available<S: Read>(socket: &mut S, buf: &mut Vec<u8>) -> ThrustResult<bool> {
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        match socket.read(&mut chunk) {
+            Ok(0) => return Ok(false),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err.into())
+        }
+    }
+}
+
+/// Handle reading and writing frames. Frames are simply some encoded thrift protocol byte
+/// buffer preceeded by a 32-bit unsigned length.
 pub struct FramedTransport {
     buffer: Vec<u8>,
-    state: FrameState
+    max_frame_size: usize
 }
 
 impl FramedTransport {
     pub fn new() -> FramedTransport {
+        FramedTransport::with_max_frame_size(Limits::default().max_frame_size)
+    }
+
+    /// Fail to decode frames longer than `max_frame_size` bytes.
+    pub fn with_max_frame_size(max_frame_size: usize) -> FramedTransport {
         FramedTransport {
             buffer: Vec::new(),
-            state: FrameState::Reading(0)
+            max_frame_size: max_frame_size
         }
     }
 
-    pub fn read<S>(&mut self, socket: &mut S) -> ThrustResult<Option<Vec<u8>>>
-        where S: TryRead + TryWrite
-    {
-        match self.state {
-            FrameState::Reading(total_len) if self.buffer.len() == 0 => {
-                let mut buf = Vec::with_capacity(4);
-                buf.extend(iter::repeat(0).take(4));
-
-                // Try reading the first unsigned 32-bits for the length of the frame.
-                let len = match socket.try_read_buf(&mut buf)? {
-                    Some(n) if n == 4 => {
-                        let mut buf = Cursor::new(buf);
-                        buf.read_u32::<BigEndian>()?
-                    },
-                    Some(n) => {
-                        return Err(ThrustError::Other);
-                    },
-                    None => panic!("err")
-                };
+    /// Read whatever is available from the socket and return the next complete frame.
+    /// Any other frames that were read stay buffered for subsequent calls.
+    pub fn read<S: Read>(&mut self, socket: &mut S) -> ThrustResult<Option<Vec<u8>>> {
+        let mut buffer = mem::replace(&mut self.buffer, Vec::new());
+        let frame = read_available(socket, &mut buffer).and_then(|_| self.decode(&mut buffer));
+        self.buffer = buffer;
+        frame
+    }
+}
 
-                // Set our internal buffer.
-                self.buffer = Vec::with_capacity(len as usize);
-                self.buffer.extend(iter::repeat(0).take(len as usize));
-                return self.read(socket);
-            },
-            FrameState::Reading(ref mut total_len) => {
-                while let Some(n) = socket.try_read_buf(&mut self.buffer)? {
-                    *total_len += n as u32;
-                    if *total_len == self.buffer.len() as u32 {
-                        return Ok(Some(mem::replace(&mut self.buffer, Vec::new())));
-                    } else if n == 0 {
-                        return Ok(None);
-                    }
-                }
+/// How message boundaries are found on a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Each message is preceeded by its length, compatible with `TFramedTransport`.
+    Framed,
+    /// Messages are written back-to-back without a length, compatible with
+    /// `TBufferedTransport`. Boundaries are found by parsing the binary protocol.
+    Buffered,
+    /// Framed messages that start with a THeader, compatible with `THeaderTransport`.
+    /// Plain framed messages are accepted too. See the `header` module.
+    Header
+}
+
+impl Framing {
+    /// The codec of a connection, which won't read frames larger than
+    /// `limits.max_frame_size`.
+    pub fn codec(&self, compression: Compression, limits: &Limits) -> Box<Codec> {
+        match *self {
+            Framing::Framed => Box::new(FramedTransport::with_max_frame_size(limits.max_frame_size)),
+            // THeader frames share the length prefix of framed messages. The header
+            // itself is unwrapped by the dispatcher or the workers, once the payload
+            // has been decompressed.
+            Framing::Header => Box::new(Compressing {
+                inner: FramedTransport::with_max_frame_size(limits.max_frame_size),
+                compression: compression,
+                max_size: limits.max_frame_size
+            }),
+            Framing::Buffered => Box::new(BufferedTransport::with_max_message_size(limits.max_frame_size))
+        }
+    }
+
+    /// Make sure frames can be compressed as asked.
+    fn check(&self, compression: &Compression) -> ThrustResult<()> {
+        if compression.transform.is_some() && *self != Framing::Header {
+            return Err(ThrustError::Str("Only connections using `Framing::Header` can be compressed.".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+impl Codec for FramedTransport {
+    fn decode(&mut self, buf: &mut Vec<u8>) -> ThrustResult<Option<Vec<u8>>> {
+        // We might only have part of the length header so far.
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+
+        let len = BigEndian::read_u32(&buf[..4]) as usize;
+        if len > self.max_frame_size {
+            return Err(ThrustError::Str(format!("A frame of {} bytes is larger than the limit of {} bytes.", len, self.max_frame_size)));
+        }
+
+        if buf.len() < 4 + len {
+            return Ok(None);
+        }
+
+        let frame = buf[4..4 + len].to_vec();
+        buf.drain(..4 + len);
+        Ok(Some(frame))
+    }
+
+    fn encode(&mut self, data: &[u8], buf: &mut Vec<u8>) -> ThrustResult<()> {
+        buf.write_u32::<BigEndian>(data.len() as u32)?;
+        buf.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Compresses the THeader frames written by a `Framing::Header` connection, and
+/// decompresses the ones it reads. See `Compression`.
+pub struct Compressing<C: Codec> {
+    inner: C,
+    compression: Compression,
+    /// The largest a frame can get once it's decompressed.
+    max_size: usize
+}
+
+impl<C: Codec> Codec for Compressing<C> {
+    fn decode(&mut self, buf: &mut Vec<u8>) -> ThrustResult<Option<Vec<u8>>> {
+        let frame = match self.inner.decode(buf)? {
+            Some(frame) => frame,
+            None => return Ok(None)
+        };
+
+        let (frame, transform) = header::decompress(frame, self.max_size)?;
+        if self.compression.transform.is_none() {
+            self.compression.transform = transform;
+        }
+
+        Ok(Some(frame))
+    }
+
+    fn encode(&mut self, data: &[u8], buf: &mut Vec<u8>) -> ThrustResult<()> {
+        match self.compression.transform {
+            Some(transform) => {
+                let frame = header::compress(data.to_vec(), transform, self.compression.threshold)?;
+                self.inner.encode(&frame, buf)
             },
-            FrameState::Writing => return Err(ThrustError::Other)
+            None => self.inner.encode(data, buf)
         }
+    }
+}
+
+/// An unframed codec. Outgoing messages are written as-is and incoming messages are
+/// delimited by incrementally parsing the binary protocol.
+pub struct BufferedTransport {
+    scanner: binary_protocol::MessageScanner,
+    max_message_size: usize
+}
+
+impl BufferedTransport {
+    pub fn new() -> BufferedTransport {
+        BufferedTransport::with_max_message_size(Limits::default().max_frame_size)
+    }
 
-        Ok(None)
+    /// Fail to decode messages longer than `max_message_size` bytes.
+    pub fn with_max_message_size(max_message_size: usize) -> BufferedTransport {
+        BufferedTransport {
+            scanner: binary_protocol::MessageScanner::new(),
+            max_message_size: max_message_size
+        }
     }
 }
 
+impl Codec for BufferedTransport {
+    fn decode(&mut self, buf: &mut Vec<u8>) -> ThrustResult<Option<Vec<u8>>> {
+        match self.scanner.scan(buf)? {
+            Some(len) if len > self.max_message_size => {},
+            Some(len) => return Ok(Some(buf.drain(..len).collect())),
+            None if buf.len() > self.max_message_size => {},
+            None => return Ok(None)
+        }
+
+        Err(ThrustError::Str(format!("A message is larger than the limit of {} bytes.", self.max_message_size)))
+    }
+
+    fn encode(&mut self, data: &[u8], buf: &mut Vec<u8>) -> ThrustResult<()> {
+        buf.extend_from_slice(data);
+        Ok(())
+    }
+}
 
 pub struct Connection {
-    stream: TcpStream,
-    addr: SocketAddr,
+    stream: Stream,
+    addr: Addr,
     pub token: Token,
-    state: State,
+    /// The listener that accepted this connection. Client connections have none.
+    listener: Option<Token>,
+    codec: Box<Codec>,
     chan: Sender<Dispatch>,
+    limits: Limits,
+    /// The number of requests dispatched from this connection that are still waiting
+    /// on a reply. Only tracked for connections accepted by a listener.
+    in_flight: usize,
+    /// Data read from the socket that hasn't been decoded into a frame yet.
     rbuffer: Vec<u8>,
-    wbuffer: Cursor<Vec<u8>>
+    wbuffer: Cursor<Vec<u8>>,
+    traffic: Arc<Traffic>,
+    /// The id of the TLS handshake the connection's deadline was set for.
+    handshake: Option<usize>
 }
 
 impl Connection {
-    pub fn new(conn: (TcpStream, SocketAddr), token: Token, chan: Sender<Dispatch>) -> Self {
+    pub fn new(conn: (Stream, Addr), token: Token, listener: Option<Token>,
+               codec: Box<Codec>, chan: Sender<Dispatch>, limits: Limits) -> Self {
         Connection {
+            traffic: metrics::opened(&conn.1, listener.is_some()),
             stream: conn.0,
             addr: conn.1,
             token: token,
-            state: State::Reading,
+            listener: listener,
+            codec: codec,
             chan: chan,
+            limits: limits,
+            in_flight: 0,
             rbuffer: vec![],
-            wbuffer: Cursor::new(vec![])
+            wbuffer: Cursor::new(vec![]),
+            handshake: None
         }
     }
 
-    pub fn reset(&mut self, event_loop: &mut EventLoop<Reactor>) {
-        event_loop.timeout(Timeout::Reconnect(self.token), Duration::from_millis(10));
-    }
+    /// Handle a socket event. Any error returned means the connection is no longer
+    /// usable and should be torn down by the `Reactor`.
+    pub fn ready(&mut self, event_loop: &mut EventLoop<Reactor>, events: EventSet) -> ThrustResult<()> {
+        if events.is_readable() {
+            self.readable()?;
+        }
 
-    pub fn ready(&mut self, event_loop: &mut EventLoop<Reactor>, events: EventSet) {
-        match self.state {
-            State::Reading if events.is_readable() => {
-                match self.readable() {
-                    Ok(_) => {},
-                    Err(err) => {
-                        println!("[reactor]: could not dispatch incoming data. {:?}", err);
-                        panic!("{:?}", err);
-                    }
-                }
-                self.reregister(event_loop, self.token);
-            },
-            State::Writing if events.is_writable() => {
-                self.writable();
-                self.reregister(event_loop, self.token);
-            },
-            _ => {
-                self.reregister(event_loop, self.token);
-            }
+        if events.is_writable() {
+            self.writable()?;
         }
+
+        self.reregister(event_loop, self.token)
     }
 
-    pub fn read(&mut self) -> ThrustResult<Option<Vec<u8>>> {
-        match self.state {
-            State::Reading => {
-                let len = self.stream.read_u32::<BigEndian>()?;
-                self.state = State::ReadingFrame(0);
-                self.rbuffer = Vec::with_capacity(len as usize);
-                self.read()
-            },
-            State::ReadingFrame(ref mut len) => {
-                match self.stream.try_read_buf(&mut self.rbuffer) {
-                    Ok(Some(n)) => {
-                        if *len + n == self.rbuffer.len() {
-                            let buf = mem::replace(&mut self.rbuffer, Vec::new());
-                            Ok(Some(buf))
-                        } else {
-                            *len += n;
-                            // We don't have a complete frame yet.
-                            Ok(None)
-                        }
-                    },
-                    Ok(None) => Err(ThrustError::NotReady),
-                    Err(err) => Err(ThrustError::Other)
-                }
-            },
-            _ => Err(ThrustError::Other)
-        }
+    /// Buffer a frame to be written and make sure we get notified once the socket
+    /// is writable if it couldn't be flushed right away.
+    pub fn send(&mut self, event_loop: &mut EventLoop<Reactor>, data: &[u8]) -> ThrustResult<()> {
+        self.write(data)?;
+
+        // A reply might have unpaused the connection while frames are still buffered.
+        // There won't be another readable event for those, so dispatch them now.
+        self.dispatch_frames()?;
+        self.reregister(event_loop, self.token)
     }
 
-    pub fn writable(&mut self) -> ThrustResult<()> {
-        // Flush the whole buffer. The socket can, at any time, be unwritable. Thus, we
-        // need to keep track of what we've written so far.
-        while self.wbuffer.has_remaining() {
-            self.flush();
+    /// Give up the in-flight slot of a request that won't be replied to, which might
+    /// unpause the connection.
+    pub fn release(&mut self, event_loop: &mut EventLoop<Reactor>) -> ThrustResult<()> {
+        if self.in_flight > 0 {
+            self.in_flight -= 1;
         }
 
-        self.state = State::Reading;
+        self.dispatch_frames()?;
+        self.reregister(event_loop, self.token)
+    }
+
+    /// The number of bytes buffered that haven't been written to the socket yet.
+    pub fn pending_writes(&self) -> usize {
+        self.wbuffer.remaining()
+    }
+
+    /// A connection is paused when it has too many outstanding requests or too much
+    /// unflushed data. Paused connections aren't read from until they drain.
+    pub fn is_paused(&self) -> bool {
+        self.in_flight >= self.limits.max_in_flight ||
+            self.pending_writes() >= self.limits.write_high_water
+    }
 
+    pub fn writable(&mut self) -> ThrustResult<()> {
+        self.stream.handshake()?;
+
+        // The socket can, at any time, be unwritable. Whatever isn't flushed now stays
+        // buffered and we'll be notified once the socket is writable again.
+        self.flush()?;
         Ok(())
     }
 
     pub fn readable(&mut self) -> ThrustResult<()> {
-        while let Ok(op) = self.read() {
-            match op {
-                Some(buf) => {
-                    self.state = State::Reading;
-                    println!("[reactor/connection]: reading data from {:?}", self.token);
-                    try!(self.chan.send(Dispatch::Data(self.token, buf)));
-                },
-                None => {}
-            }
+        // Leave the data in the socket while paused, that's what pushes back on the peer.
+        if self.is_paused() {
+            return Ok(());
         }
 
-        self.state = State::Writing;
+        let buffered = self.rbuffer.len();
+        let open = read_available(&mut self.stream, &mut self.rbuffer)?;
+        self.traffic.read(self.rbuffer.len() - buffered);
+
+        // Frames that arrived before the peer hung up are still dispatched.
+        self.dispatch_frames()?;
+
+        if open {
+            Ok(())
+        } else {
+            Err(ThrustError::Disconnected)
+        }
+    }
+
+    /// Decode and dispatch every complete frame that's been buffered, for as long as
+    /// the connection isn't paused.
+    fn dispatch_frames(&mut self) -> ThrustResult<()> {
+        while !self.is_paused() {
+            let buf = match self.codec.decode(&mut self.rbuffer)? {
+                Some(buf) => buf,
+                None => break
+            };
+
+            if self.listener.is_some() {
+                self.in_flight += 1;
+            }
+
+            trace!("reading data; token={:?} peer={:?} bytes={}", self.token, self.addr, buf.len());
+            let peer = Peer {
+                addr: self.addr.clone(),
+                identity: self.stream.identity()
+            };
+
+            try!(self.chan.send(Dispatch::Data(self.token, peer, buf)));
+        }
 
         Ok(())
     }
 
+    /// Whether the connection is still in the TLS handshake `id` was set for.
+    fn is_handshaking(&self, id: usize) -> bool {
+        self.handshake == Some(id) && self.stream.is_handshaking()
+    }
+
     fn register(&mut self, event_loop: &mut EventLoop<Reactor>, token: Token) -> ThrustResult<()> {
-        event_loop.register(&self.stream, token, EventSet::readable(), PollOpt::edge() | PollOpt::oneshot())?;
+        if self.stream.is_handshaking() {
+            let id = NEXT_HANDSHAKE.fetch_add(1, Ordering::Relaxed);
+            if let Err(err) = event_loop.timeout(Timeout::Handshake(token, id), self.limits.handshake_timeout) {
+                return Err(ThrustError::Str(format!("Failed to set the handshake deadline. {:?}", err)));
+            }
+
+            self.handshake = Some(id);
+        }
+
+        let mut event_set = EventSet::readable();
+
+        // A TLS client sends the first message of the handshake once it's connected.
+        if self.stream.wants_write() {
+            event_set = event_set | EventSet::writable();
+        }
+
+        event_loop.register(self.stream.evented(), token, event_set, PollOpt::edge() | PollOpt::oneshot())?;
         Ok(())
     }
 
     pub fn reregister(&self, event_loop: &mut EventLoop<Reactor>, token: Token) -> ThrustResult<()> {
-        let event_set = match self.state {
-            State::Reading => EventSet::readable(),
-            State::Writing => EventSet::writable(),
-            _ => EventSet::none()
-        };
+        let mut event_set = EventSet::none();
+
+        if !self.is_paused() {
+            event_set = event_set | EventSet::readable();
+        }
+
+        if self.pending_writes() > 0 || self.stream.wants_write() {
+            event_set = event_set | EventSet::writable();
+        }
 
-        event_loop.reregister(&self.stream, self.token, event_set, PollOpt::oneshot())?;
+        event_loop.reregister(self.stream.evented(), self.token, event_set, PollOpt::edge() | PollOpt::oneshot())?;
         Ok(())
     }
 }
 
 impl Write for Connection {
     fn write(&mut self, data: &[u8]) -> io::Result<usize> {
-        self.wbuffer.get_mut().write_u32::<BigEndian>(data.len() as u32)?;
-        self.wbuffer.get_mut().write(data)?;
+        if let Err(err) = self.codec.encode(data, self.wbuffer.get_mut()) {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", err)));
+        }
+
+        // Anything written to an accepted connection is a reply to one of its requests.
+        if self.listener.is_some() && self.in_flight > 0 {
+            self.in_flight -= 1;
+        }
+
         self.flush()?;
-        Ok(0)
+        Ok(data.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        match self.stream.try_write_buf(&mut self.wbuffer) {
-            Ok(Some(_)) => Ok(()),
-            Ok(None) => Ok(()),
-            Err(err) => Err(err)
+        while self.wbuffer.has_remaining() {
+            match self.stream.try_write_buf(&mut self.wbuffer)? {
+                Some(0) | None => break,
+                Some(n) => self.traffic.written(n)
+            }
         }
+
+        // Reclaim the buffer once everything has been written.
+        if !self.wbuffer.has_remaining() {
+            self.wbuffer.get_mut().clear();
+            self.wbuffer.set_position(0);
+        }
+
+        Ok(())
     }
 }
 
+impl Drop for Connection {
+    fn drop(&mut self) {
+        metrics::closed(&self.traffic);
+    }
+}
+
+/// A bound listener along with the channel every accepted socket dispatches to.
+pub struct Listener {
+    socket: ListenerSocket,
+    chan: Sender<Dispatch>,
+    framing: Framing,
+    compression: Compression,
+    limits: Limits,
+    security: Security,
+    /// The number of open connections that were accepted by this listener.
+    connections: usize,
+    /// Whether the listener has been deregistered after hitting `max_connections`.
+    paused: bool
+}
+
 /// The `Reactor` is the component that interacts with networking. The reactor is
-/// built around Mio's event loop and manages both TcpListeners and TcpStreams.
+/// built around Mio's event loop and manages listeners and streams over both TCP and
+/// Unix domain sockets.
 ///
 /// The reactor isn't responsible for anything Thrift related, so it doesn't know
 /// about parsing, protocols, serialization, etc... All it's responsible for
@@ -286,7 +820,7 @@ impl Write for Connection {
 ///
 /// Things you might send to the `Reactor` through this mechanism:
 ///
-/// 1. Binding a new TCP listener &mdash; Each reactor is capable of handling an unbounded
+/// 1. Binding a new TCP or Unix socket listener &mdash; Each reactor is capable of handling an unbounded
 /// number of listeners, who will all be able to accept new sockets.
 ///
 /// Binding a new listener requires that you have already established a blocking variant
@@ -301,10 +835,12 @@ impl Write for Connection {
 /// let (tx, rx) = channel();
 /// let addr = "127.0.0.1:4566".parse().unwrap();
 ///
-/// reactor_sender.send(Message::Bind(addr, tx));
+/// reactor_sender.send(Message::Bind(addr, Framing::Framed, Compression::default(), Limits::default(),
+///                                   Security::Plain, id_tx, tx));
 /// ```
 ///
-/// 2. Connecting to a remote TCP server and establishing a new non-blocking `TcpStream`.
+/// 2. Connecting to a remote TCP or Unix socket server and establishing a new non-blocking
+/// stream.
 ///
 /// ```notrust
 /// use std::sync::mpsc::channel;
@@ -312,7 +848,7 @@ impl Write for Connection {
 /// // The callback channel on the single socket.
 /// let (tx, rx) = channel();
 /// let addr = "127.0.0.1::4566".parse().unwrap();
-/// reactor_sender.send(Message::Connect(addr, tx));
+/// reactor_sender.send(Message::Connect(addr, Framing::Framed, Compression::default(), Security::Plain, id_tx, tx));
 /// ```
 ///
 ///
@@ -324,28 +860,35 @@ impl Write for Connection {
 /// reactor_sender.send(Message::Rpc(Token(1), vec![0, 1, 3, 4]));
 /// ```
 pub struct Reactor {
-    listeners: HashMap<Token, TcpListener>,
-    connections: HashMap<Token, Connection>,
-    /// Channels that are sent from `::Bind` messages to establish a listener will
-    /// be appended in this map. All subsequent sockets being accepted from the listener
-    /// will use the same sender channel to consolidate communications.
-    servers: HashMap<Token, Sender<Dispatch>>,
-    /// The `Reactor` manages a count of the number of tokens, the number being
-    /// the token used for the next allocated resource. Tokens are used sequentially
-    /// across both listeners and connections.
-    current_token: usize
+    /// Channels that are sent from `::Bind` messages are kept alongside their listener.
+    /// All subsequent sockets being accepted from the listener will use the same sender
+    /// channel to consolidate communications.
+    listeners: Slab<Listener, Token>,
+    /// Tokens of closed connections are handed out again, so a long-running `Reactor`
+    /// never runs out of token space.
+    connections: Slab<Connection, Token>
 }
 
 impl Reactor {
     pub fn new() -> Reactor {
         Reactor {
-            listeners: HashMap::new(),
-            connections: HashMap::new(),
-            servers: HashMap::new(),
-            current_token: 0
+            listeners: Slab::new_starting_at(Token(0), MAX_LISTENERS),
+            connections: Slab::new_starting_at(Token(MAX_LISTENERS), INITIAL_CONNECTIONS)
         }
     }
 
+    /// Allocate a token for a new connection, growing the slab when it's full.
+    fn insert_connection<F>(&mut self, f: F) -> Token
+        where F: FnOnce(Token) -> Connection
+    {
+        if !self.connections.has_remaining() {
+            let count = self.connections.count();
+            self.connections.grow(count);
+        }
+
+        self.connections.insert_with(f).expect("The connection slab is full after growing.")
+    }
+
     pub fn run() -> JoinHandle<()> {
         thread::spawn(move || {
             let mut event_loop = EVENT_LOOP.lock().expect("Failed to take the `EVENT_LOOP` lock.");
@@ -354,13 +897,28 @@ impl Reactor {
         })
     }
 
+    /// Stop the event loop started by `run`, dropping all of its listeners and
+    /// connections.
+    pub fn shutdown() -> ThrustResult<()> {
+        Ok(SENDER.send(Message::Shutdown)?)
+    }
+
     pub fn incoming_timeout(&mut self, event_loop: &mut EventLoop<Self>, timeout: Timeout) -> ThrustResult<()> {
         match timeout {
-            Timeout::Reconnect(token) => {
-                let mut conn = self.connections.get_mut(&token).expect("Could not find the connection.");
-                let mut stream = TcpStream::connect(&conn.addr)?;
-                conn.stream = stream;
-                conn.register(event_loop, token);
+            // The dispatcher might be gone already, which is fine.
+            Timeout::Deadline(seq, tx) => {
+                tx.send(seq);
+            },
+            Timeout::Handshake(token, id) => {
+                let stalled = match self.connections.get(token) {
+                    Some(conn) => conn.is_handshaking(id),
+                    None => false
+                };
+
+                if stalled {
+                    warn!("closing a connection stalled in the TLS handshake; token={:?}", token);
+                    self.close_connection(event_loop, token)?;
+                }
             }
         }
 
@@ -370,63 +928,188 @@ impl Reactor {
     pub fn incoming_msg(&mut self, event_loop: &mut EventLoop<Self>, msg: Message) -> ThrustResult<()> {
         match msg {
             Message::Rpc(id, data) => {
-                println!("[reactor]: rpc @ {:?}", id);
-                self.connections.get_mut(&id).expect("connection was not found #2").write(&*data);
+                trace!("rpc; token={:?} bytes={}", id, data.len());
+                let res = match self.connections.get_mut(id) {
+                    Some(conn) => conn.send(event_loop, &*data),
+                    None => {
+                        debug!("dropping an rpc for an unknown connection; token={:?}", id);
+                        return Ok(());
+                    }
+                };
+
+                if let Err(err) = res {
+                    warn!("failed to write; token={:?} err={:?}", id, err);
+                    self.close_connection(event_loop, id)?;
+                }
+            },
+            Message::Release(id) => {
+                let res = match self.connections.get_mut(id) {
+                    Some(conn) => conn.release(event_loop),
+                    None => return Ok(())
+                };
+
+                if let Err(err) = res {
+                    debug!("error on connection; token={:?} err={:?}", id, err);
+                    self.close_connection(event_loop, id)?;
+                }
+            },
+            Message::Deadline(after, seq, tx) => {
+                if let Err(err) = event_loop.timeout(Timeout::Deadline(seq, tx), after) {
+                    warn!("failed to set a deadline; seq={} err={:?}", seq, err);
+                }
             },
             Message::Shutdown => {
-                println!("Shutting down...");
+                info!("shutting down");
                 event_loop.shutdown();
             },
-            Message::Connect(addr, id_tx, tx) => {
-                let mut mio_stream = TcpStream::connect(&addr)?;
-                let new_token = Token(self.current_token);
-                id_tx.send(Id(new_token));
-                let mut conn = Connection::new((mio_stream, addr), new_token, tx);
+            // The initiator might have given up waiting, which is fine.
+            Message::Connect(addr, framing, compression, security, id_tx, tx) => {
+                id_tx.send(self.connect(event_loop, Addr::Tcp(addr), framing, compression, security, tx));
+            },
+            Message::ConnectUnix(path, framing, compression, id_tx, tx) => {
+                id_tx.send(self.connect(event_loop, Addr::Unix(path), framing, compression, Security::Plain, tx));
+            },
+            Message::Bind(addr, framing, compression, limits, security, id_tx, tx) => {
+                id_tx.send(self.bind(event_loop, Addr::Tcp(addr), framing, compression, limits, security, tx));
+            },
+            Message::BindUnix(path, framing, compression, limits, id_tx, tx) => {
+                id_tx.send(self.bind(event_loop, Addr::Unix(path), framing, compression, limits, Security::Plain, tx));
+            }
+        }
 
-                println!("[reactor]: binding to {:?} @ {:?}", addr, new_token);
+        Ok(())
+    }
 
-                self.connections.insert(new_token, conn);
-                self.connections.get_mut(&new_token)
-                    .expect("Cannot find the connection from the token {:?}")
-                    .register(event_loop, new_token);
+    fn connect(&mut self, event_loop: &mut EventLoop<Self>, addr: Addr, framing: Framing,
+               compression: Compression, security: Security, tx: Sender<Dispatch>) -> ThrustResult<Id> {
+        framing.check(&compression)?;
+        let stream = Stream::connect(&addr)?.secure(&security)?;
 
-                self.current_token += 1;
-            },
-            Message::Bind(addr, id_tx, tx) => {
-                let token = Token(self.current_token);
-                let mut lis = TcpListener::bind(&addr)?;
+        info!("connecting; addr={:?}", addr);
 
-                id_tx.send(Id(token));
-                self.servers.insert(token, tx);
+        let limits = Limits::default();
+        let new_token = self.insert_connection(|token| {
+            Connection::new((stream, addr.clone()), token, None, framing.codec(compression, &limits), tx, limits)
+        });
 
-                println!("[reactor]: binding to {:?} @ {:?}", addr, token);
+        if let Err(err) = self.connections[new_token].register(event_loop, new_token) {
+            self.connections.remove(new_token);
+            return Err(err);
+        }
 
-                event_loop.register(&lis, token, EventSet::readable(), PollOpt::edge())?;
-                self.listeners.insert(token, lis);
-                self.current_token += 1;
-            }
+        Ok(Id(new_token, addr))
+    }
+
+    fn bind(&mut self, event_loop: &mut EventLoop<Self>, addr: Addr, framing: Framing, compression: Compression,
+            limits: Limits, security: Security, tx: Sender<Dispatch>) -> ThrustResult<Id> {
+        framing.check(&compression)?;
+        let lis = ListenerSocket::bind(&addr)?;
+        let local_addr = lis.local_addr()?;
+        let token = match self.listeners.insert(Listener {
+            socket: lis,
+            chan: tx,
+            framing: framing,
+            compression: compression,
+            limits: limits,
+            security: security,
+            connections: 0,
+            paused: false
+        }) {
+            Ok(token) => token,
+            Err(_) => return Err(ThrustError::Str(format!("Cannot bind more than {} listeners.", MAX_LISTENERS)))
+        };
+
+        info!("binding; addr={:?} token={:?}", local_addr, token);
+
+        if let Err(err) = event_loop.register(self.listeners[token].socket.evented(), token, EventSet::readable(), PollOpt::edge()) {
+            self.listeners.remove(token);
+            return Err(err.into());
         }
 
-        Ok(())
+        Ok(Id(token, local_addr))
     }
 
-    pub fn accept_connection(&mut self, event_loop: &mut EventLoop<Self>, token: Token) {
-        let mut listener = self.listeners.get_mut(&token).expect("Listener was not found.");
-        match listener.accept() {
-            Ok(Some(socket)) => {
-                let clone = self.servers[&token].clone();
-                let new_token = Token(self.current_token);
-                let mut conn = Connection::new(socket, new_token, clone);
+    /// Accept every pending socket on the listener until it would block or the listener
+    /// reaches its connection limit. At the limit the listener is deregistered and the
+    /// remaining sockets wait in the kernel's backlog until a connection closes.
+    pub fn accept_connection(&mut self, event_loop: &mut EventLoop<Self>, token: Token) -> ThrustResult<()> {
+        loop {
+            let (socket, chan, codec, limits) = {
+                let mut listener = match self.listeners.get_mut(token) {
+                    Some(listener) => listener,
+                    None => return Ok(())
+                };
 
-                self.connections.insert(new_token, conn);
-                self.connections.get_mut(&new_token)
-                    .expect("Cannot find the connection in the cache.")
-                    .register(event_loop, new_token);
+                if listener.connections >= listener.limits.max_connections {
+                    if !listener.paused {
+                        warn!("listener reached its connection limit; token={:?}", token);
+                        event_loop.deregister(listener.socket.evented())?;
+                        listener.paused = true;
+                    }
 
-                self.current_token += 1;
-            },
-            _ => {}
+                    return Ok(());
+                }
+
+                let (stream, addr) = match listener.socket.accept()? {
+                    Some(socket) => socket,
+                    None => return Ok(())
+                };
+
+                // A TLS handshake that can't even be started only costs this socket.
+                let stream = match stream.secure(&listener.security) {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("failed to secure a connection; peer={:?} err={:?}", addr, err);
+                        continue;
+                    }
+                };
+
+                listener.connections += 1;
+                ((stream, addr), listener.chan.clone(), listener.framing.codec(listener.compression, &listener.limits), listener.limits)
+            };
+
+            let new_token = self.insert_connection(|new_token| {
+                Connection::new(socket, new_token, Some(token), codec, chan, limits)
+            });
+
+            // Removing the connection again counts it off the listener.
+            if let Err(err) = self.connections[new_token].register(event_loop, new_token) {
+                warn!("failed to register a connection; token={:?} err={:?}", new_token, err);
+                self.close_connection(event_loop, new_token)?;
+            }
+        }
+    }
+
+    /// Tear down a connection and let its owner know through `Dispatch::Closed`. If it
+    /// was accepted by a listener that has been paused at its connection limit, start
+    /// accepting on that listener again.
+    pub fn close_connection(&mut self, event_loop: &mut EventLoop<Self>, token: Token) -> ThrustResult<()> {
+        let conn = match self.connections.remove(token) {
+            Some(conn) => conn,
+            None => return Ok(())
+        };
+
+        debug!("closing connection; token={:?} peer={:?}", token, conn.addr);
+
+        // The socket might already be gone on the OS side, there's nothing else to do
+        // if deregistering fails.
+        event_loop.deregister(conn.stream.evented());
+
+        // The owner might not be listening anymore, which is fine.
+        conn.chan.send(Dispatch::Closed(token));
+
+        if let Some(lis_token) = conn.listener {
+            if let Some(listener) = self.listeners.get_mut(lis_token) {
+                listener.connections -= 1;
+
+                if listener.paused && listener.connections < listener.limits.max_connections {
+                    event_loop.register(listener.socket.evented(), lis_token, EventSet::readable(), PollOpt::edge())?;
+                    listener.paused = false;
+                }
+            }
         }
+
+        Ok(())
     }
 }
 
@@ -435,43 +1118,47 @@ impl Handler for Reactor {
     type Message = Message;
 
     fn ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token, events: EventSet) {
-        if events.is_hup() {
-            if self.connections.contains_key(&token) {
-                let mut conn = self.connections.get_mut(&token)
-                    .expect("Cannot find the connection in the cache.")
-                    .reset(event_loop);
+        if self.listeners.contains(token) {
+            if events.is_error() {
+                error!("error on listener; token={:?}", token);
+            } else if events.is_readable() {
+                if let Err(err) = self.accept_connection(event_loop, token) {
+                    warn!("failed to accept a connection; token={:?} err={:?}", token, err);
+                }
             }
 
             return;
         }
 
-        if events.is_error() {
-            println!("Err: {:?}", events);
-            return;
-        }
-
-        if events.is_readable() && self.listeners.contains_key(&token) {
-            self.accept_connection(event_loop, token);
-            return;
-        }
+        // Read whatever the peer sent before hanging up, then tear the connection down.
+        let res = match self.connections.get_mut(token) {
+            Some(conn) => conn.ready(event_loop, events),
+            None => return
+        };
 
-        if self.connections.contains_key(&token) {
-            self.connections.get_mut(&token).expect("connection was not found #1").ready(event_loop, events);
+        match res {
+            Ok(_) if !events.is_hup() && !events.is_error() => {},
+            Ok(_) | Err(ThrustError::Disconnected) => {
+                self.close_connection(event_loop, token);
+            },
+            Err(err) => {
+                debug!("error on connection; token={:?} err={:?}", token, err);
+                self.close_connection(event_loop, token);
+            }
         }
     }
 
-    /// XXX: Timeouts would be useful to implement.
     fn timeout(&mut self, event_loop: &mut EventLoop<Self>, timeout: Timeout) {
         match self.incoming_timeout(event_loop, timeout) {
             Ok(_) => {},
-            Err(err) => panic!("An error occurred while handling a timeout")
+            Err(err) => error!("error handling a timeout; err={:?}", err)
         }
     }
 
     fn notify(&mut self, event_loop: &mut EventLoop<Self>, msg: Message) {
         match self.incoming_msg(event_loop, msg) {
             Ok(_) => {},
-            Err(err) => panic!("Reactor failed to handle incoming msg")
+            Err(err) => error!("failed to handle an incoming message; err={:?}", err)
         }
     }
 }
@@ -487,57 +1174,181 @@ mod tests {
     use std::time::Duration;
     use std::net::{TcpListener, TcpStream, SocketAddr};
     use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+    use protocol::ThriftMessageType;
+    use header::{self, Header, Transform, BINARY_PROTOCOL};
+    use util;
 
-    // #[test]
-    // fn should_read_frame() {
-    //     let mut buf = vec![1, 2, 3];
-    //     let mut source = Vec::new();
-    //     source.write_u32::<BigEndian>(3);
-    //     source.write(&mut buf);
-
-    //     let mut source = Cursor::new(source);
-    //     let mut framed = FramedTransport::new();
-    //     let buf = match framed.read(&mut source) {
-    //         Ok(Some(buf)) => buf,
-    //         Ok(None) => panic!("Could not read the next frame from the socket."),
-    //         Err(err) => panic!("Tests failed.")
-    //     };
-
-    //     assert_eq!(&buf[..], &[1, 2, 3]);
-    // }
-
-    // #[test]
-    // fn should_error_on_incomplete_frame() {
-    //     let mut buf = vec![1, 2];
-    //     let mut source = Vec::new();
-    //     source.write_u32::<BigEndian>(3);
-    //     source.write(&mut buf);
-
-    //     let mut source = Cursor::new(source);
-    //     let mut framed = FramedTransport::new();
-    //     match framed.read(&mut source) {
-    //         Ok(Some(buf)) => panic!("We shouldn't have gotten a read frame back."),
-    //         Ok(None) => {},
-    //         Err(err) => panic!("Tests failed. {:?}", err)
-    //     }
-    // }
-
-    // #[test]
-    // fn should_eventually_read_delayed_frame() {
-    //     let mut buf = vec![];
-    //     let mut source = Vec::new();
-    //     source.write_u32::<BigEndian>(3);
-    //     source.write(&mut buf);
-
-    //     let mut reader = Cursor::new(source);
-    //     let mut framed = FramedTransport::new();
-    //     match framed.read(&mut reader) {
-    //         Ok(Some(buf)) => panic!("We shouldn't have gotten a read frame back."),
-    //         Ok(None) => {},
-    //         Err(err) => panic!("Tests failed. {:?}", err)
-    //     }
-    // }
+    /// Bind a listener to an ephemeral port on the loopback interface, and return the
+    /// address it's bound to.
+    fn bind_local(sender: &mio::Sender<Message>, limits: Limits, tx: Sender<Dispatch>) -> SocketAddr {
+        let (id_tx, id_rx) = channel();
+        sender.send(Message::Bind("127.0.0.1:0".parse().unwrap(), Framing::Framed, Compression::default(), limits, Security::Plain, id_tx, tx));
+        match id_rx.recv().expect("[test]: Receiving from channel `id_rx` failed.") {
+            Ok(Id(_, Addr::Tcp(addr))) => addr,
+            Ok(Id(_, addr)) => panic!("[test]: Expected a TCP address, got {:?}.", addr),
+            Err(err) => panic!("[test]: Binding failed. {:?}", err)
+        }
+    }
 
+    #[test]
+    fn should_read_frame() {
+        let mut buf = vec![1, 2, 3];
+        let mut source = Vec::new();
+        source.write_u32::<BigEndian>(3);
+        source.write(&mut buf);
+
+        let mut source = Cursor::new(source);
+        let mut framed = FramedTransport::new();
+        let buf = match framed.read(&mut source) {
+            Ok(Some(buf)) => buf,
+            Ok(None) => panic!("Could not read the next frame from the socket."),
+            Err(err) => panic!("Tests failed.")
+        };
+
+        assert_eq!(&buf[..], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn should_error_on_incomplete_frame() {
+        let mut buf = vec![1, 2];
+        let mut source = Vec::new();
+        source.write_u32::<BigEndian>(3);
+        source.write(&mut buf);
+
+        let mut source = Cursor::new(source);
+        let mut framed = FramedTransport::new();
+        match framed.read(&mut source) {
+            Ok(Some(buf)) => panic!("We shouldn't have gotten a read frame back."),
+            Ok(None) => {},
+            Err(err) => panic!("Tests failed. {:?}", err)
+        }
+    }
+
+    #[test]
+    fn should_eventually_read_delayed_frame() {
+        let mut buf = vec![];
+        let mut source = Vec::new();
+        source.write_u32::<BigEndian>(3);
+        source.write(&mut buf);
+
+        let mut reader = Cursor::new(source);
+        let mut framed = FramedTransport::new();
+        match framed.read(&mut reader) {
+            Ok(Some(buf)) => panic!("We shouldn't have gotten a read frame back."),
+            Ok(None) => {},
+            Err(err) => panic!("Tests failed. {:?}", err)
+        }
+
+        let mut reader = Cursor::new(vec![1, 2, 3]);
+        match framed.read(&mut reader) {
+            Ok(Some(buf)) => assert_eq!(&buf[..], &[1, 2, 3]),
+            Ok(None) => panic!("Could not read the delayed frame."),
+            Err(err) => panic!("Tests failed. {:?}", err)
+        }
+    }
+
+    #[test]
+    fn should_decode_partial_length_header() {
+        let mut framed = FramedTransport::new();
+        let mut buf = vec![0, 0];
+        assert_eq!(framed.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(&[0, 1, 9]);
+        assert_eq!(framed.decode(&mut buf).unwrap(), Some(vec![9]));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn should_decode_multiple_frames() {
+        let mut framed = FramedTransport::new();
+        let mut buf = Vec::new();
+        framed.encode(b"abc", &mut buf).unwrap();
+        framed.encode(b"de", &mut buf).unwrap();
+        buf.write_u32::<BigEndian>(5);
+
+        assert_eq!(framed.decode(&mut buf).unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(framed.decode(&mut buf).unwrap(), Some(b"de".to_vec()));
+        assert_eq!(framed.decode(&mut buf).unwrap(), None);
+        assert_eq!(buf.len(), 4);
+    }
+
+    #[test]
+    fn should_decode_zero_length_frame() {
+        let mut framed = FramedTransport::new();
+        let mut buf = Vec::new();
+        framed.encode(b"", &mut buf).unwrap();
+        framed.encode(b"a", &mut buf).unwrap();
+
+        assert_eq!(framed.decode(&mut buf).unwrap(), Some(vec![]));
+        assert_eq!(framed.decode(&mut buf).unwrap(), Some(b"a".to_vec()));
+    }
+
+    #[test]
+    fn should_reject_frames_over_the_limit() {
+        let mut framed = FramedTransport::with_max_frame_size(4);
+        let mut buf = Vec::new();
+        framed.encode(b"abcd", &mut buf).unwrap();
+        assert_eq!(framed.decode(&mut buf).unwrap(), Some(b"abcd".to_vec()));
+
+        // Only the length has arrived, which is enough to know the frame is too large.
+        buf.write_u32::<BigEndian>(0xFFFFFFFF);
+        assert!(framed.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn should_decode_unframed_messages() {
+        let msg = util::create_empty_thrift_message("foobar123", ThriftMessageType::Call);
+        let mut buffered = BufferedTransport::new();
+        let mut buf = Vec::new();
+        buffered.encode(&msg, &mut buf).unwrap();
+        buffered.encode(&msg, &mut buf).unwrap();
+        assert_eq!(buf.len(), msg.len() * 2);
+
+        // Only part of the second message has been received.
+        buf.pop();
+
+        assert_eq!(buffered.decode(&mut buf).unwrap(), Some(msg.clone()));
+        assert_eq!(buffered.decode(&mut buf).unwrap(), None);
+        assert_eq!(buf.len(), msg.len() - 1);
+    }
+
+    #[test]
+    fn should_compress_header_frames() {
+        let frame = header::wrap(&Header::new(BINARY_PROTOCOL), &[1u8; 1000]).unwrap();
+        let mut codec = Framing::Header.codec(Compression {
+            transform: Some(Transform::Zlib),
+            threshold: 100
+        }, &Limits::default());
+
+        let mut buf = Vec::new();
+        codec.encode(&frame, &mut buf).unwrap();
+        assert!(buf.len() < frame.len());
+
+        // Plain frames have nowhere to mark the transform.
+        let mut plain = Vec::new();
+        codec.encode(b"abc", &mut plain).unwrap();
+        assert_eq!(&plain[4..], b"abc");
+
+        // The peer decompresses, and compresses its replies the same way.
+        let mut peer = Framing::Header.codec(Compression::default(), &Limits::default());
+        assert_eq!(peer.decode(&mut buf).unwrap(), Some(frame.clone()));
+
+        let mut reply = Vec::new();
+        peer.encode(&frame, &mut reply).unwrap();
+        assert!(reply.len() < frame.len());
+    }
+
+    #[test]
+    fn should_only_compress_header_frames() {
+        let compression = Compression {
+            transform: Some(Transform::Zlib),
+            threshold: 0
+        };
+
+        assert!(Framing::Framed.check(&compression).is_err());
+        assert!(Framing::Header.check(&compression).is_ok());
+        assert!(Framing::Framed.check(&Compression::default()).is_ok());
+    }
 
     #[test]
     fn create_reactor() {
@@ -550,34 +1361,33 @@ mod tests {
             event_loop.run(&mut reactor);
         });
 
-        // Establish a local TcpListener.
-        let addr: SocketAddr = "127.0.0.1:6543".parse().expect("[test]: Parsing into SocketAddr failed.");
-        let (rpc_server_tx, rpc_server_rx) = channel();
-
         // Create a new non-blocking tcp server.
-        let (id_tx, id_rx) = channel();
-        sender.send(Message::Bind(addr.clone(), id_tx, rpc_server_tx.clone()));
+        let (rpc_server_tx, rpc_server_rx) = channel();
+        let addr = bind_local(&sender, Limits::default(), rpc_server_tx.clone());
 
         let (rpc_client_tx, rpc_client_rx) = channel();
         let (rpc_client_id_tx, rpc_client_id_rx) = channel();
 
-        sender.send(Message::Connect(addr, rpc_client_id_tx, rpc_client_tx));
+        sender.send(Message::Connect(addr, Framing::Framed, Compression::default(), Security::Plain, rpc_client_id_tx, rpc_client_tx));
 
-        let Id(client_id) = rpc_client_id_rx.recv().expect("[test]: Receiving from channel `rpc_client_id_rx` failed.");
+        let Id(client_id, _) = rpc_client_id_rx.recv().expect("[test]: Receiving from channel `rpc_client_id_rx` failed.").expect("[test]: Connecting failed.");
         sender.send(Message::Rpc(client_id, b"abc".to_vec()));
 
         let server = thread::spawn(move || {
             for msg in rpc_server_rx.iter() {
                 match msg {
-                    Dispatch::Data(id, msg) => {
+                    Dispatch::Data(id, _, msg) => {
                         assert_tx.send((id, msg)).expect("Could not assert_tx");
-                    }
+                    },
+                    Dispatch::Closed(_) => {}
                 }
             }
         });
 
         let (new_id, v) = assert_rx.recv().expect("Error trying to assert reactor test.");
-        assert_eq!(new_id, Token(2));
+        // The client connection takes the first connection token, the accepted socket
+        // the one after it.
+        assert_eq!(new_id, Token(MAX_LISTENERS + 1));
         assert_eq!(v.len(), 3);
         assert_eq!(v, b"abc");
 
@@ -585,10 +1395,271 @@ mod tests {
         sender.send(Message::Rpc(new_id, b"bbb".to_vec()));
 
         match rpc_client_rx.recv().expect("[test]: Receiving from channel `rpc_client_rx` failed.") {
-            Dispatch::Data(id, v) => {
+            Dispatch::Data(id, _, v) => {
                 assert_eq!(id, client_id);
                 assert_eq!(v, b"bbb");
+            },
+            Dispatch::Closed(id) => panic!("[test]: Connection {:?} was closed.", id)
+        }
+    }
+
+    #[test]
+    fn should_stop_accepting_past_max_connections() {
+        let mut reactor = Reactor::new();
+        let mut event_loop = EventLoop::new().expect("[test]: EventLoop failed to create.");
+        let sender = event_loop.channel();
+
+        thread::spawn(move || {
+            event_loop.run(&mut reactor);
+        });
+
+        let (rpc_server_tx, rpc_server_rx) = channel();
+        let limits = Limits {
+            max_connections: 1,
+            .. Limits::default()
+        };
+
+        let addr = bind_local(&sender, limits, rpc_server_tx);
+
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let (rpc_client_tx, rpc_client_rx) = channel();
+            let (rpc_client_id_tx, rpc_client_id_rx) = channel();
+            sender.send(Message::Connect(addr, Framing::Framed, Compression::default(), Security::Plain, rpc_client_id_tx, rpc_client_tx));
+
+            let Id(client_id, _) = rpc_client_id_rx.recv().expect("[test]: Receiving from channel `rpc_client_id_rx` failed.").expect("[test]: Connecting failed.");
+            clients.push((client_id, rpc_client_rx));
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        for &(client_id, _) in clients.iter() {
+            sender.send(Message::Rpc(client_id, b"abc".to_vec()));
+        }
+
+        thread::sleep(Duration::from_millis(100));
+
+        // Only the first connection was accepted, the second one waits in the backlog.
+        assert!(rpc_server_rx.try_recv().is_ok());
+        assert!(rpc_server_rx.try_recv().is_err());
+
+        sender.send(Message::Shutdown);
+    }
+
+    #[test]
+    fn should_keep_reading_past_released_requests() {
+        let mut reactor = Reactor::new();
+        let mut event_loop = EventLoop::new().expect("[test]: EventLoop failed to create.");
+        let sender = event_loop.channel();
+
+        thread::spawn(move || {
+            event_loop.run(&mut reactor);
+        });
+
+        let (rpc_server_tx, rpc_server_rx) = channel();
+        let addr = bind_local(&sender, Limits::default(), rpc_server_tx);
+
+        // More one-way requests than `max_in_flight`, followed by a call.
+        let count = Limits::default().max_in_flight * 2;
+        let mut stream = TcpStream::connect(addr).expect("[test]: Connecting to the listener failed.");
+        for i in 0..count + 1 {
+            let frame: &[u8] = if i < count { b"one" } else { b"two" };
+            stream.write_u32::<BigEndian>(frame.len() as u32);
+            stream.write(frame);
+        }
+
+        for _ in 0..count + 1 {
+            match rpc_server_rx.recv().expect("[test]: Receiving from channel `rpc_server_rx` failed.") {
+                Dispatch::Data(id, _, v) => {
+                    if &v[..] == &b"one"[..] {
+                        sender.send(Message::Release(id));
+                    } else {
+                        sender.send(Message::Rpc(id, b"bbb".to_vec()));
+                    }
+                },
+                Dispatch::Closed(id) => panic!("[test]: Connection {:?} was closed.", id)
+            }
+        }
+
+        assert_eq!(stream.read_u32::<BigEndian>().unwrap(), 3);
+        let mut reply = [0u8; 3];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"bbb");
+
+        sender.send(Message::Shutdown);
+    }
+
+    #[test]
+    fn should_close_connection_on_peer_disconnect() {
+        let mut reactor = Reactor::new();
+        let mut event_loop = EventLoop::new().expect("[test]: EventLoop failed to create.");
+        let sender = event_loop.channel();
+
+        thread::spawn(move || {
+            event_loop.run(&mut reactor);
+        });
+
+        let (rpc_server_tx, rpc_server_rx) = channel();
+        let addr = bind_local(&sender, Limits::default(), rpc_server_tx);
+
+        // An Rpc for a connection that doesn't exist shouldn't take down the reactor.
+        sender.send(Message::Rpc(Token(999), b"abc".to_vec()));
+
+        {
+            let mut stream = TcpStream::connect(addr).expect("[test]: Connecting to the listener failed.");
+            stream.write_u32::<BigEndian>(3);
+            stream.write(b"abc");
+        }
+
+        match rpc_server_rx.recv().expect("[test]: Receiving from channel `rpc_server_rx` failed.") {
+            Dispatch::Data(_, _, v) => assert_eq!(v, b"abc"),
+            Dispatch::Closed(id) => panic!("[test]: Connection {:?} was closed before any data was read.", id)
+        }
+
+        match rpc_server_rx.recv().expect("[test]: Receiving from channel `rpc_server_rx` failed.") {
+            Dispatch::Closed(_) => {},
+            Dispatch::Data(..) => panic!("[test]: Expected the connection to be closed.")
+        }
+
+        sender.send(Message::Shutdown);
+    }
+
+    #[test]
+    fn should_reuse_tokens_of_closed_connections() {
+        let mut reactor = Reactor::new();
+        let mut event_loop = EventLoop::new().expect("[test]: EventLoop failed to create.");
+        let sender = event_loop.channel();
+
+        thread::spawn(move || {
+            event_loop.run(&mut reactor);
+        });
+
+        let (rpc_server_tx, rpc_server_rx) = channel();
+        let addr = bind_local(&sender, Limits::default(), rpc_server_tx);
+
+        let mut tokens = Vec::new();
+        for _ in 0..2 {
+            {
+                let mut stream = TcpStream::connect(addr).expect("[test]: Connecting to the listener failed.");
+                stream.write_u32::<BigEndian>(3);
+                stream.write(b"abc");
+            }
+
+            match rpc_server_rx.recv().expect("[test]: Receiving from channel `rpc_server_rx` failed.") {
+                Dispatch::Data(id, ..) => tokens.push(id),
+                Dispatch::Closed(id) => panic!("[test]: Connection {:?} was closed before any data was read.", id)
+            }
+
+            match rpc_server_rx.recv().expect("[test]: Receiving from channel `rpc_server_rx` failed.") {
+                Dispatch::Closed(id) => assert_eq!(id, tokens[tokens.len() - 1]),
+                Dispatch::Data(..) => panic!("[test]: Expected the connection to be closed.")
             }
         }
+
+        assert_eq!(tokens[0], tokens[1]);
+
+        sender.send(Message::Shutdown);
+    }
+
+    #[test]
+    fn should_read_frames_over_unix_socket() {
+        use std::env;
+        use std::fs;
+        use std::os::unix::net::UnixStream;
+        use libc;
+
+        let mut reactor = Reactor::new();
+        let mut event_loop = EventLoop::new().expect("[test]: EventLoop failed to create.");
+        let sender = event_loop.channel();
+
+        thread::spawn(move || {
+            event_loop.run(&mut reactor);
+        });
+
+        let path = env::temp_dir().join(format!("thrust-reactor-{}.sock", unsafe { libc::getpid() }));
+        fs::remove_file(&path);
+
+        let (rpc_server_tx, rpc_server_rx) = channel();
+        let (id_tx, id_rx) = channel();
+        sender.send(Message::BindUnix(path.clone(), Framing::Framed, Compression::default(), Limits::default(), id_tx, rpc_server_tx));
+        id_rx.recv().expect("[test]: Receiving from channel `id_rx` failed.").expect("[test]: Binding failed.");
+
+        {
+            let mut stream = UnixStream::connect(&path).expect("[test]: Connecting to the listener failed.");
+            stream.write_u32::<BigEndian>(3);
+            stream.write(b"abc");
+        }
+
+        match rpc_server_rx.recv().expect("[test]: Receiving from channel `rpc_server_rx` failed.") {
+            Dispatch::Data(_, _, v) => assert_eq!(v, b"abc"),
+            Dispatch::Closed(id) => panic!("[test]: Connection {:?} was closed before any data was read.", id)
+        }
+
+        sender.send(Message::Shutdown);
+        fs::remove_file(&path);
+    }
+
+    #[test]
+    fn should_report_ephemeral_port() {
+        let mut reactor = Reactor::new();
+        let mut event_loop = EventLoop::new().expect("[test]: EventLoop failed to create.");
+        let sender = event_loop.channel();
+
+        thread::spawn(move || {
+            event_loop.run(&mut reactor);
+        });
+
+        let addr: SocketAddr = "127.0.0.1:0".parse().expect("[test]: Parsing into SocketAddr failed.");
+        let (rpc_server_tx, rpc_server_rx) = channel();
+
+        let (id_tx, id_rx) = channel();
+        sender.send(Message::Bind(addr, Framing::Framed, Compression::default(), Limits::default(), Security::Plain, id_tx, rpc_server_tx));
+        let addr = match id_rx.recv().expect("[test]: Receiving from channel `id_rx` failed.") {
+            Ok(Id(_, Addr::Tcp(addr))) => addr,
+            Ok(Id(_, addr)) => panic!("[test]: Expected a TCP address, got {:?}.", addr),
+            Err(err) => panic!("[test]: Binding failed. {:?}", err)
+        };
+
+        assert!(addr.port() != 0);
+
+        {
+            let mut stream = TcpStream::connect(addr).expect("[test]: Connecting to the listener failed.");
+            stream.write_u32::<BigEndian>(3);
+            stream.write(b"abc");
+        }
+
+        match rpc_server_rx.recv().expect("[test]: Receiving from channel `rpc_server_rx` failed.") {
+            Dispatch::Data(_, _, v) => assert_eq!(v, b"abc"),
+            Dispatch::Closed(id) => panic!("[test]: Connection {:?} was closed before any data was read.", id)
+        }
+
+        sender.send(Message::Shutdown);
+    }
+
+    #[test]
+    fn should_report_bind_errors() {
+        let mut reactor = Reactor::new();
+        let mut event_loop = EventLoop::new().expect("[test]: EventLoop failed to create.");
+        let sender = event_loop.channel();
+
+        thread::spawn(move || {
+            event_loop.run(&mut reactor);
+        });
+
+        // Hold on to the port so binding it again fails.
+        let lis = TcpListener::bind("127.0.0.1:0").expect("[test]: Binding the listener failed.");
+        let addr = lis.local_addr().expect("[test]: Getting the local address failed.");
+        let (rpc_server_tx, rpc_server_rx) = channel();
+
+        let (id_tx, id_rx) = channel();
+        sender.send(Message::Bind(addr, Framing::Framed, Compression::default(), Limits::default(), Security::Plain, id_tx, rpc_server_tx));
+        assert!(id_rx.recv().expect("[test]: Receiving from channel `id_rx` failed.").is_err());
+
+        // The reactor is still running after the failed bind.
+        let (id_tx, id_rx) = channel();
+        let (rpc_server_tx, rpc_server_rx) = channel();
+        sender.send(Message::Bind("127.0.0.1:0".parse().unwrap(), Framing::Framed, Compression::default(), Limits::default(), Security::Plain, id_tx, rpc_server_tx));
+        assert!(id_rx.recv().expect("[test]: Receiving from channel `id_rx` failed.").is_ok());
+
+        sender.send(Message::Shutdown);
     }
 }
@@ -6,11 +6,24 @@ use std::sync::mpsc::{SendError, RecvError};
 use mio::NotifyError;
 use reactor::Message;
 use protocol;
+use server::Exception;
+#[cfg(feature = "tls")]
+use openssl::error::ErrorStack;
 
 #[derive(Debug)]
 pub enum ThrustError {
     Other,
     NotReady,
+    /// The peer closed the connection.
+    Disconnected,
+    /// The server replied with an exception instead of a result.
+    Exception(Exception),
+    /// A circuit breaker is open, so the call wasn't sent.
+    CircuitOpen,
+    /// The call was cancelled before its reply arrived.
+    Cancelled,
+    /// The call wasn't replied to before its deadline.
+    Timeout,
     Str(String),
     IO(io::Error),
     ByteOrder(byteorder::Error),
@@ -58,6 +71,13 @@ impl convert::From<byteorder::Error> for ThrustError {
     }
 }
 
+#[cfg(feature = "tls")]
+impl convert::From<ErrorStack> for ThrustError {
+    fn from(val: ErrorStack) -> ThrustError {
+        ThrustError::Str(format!("TLS setup failed. {}", val))
+    }
+}
+
 impl<T> convert::From<PoisonError<T>> for ThrustError {
     fn from(val: PoisonError<T>) -> ThrustError {
         ThrustError::PoisonError
@@ -1,7 +1,18 @@
-use protocol::{Deserializer, ThriftDeserializer, ThriftMessage, Error};
+use protocol::{Deserializer, ThriftDeserializer, ThriftMessage, Protocol, Error};
+use context::Context;
 use tangle::Future;
 
 pub trait Runner {
-    fn run<D>(&mut self, de: &mut D, msg: ThriftMessage) -> Result<Future<Vec<u8>>, Error>
-        where D: Deserializer + ThriftDeserializer;
+    /// The methods the runner serves. Calls to any other method are recorded under
+    /// `metrics::UNKNOWN_METHOD`.
+    fn methods(&self) -> &'static [&'static str] {
+        static NONE: &'static [&'static str] = &[];
+        NONE
+    }
+
+    /// Run the call `msg`, whose arguments are read from `de`. The reply is encoded
+    /// with `protocol`, and `ctx` is what's known about the call.
+    fn run<P, D>(&mut self, ctx: &Context, protocol: &P, de: &mut D, msg: ThriftMessage) -> Result<Future<Vec<u8>>, Error>
+        where P: Protocol,
+              D: Deserializer + ThriftDeserializer;
 }
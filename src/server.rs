@@ -0,0 +1,368 @@
+use std::sync::Arc;
+use std::sync::mpsc::{Sender, channel};
+use std::thread::JoinHandle;
+use tangle::Future;
+use protocol::{Serializer, ThriftSerializer, Deserializer, ThriftDeserializer};
+use protocol::{ThriftMessage, ThriftMessageType, ThriftType, Protocol, Error};
+use binary_protocol::Binary;
+use dispatcher::{self, Dispatcher, Incoming};
+use reactor::{Addr, Framing, Compression, Limits, Security};
+use result::ThrustResult;
+use runner::Runner;
+use workers::{self, Workers};
+
+/// The kinds of `TApplicationException`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionKind {
+    Unknown = 0,
+    UnknownMethod = 1,
+    InvalidMessageType = 2,
+    WrongMethodName = 3,
+    BadSequenceId = 4,
+    MissingResult = 5,
+    InternalError = 6,
+    ProtocolError = 7
+}
+
+impl From<i32> for ExceptionKind {
+    fn from(val: i32) -> ExceptionKind {
+        match val {
+            1 => ExceptionKind::UnknownMethod,
+            2 => ExceptionKind::InvalidMessageType,
+            3 => ExceptionKind::WrongMethodName,
+            4 => ExceptionKind::BadSequenceId,
+            5 => ExceptionKind::MissingResult,
+            6 => ExceptionKind::InternalError,
+            7 => ExceptionKind::ProtocolError,
+            _ => ExceptionKind::Unknown
+        }
+    }
+}
+
+/// An error sent back to the client instead of a reply, compatible with Thrift's
+/// `TApplicationException`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exception {
+    pub message: String,
+    pub kind: ExceptionKind
+}
+
+impl Exception {
+    pub fn new(kind: ExceptionKind, message: &str) -> Exception {
+        Exception {
+            message: message.to_string(),
+            kind: kind
+        }
+    }
+
+    /// Decode an exception that follows the header of an exception reply.
+    pub fn read<D>(de: &mut D) -> Result<Exception, Error>
+        where D: Deserializer + ThriftDeserializer
+    {
+        let mut exception = Exception::new(ExceptionKind::Unknown, "");
+
+        de.read_struct_begin()?;
+        loop {
+            let field = de.read_field_begin()?;
+            match (field.ty, field.seq) {
+                (ThriftType::Stop, _) => break,
+                (ThriftType::String, 1) => exception.message = de.deserialize_str()?,
+                (ThriftType::I32, 2) => exception.kind = ExceptionKind::from(de.deserialize_i32()?),
+                (ty, _) => return Err(Error::UnknownType(ty as i8))
+            }
+            de.read_field_end()?;
+        }
+        de.read_struct_end()?;
+
+        Ok(exception)
+    }
+
+    /// Encode the exception as the reply to the method `name`, using the binary
+    /// protocol.
+    pub fn to_reply(&self, name: &str) -> Result<Vec<u8>, Error> {
+        self.encode(&Binary, name, 0)
+    }
+
+    /// Encode the exception as the reply to the call of the method `name` with the
+    /// sequence id `seq`.
+    pub fn encode<P: Protocol>(&self, protocol: &P, name: &str, seq: i32) -> Result<Vec<u8>, Error> {
+        let mut se = protocol.serializer();
+        se.write_message_begin_seq(name, ThriftMessageType::Exception, seq)?;
+        se.write_struct_begin("TApplicationException")?;
+        se.write_field_begin("message", ThriftType::String, 1)?;
+        se.serialize_str(&self.message)?;
+        se.write_field_end()?;
+        se.write_field_begin("type", ThriftType::I32, 2)?;
+        se.serialize_i32(self.kind as i32)?;
+        se.write_field_end()?;
+        se.write_field_stop()?;
+        se.write_struct_end()?;
+        se.write_message_end()?;
+
+        Ok(protocol.finish(se))
+    }
+}
+
+/// Hooks that run around every request a server handles, for things like auth checks,
+/// logging, metrics or rate limiting.
+///
+/// Interceptors are shared by every worker thread, so any state needs to be
+/// synchronized.
+pub trait Interceptor: Send + Sync {
+    /// Called before the handler runs. `payload` is the whole request as it was
+    /// received, including the message header. Returning an `Exception` short-circuits
+    /// the request; neither the handler nor the following interceptors are run.
+    fn before(&self, msg: &ThriftMessage, payload: &[u8]) -> Result<(), Exception> {
+        Ok(())
+    }
+
+    /// Called with the future of the encoded reply. The returned future is what's
+    /// sent back to the client, so the reply can be observed or replaced.
+    fn after(&self, msg: &ThriftMessage, reply: Future<Vec<u8>>) -> Future<Vec<u8>> {
+        reply
+    }
+
+    /// Called when a client gave up on its call to `method` with the sequence id `seq`.
+    /// The handler can't be stopped, but any work the interceptor started for the call
+    /// can.
+    fn cancelled(&self, method: &str, seq: i32) {}
+}
+
+/// An ordered stack of interceptors. The first interceptor added is the outermost:
+/// its `before` runs first and its `after` runs last.
+#[derive(Clone)]
+pub struct Chain {
+    interceptors: Vec<Arc<Interceptor>>
+}
+
+impl Chain {
+    pub fn new() -> Chain {
+        Chain {
+            interceptors: Vec::new()
+        }
+    }
+
+    pub fn push<I: 'static + Interceptor>(&mut self, interceptor: I) {
+        self.interceptors.push(Arc::new(interceptor));
+    }
+
+    pub fn before(&self, msg: &ThriftMessage, payload: &[u8]) -> Result<(), Exception> {
+        for interceptor in self.interceptors.iter() {
+            interceptor.before(msg, payload)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn after(&self, msg: &ThriftMessage, reply: Future<Vec<u8>>) -> Future<Vec<u8>> {
+        self.interceptors.iter().rev().fold(reply, |reply, interceptor| interceptor.after(msg, reply))
+    }
+
+    pub fn cancelled(&self, method: &str, seq: i32) {
+        for interceptor in self.interceptors.iter() {
+            interceptor.cancelled(method, seq);
+        }
+    }
+}
+
+/// Configures a generated server before it's bound.
+///
+/// ```notrust
+/// let builder = ServerBuilder::new()
+///     .workers(Workers { threads: 4, ordered: true })
+///     .intercept(Auth)
+///     .intercept(Logging);
+///
+/// let server = FooServer::build(service, addr, builder);
+/// ```
+///
+/// Servers speak the binary protocol unless another one is chosen with `protocol`.
+pub struct ServerBuilder<P: Protocol = Binary> {
+    framing: Framing,
+    limits: Limits,
+    workers: Workers,
+    chain: Chain,
+    protocol: P,
+    /// The name the server's metrics are recorded under.
+    service: String,
+    compression: Compression,
+    security: Security
+}
+
+impl ServerBuilder<Binary> {
+    pub fn new() -> ServerBuilder<Binary> {
+        ServerBuilder {
+            framing: Framing::Framed,
+            limits: Limits::default(),
+            workers: Workers::default(),
+            chain: Chain::new(),
+            protocol: Binary,
+            service: String::new(),
+            compression: Compression::default(),
+            security: Security::Plain
+        }
+    }
+}
+
+impl<P: Protocol> ServerBuilder<P> {
+    pub fn framing(mut self, framing: Framing) -> ServerBuilder<P> {
+        self.framing = framing;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> ServerBuilder<P> {
+        self.limits = limits;
+        self
+    }
+
+    pub fn workers(mut self, workers: Workers) -> ServerBuilder<P> {
+        self.workers = workers;
+        self
+    }
+
+    /// Add an interceptor inside the ones added before it.
+    pub fn intercept<I: 'static + Interceptor>(mut self, interceptor: I) -> ServerBuilder<P> {
+        self.chain.push(interceptor);
+        self
+    }
+
+    /// Speak `protocol` instead.
+    pub fn protocol<Q: Protocol>(self, protocol: Q) -> ServerBuilder<Q> {
+        ServerBuilder {
+            framing: self.framing,
+            limits: self.limits,
+            workers: self.workers,
+            chain: self.chain,
+            protocol: protocol,
+            service: self.service,
+            compression: self.compression,
+            security: self.security
+        }
+    }
+
+    /// Name the service in the server's metrics. Generated servers use the name of the
+    /// service in the thrift file.
+    pub fn service(mut self, name: &str) -> ServerBuilder<P> {
+        self.service = name.to_string();
+        self
+    }
+
+    /// Compress the replies the server writes, which needs `Framing::Header`. Calls
+    /// that came compressed are replied to with the same transform regardless.
+    pub fn compression(mut self, compression: Compression) -> ServerBuilder<P> {
+        self.compression = compression;
+        self
+    }
+
+    /// Secure every connection the server accepts, like with TLS given the `tls`
+    /// feature.
+    pub fn security(mut self, security: Security) -> ServerBuilder<P> {
+        self.security = security;
+        self
+    }
+
+    /// Bind to `addr` and start running requests with runners made by `new_runner`.
+    /// This is what generated servers are built on.
+    pub fn spawn<F, R>(self, addr: Addr, new_runner: F) -> ThrustResult<(JoinHandle<ThrustResult<()>>, Sender<Incoming>)>
+        where F: Fn() -> R,
+              R: 'static + Runner + Send
+    {
+        let (sender, receiver) = channel();
+        let role = dispatcher::Role::Server(addr, self.framing, self.limits, sender);
+        let (handle, tx) = Dispatcher::spawn_with(role, self.compression, self.security, self.protocol.clone())?;
+
+        workers::spawn(self.workers, self.chain, self.protocol, &self.service, receiver, tx.clone(), new_runner);
+        Ok((handle, tx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use tangle::{Future, Async};
+    use protocol::{Deserializer, ThriftDeserializer, ThriftMessage, ThriftMessageType};
+    use binary_protocol::BinaryDeserializer;
+
+    struct Deny;
+
+    impl Interceptor for Deny {
+        fn before(&self, msg: &ThriftMessage, payload: &[u8]) -> Result<(), Exception> {
+            Err(Exception::new(ExceptionKind::InternalError, "denied"))
+        }
+    }
+
+    struct Record(&'static str, Arc<Mutex<Vec<String>>>);
+
+    impl Interceptor for Record {
+        fn before(&self, msg: &ThriftMessage, payload: &[u8]) -> Result<(), Exception> {
+            self.1.lock().unwrap().push(format!("before {}", self.0));
+            Ok(())
+        }
+
+        fn after(&self, msg: &ThriftMessage, reply: Future<Vec<u8>>) -> Future<Vec<u8>> {
+            self.1.lock().unwrap().push(format!("after {}", self.0));
+            reply
+        }
+    }
+
+    fn msg() -> ThriftMessage {
+        ThriftMessage {
+            name: "foo".to_string(),
+            ty: ThriftMessageType::Call,
+            seq: 0
+        }
+    }
+
+    #[test]
+    fn should_run_interceptors_in_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = Chain::new();
+        chain.push(Record("a", log.clone()));
+        chain.push(Record("b", log.clone()));
+
+        chain.before(&msg(), &[]).unwrap();
+        chain.after(&msg(), Future::unit(Vec::new()));
+
+        assert_eq!(*log.lock().unwrap(), vec!["before a", "before b", "after b", "after a"]);
+    }
+
+    #[test]
+    fn should_short_circuit() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = Chain::new();
+        chain.push(Deny);
+        chain.push(Record("a", log.clone()));
+
+        let err = chain.before(&msg(), &[]).unwrap_err();
+        assert_eq!(err.kind, ExceptionKind::InternalError);
+        assert!(log.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_encode_exception() {
+        let buf = Exception::new(ExceptionKind::UnknownMethod, "no such method").to_reply("foo").unwrap();
+        let mut de = BinaryDeserializer::new(Cursor::new(buf));
+
+        let msg = de.read_message_begin().unwrap();
+        assert_eq!(&*msg.name, "foo");
+        assert_eq!(msg.ty, ThriftMessageType::Exception);
+
+        de.read_struct_begin().unwrap();
+        de.read_field_begin().unwrap();
+        assert_eq!(de.deserialize_str().unwrap(), "no such method");
+        de.read_field_end().unwrap();
+        de.read_field_begin().unwrap();
+        assert_eq!(de.deserialize_i32().unwrap(), 1);
+    }
+
+    #[test]
+    fn should_decode_exception() {
+        let exception = Exception::new(ExceptionKind::InternalError, "oops");
+        let mut de = BinaryDeserializer::new(Cursor::new(exception.to_reply("foo").unwrap()));
+
+        de.read_message_begin().unwrap();
+        assert_eq!(Exception::read(&mut de).unwrap(), exception);
+    }
+}
@@ -0,0 +1,444 @@
+//! TLS for listeners and client connections, built on OpenSSL. Only compiled with the
+//! `tls` feature.
+//!
+//! A `Tls` is made once from a `ServerConfig` or a `ClientConfig` and handed to the
+//! `Reactor` with `Security::Tls`. Servers can require clients to present a certificate
+//! signed by a CA of their choosing, in which case the common name of the certificate
+//! is the identity handlers see in `Context::identity`.
+//!
+//! Handshakes don't block the event loop. Until one is done, reads and writes on the
+//! connection fail with `WouldBlock` and outgoing frames stay buffered.
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::Arc;
+use mio::tcp::TcpStream;
+use openssl::nid;
+use openssl::pkey::PKey;
+use openssl::ssl::{self, SslMethod, SslAcceptor, SslAcceptorBuilder, SslConnector, SslConnectorBuilder};
+use openssl::ssl::{SslStream, MidHandshakeSslStream, HandshakeError, SSL_VERIFY_PEER, SSL_VERIFY_FAIL_IF_NO_PEER_CERT};
+use openssl::x509::X509;
+use result::ThrustResult;
+
+/// What a server presents to its clients. Certificates and keys are PEM encoded.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+    /// Require clients to present a certificate signed by this CA, which is mutual TLS.
+    /// Clients without one can't finish the handshake.
+    pub client_ca: Option<Vec<u8>>
+}
+
+/// How a client checks the server it connects to. Certificates and keys are PEM
+/// encoded.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// The name the server's certificate has to be issued to, which is also sent with
+    /// SNI.
+    pub domain: String,
+    /// Trust the server's certificate if it's signed by this CA. The system's CAs are
+    /// trusted without one.
+    pub ca: Option<Vec<u8>>,
+    /// The certificate and key presented to servers that require one.
+    pub identity: Option<(Vec<u8>, Vec<u8>)>
+}
+
+/// A TLS configuration that's ready to secure connections. Clones share the same
+/// OpenSSL context.
+#[derive(Clone)]
+pub enum Tls {
+    /// Accepts handshakes from clients.
+    Server(Arc<SslAcceptor>),
+    /// Starts handshakes with servers, expecting them to be the domain.
+    Client(Arc<SslConnector>, String)
+}
+
+impl Tls {
+    pub fn server(config: &ServerConfig) -> ThrustResult<Tls> {
+        let cert = X509::from_pem(&config.cert)?;
+        let key = PKey::private_key_from_pem(&config.key)?;
+        let mut builder = SslAcceptorBuilder::mozilla_intermediate(SslMethod::tls(), &key, &cert, Vec::<X509>::new())?;
+
+        if let Some(ref ca) = config.client_ca {
+            let ctx = builder.builder_mut();
+            ctx.cert_store_mut().add_cert(X509::from_pem(ca)?)?;
+            ctx.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT);
+        }
+
+        Ok(Tls::Server(Arc::new(builder.build())))
+    }
+
+    pub fn client(config: &ClientConfig) -> ThrustResult<Tls> {
+        let mut builder = SslConnectorBuilder::new(SslMethod::tls())?;
+
+        {
+            let ctx = builder.builder_mut();
+            if let Some(ref ca) = config.ca {
+                ctx.cert_store_mut().add_cert(X509::from_pem(ca)?)?;
+            }
+
+            if let Some((ref cert, ref key)) = config.identity {
+                ctx.set_certificate(&X509::from_pem(cert)?)?;
+                ctx.set_private_key(&PKey::private_key_from_pem(key)?)?;
+            }
+        }
+
+        Ok(Tls::Client(Arc::new(builder.build()), config.domain.clone()))
+    }
+}
+
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Tls::Server(_) => write!(f, "Tls::Server"),
+            Tls::Client(_, ref domain) => write!(f, "Tls::Client({:?})", domain)
+        }
+    }
+}
+
+enum State {
+    Handshaking(MidHandshakeSslStream<TcpStream>),
+    Established(SslStream<TcpStream>),
+    /// Only seen while moving from one state to the other.
+    Empty
+}
+
+/// A TCP stream that's encrypted once its handshake is done.
+pub struct TlsStream {
+    state: State,
+    /// The common name of the peer's certificate, read once the handshake is done.
+    identity: Option<String>
+}
+
+impl TlsStream {
+    /// Start the handshake on `stream`, as a server or a client depending on `tls`.
+    pub fn new(tls: &Tls, stream: TcpStream) -> ThrustResult<TlsStream> {
+        let res = match *tls {
+            Tls::Server(ref acceptor) => acceptor.accept(stream),
+            Tls::Client(ref connector, ref domain) => connector.connect(domain, stream)
+        };
+
+        let mut stream = TlsStream {
+            state: State::Empty,
+            identity: None
+        };
+
+        stream.advance(res)?;
+        Ok(stream)
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        match self.state {
+            State::Handshaking(ref stream) => stream.get_ref(),
+            State::Established(ref stream) => stream.get_ref(),
+            State::Empty => unreachable!("A `TlsStream` is never left without a socket.")
+        }
+    }
+
+    /// The common name of the certificate the peer presented, if it presented one.
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_ref().map(|identity| &**identity)
+    }
+
+    /// Whether the handshake is done.
+    pub fn is_established(&self) -> bool {
+        match self.state {
+            State::Established(_) => true,
+            _ => false
+        }
+    }
+
+    /// Whether the handshake is waiting for the socket to become writable.
+    pub fn wants_write(&self) -> bool {
+        match self.state {
+            State::Handshaking(ref stream) => match *stream.error() {
+                ssl::Error::WantWrite(_) => true,
+                _ => false
+            },
+            _ => false
+        }
+    }
+
+    /// Move the handshake along for as far as the socket allows.
+    pub fn handshake(&mut self) -> io::Result<()> {
+        match self.established() {
+            Ok(_) => Ok(()),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// The encrypted stream, which isn't available until the handshake is done.
+    fn established(&mut self) -> io::Result<&mut SslStream<TcpStream>> {
+        if let State::Handshaking(_) = self.state {
+            let res = match mem::replace(&mut self.state, State::Empty) {
+                State::Handshaking(stream) => stream.handshake(),
+                _ => unreachable!()
+            };
+
+            self.advance(res)?;
+        }
+
+        match self.state {
+            State::Established(ref mut stream) => Ok(stream),
+            _ => Err(io::Error::new(io::ErrorKind::WouldBlock, "The TLS handshake isn't done yet."))
+        }
+    }
+
+    fn advance(&mut self, res: Result<SslStream<TcpStream>, HandshakeError<TcpStream>>) -> io::Result<()> {
+        match res {
+            Ok(stream) => {
+                self.identity = peer_identity(&stream);
+                self.state = State::Established(stream);
+                Ok(())
+            },
+            Err(HandshakeError::Interrupted(stream)) => {
+                self.state = State::Handshaking(stream);
+                Ok(())
+            },
+            // The socket is kept so it can still be deregistered.
+            Err(HandshakeError::Failure(stream)) => {
+                let err = io::Error::new(io::ErrorKind::Other, format!("The TLS handshake failed. {}", stream.error()));
+                self.state = State::Handshaking(stream);
+                Err(err)
+            },
+            Err(HandshakeError::SetupFailure(err)) => Err(io::Error::new(io::ErrorKind::Other, err))
+        }
+    }
+}
+
+fn peer_identity(stream: &SslStream<TcpStream>) -> Option<String> {
+    let cert = match stream.ssl().peer_certificate() {
+        Some(cert) => cert,
+        None => return None
+    };
+
+    let name = cert.subject_name().entries_by_nid(nid::COMMONNAME).next();
+    name.and_then(|entry| entry.data().as_utf8().ok()).map(|name| name.to_string())
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.established()?.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.established()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.established() {
+            Ok(stream) => stream.flush(),
+            // There's nothing to flush before the handshake is done.
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(err) => Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::{self, SocketAddr};
+    use std::sync::mpsc::{Receiver, channel};
+    use std::thread;
+    use std::time::Duration;
+    use mio::{self, EventLoop, Token};
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::nid;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509, X509Builder, X509Name, X509NameBuilder};
+    use rand;
+    use reactor::{Reactor, Message, Dispatch, Id, Addr, Framing, Compression, Limits, Security};
+
+    fn name(common_name: &str) -> X509Name {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(nid::COMMONNAME, common_name).unwrap();
+        name.build()
+    }
+
+    /// A certificate for `common_name` and its key, signed by `issuer` or by itself.
+    /// They're version 1 certificates, which OpenSSL takes as CAs when self-signed.
+    fn certificate(common_name: &str, issuer: Option<&(X509, PKey)>) -> (X509, PKey) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let serial = BigNum::from_u32(rand::random()).unwrap().to_asn1_integer().unwrap();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&name(common_name)).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+
+        match issuer {
+            Some(&(ref ca, ref ca_key)) => {
+                builder.set_issuer_name(ca.subject_name()).unwrap();
+                builder.sign(ca_key, MessageDigest::sha256()).unwrap();
+            },
+            None => {
+                builder.set_issuer_name(&name(common_name)).unwrap();
+                builder.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+
+        (builder.build(), key)
+    }
+
+    fn pem(cert: &(X509, PKey)) -> (Vec<u8>, Vec<u8>) {
+        (cert.0.to_pem().unwrap(), cert.1.private_key_to_pem().unwrap())
+    }
+
+    fn reactor() -> mio::Sender<Message> {
+        let mut reactor = Reactor::new();
+        let mut event_loop = EventLoop::new().expect("[test]: EventLoop failed to create.");
+        let sender = event_loop.channel();
+
+        thread::spawn(move || {
+            event_loop.run(&mut reactor);
+        });
+
+        sender
+    }
+
+    fn bind(sender: &mio::Sender<Message>, tls: Tls) -> (SocketAddr, Receiver<Dispatch>) {
+        bind_with_limits(sender, tls, Limits::default())
+    }
+
+    fn bind_with_limits(sender: &mio::Sender<Message>, tls: Tls, limits: Limits) -> (SocketAddr, Receiver<Dispatch>) {
+        let (id_tx, id_rx) = channel();
+        let (tx, rx) = channel();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        sender.send(Message::Bind(addr, Framing::Framed, Compression::default(), limits, Security::Tls(tls), id_tx, tx));
+
+        match id_rx.recv().expect("[test]: Receiving from channel `id_rx` failed.") {
+            Ok(Id(_, Addr::Tcp(addr))) => (addr, rx),
+            Ok(Id(_, addr)) => panic!("[test]: Expected a TCP address, got {:?}.", addr),
+            Err(err) => panic!("[test]: Binding failed. {:?}", err)
+        }
+    }
+
+    fn connect(sender: &mio::Sender<Message>, addr: SocketAddr, tls: Tls) -> (Token, Receiver<Dispatch>) {
+        let (id_tx, id_rx) = channel();
+        let (tx, rx) = channel();
+        sender.send(Message::Connect(addr, Framing::Framed, Compression::default(), Security::Tls(tls), id_tx, tx));
+
+        let Id(token, _) = id_rx.recv().expect("[test]: Receiving from channel `id_rx` failed.").expect("[test]: Connecting failed.");
+        (token, rx)
+    }
+
+    #[test]
+    fn should_identify_peers_over_mutual_tls() {
+        let ca = certificate("Test CA", None);
+        let (server_cert, server_key) = pem(&certificate("localhost", Some(&ca)));
+        let server = Tls::server(&ServerConfig {
+            cert: server_cert,
+            key: server_key,
+            client_ca: Some(pem(&ca).0)
+        }).unwrap();
+
+        let client = Tls::client(&ClientConfig {
+            domain: "localhost".to_string(),
+            ca: Some(pem(&ca).0),
+            identity: Some(pem(&certificate("billing", Some(&ca))))
+        }).unwrap();
+
+        let sender = reactor();
+        let (addr, server_rx) = bind(&sender, server);
+        let (client_token, client_rx) = connect(&sender, addr, client);
+        sender.send(Message::Rpc(client_token, b"abc".to_vec()));
+
+        let token = match server_rx.recv().expect("[test]: Receiving from channel `server_rx` failed.") {
+            Dispatch::Data(token, peer, v) => {
+                assert_eq!(v, b"abc");
+                assert_eq!(peer.identity, Some("billing".to_string()));
+                token
+            },
+            Dispatch::Closed(id) => panic!("[test]: Connection {:?} was closed.", id)
+        };
+
+        sender.send(Message::Rpc(token, b"bbb".to_vec()));
+
+        match client_rx.recv().expect("[test]: Receiving from channel `client_rx` failed.") {
+            Dispatch::Data(token, peer, v) => {
+                assert_eq!(token, client_token);
+                assert_eq!(v, b"bbb");
+                assert_eq!(peer.identity, Some("localhost".to_string()));
+            },
+            Dispatch::Closed(id) => panic!("[test]: Connection {:?} was closed.", id)
+        }
+
+        sender.send(Message::Shutdown);
+    }
+
+    #[test]
+    fn should_close_connections_stalled_in_the_handshake() {
+        let (server_cert, server_key) = pem(&certificate("localhost", None));
+        let server = Tls::server(&ServerConfig {
+            cert: server_cert,
+            key: server_key,
+            client_ca: None
+        }).unwrap();
+
+        let limits = Limits {
+            handshake_timeout: Duration::from_millis(200),
+            .. Limits::default()
+        };
+
+        let sender = reactor();
+        let (addr, server_rx) = bind_with_limits(&sender, server, limits);
+
+        // Connects, but never says hello.
+        let mut stalled = net::TcpStream::connect(addr).unwrap();
+
+        match server_rx.recv().expect("[test]: Receiving from channel `server_rx` failed.") {
+            Dispatch::Closed(_) => {},
+            Dispatch::Data(..) => panic!("[test]: Expected the connection to be closed.")
+        }
+
+        let mut buf = [0u8; 1];
+        assert_eq!(stalled.read(&mut buf).unwrap(), 0);
+
+        sender.send(Message::Shutdown);
+    }
+
+    #[test]
+    fn should_reject_clients_without_a_certificate() {
+        let ca = certificate("Test CA", None);
+        let (server_cert, server_key) = pem(&certificate("localhost", Some(&ca)));
+        let server = Tls::server(&ServerConfig {
+            cert: server_cert,
+            key: server_key,
+            client_ca: Some(pem(&ca).0)
+        }).unwrap();
+
+        let client = Tls::client(&ClientConfig {
+            domain: "localhost".to_string(),
+            ca: Some(pem(&ca).0),
+            identity: None
+        }).unwrap();
+
+        let sender = reactor();
+        let (addr, server_rx) = bind(&sender, server);
+        let (client_token, client_rx) = connect(&sender, addr, client);
+        sender.send(Message::Rpc(client_token, b"abc".to_vec()));
+
+        match server_rx.recv().expect("[test]: Receiving from channel `server_rx` failed.") {
+            Dispatch::Closed(_) => {},
+            Dispatch::Data(..) => panic!("[test]: Expected the handshake to fail.")
+        }
+
+        match client_rx.recv().expect("[test]: Receiving from channel `client_rx` failed.") {
+            Dispatch::Closed(token) => assert_eq!(token, client_token),
+            Dispatch::Data(..) => panic!("[test]: Expected the handshake to fail.")
+        }
+
+        sender.send(Message::Shutdown);
+    }
+}
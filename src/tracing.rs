@@ -0,0 +1,394 @@
+//! Zipkin-style tracing across calls. Traces are propagated with the B3 info headers of
+//! the THeader, so only connections using `Framing::Header` carry them.
+//!
+//! A server joins the trace of every call it receives, and runs the handler with it as
+//! the current trace. Calls made from the handler's thread then continue the trace
+//! with a child span. Spans are sent to the reporter installed with `set_reporter`.
+use std::cell::RefCell;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use rand;
+use header::Headers;
+
+pub const TRACE_ID_HEADER: &'static str = "X-B3-TraceId";
+pub const SPAN_ID_HEADER: &'static str = "X-B3-SpanId";
+pub const PARENT_ID_HEADER: &'static str = "X-B3-ParentSpanId";
+pub const SAMPLED_HEADER: &'static str = "X-B3-Sampled";
+
+/// Identifies a span and the trace it's part of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: u64,
+    /// The upper half of 128-bit trace ids, and 0 for 64-bit ones. Traces started here
+    /// have 64-bit ids, but ones joined from other services are passed on whole.
+    pub trace_id_high: u64,
+    pub span_id: u64,
+    pub parent_id: Option<u64>,
+    /// Whether the spans of the trace are reported. The decision is made once, where
+    /// the trace starts.
+    pub sampled: bool
+}
+
+impl TraceContext {
+    /// Start a new trace.
+    pub fn root(sampled: bool) -> TraceContext {
+        let id = rand::random();
+        TraceContext {
+            trace_id: id,
+            trace_id_high: 0,
+            span_id: id,
+            parent_id: None,
+            sampled: sampled
+        }
+    }
+
+    /// A new span of the same trace, with this span as its parent.
+    pub fn child(&self) -> TraceContext {
+        TraceContext {
+            trace_id: self.trace_id,
+            trace_id_high: self.trace_id_high,
+            span_id: rand::random(),
+            parent_id: Some(self.span_id),
+            sampled: self.sampled
+        }
+    }
+
+    /// Read a trace from B3 headers. Headers without both a trace and a span id don't
+    /// carry a trace. Trace ids can be 64 or 128 bits long.
+    pub fn from_headers(headers: &Headers) -> Option<TraceContext> {
+        let id = |key| headers.get(key).and_then(|val| u64::from_str_radix(val, 16).ok());
+
+        match (headers.get(TRACE_ID_HEADER).and_then(|val| parse_trace_id(val)), id(SPAN_ID_HEADER)) {
+            (Some((trace_id_high, trace_id)), Some(span_id)) => Some(TraceContext {
+                trace_id: trace_id,
+                trace_id_high: trace_id_high,
+                span_id: span_id,
+                parent_id: id(PARENT_ID_HEADER),
+                sampled: headers.get(SAMPLED_HEADER).map(|val| &**val == "1" || &**val == "true").unwrap_or(false)
+            }),
+            _ => None
+        }
+    }
+
+    /// Add the B3 headers of the trace.
+    pub fn to_headers(&self, headers: &mut Headers) {
+        let trace_id = match self.trace_id_high {
+            0 => format!("{:016x}", self.trace_id),
+            high => format!("{:016x}{:016x}", high, self.trace_id)
+        };
+
+        headers.insert(TRACE_ID_HEADER.to_string(), trace_id);
+        headers.insert(SPAN_ID_HEADER.to_string(), format!("{:016x}", self.span_id));
+        if let Some(parent_id) = self.parent_id {
+            headers.insert(PARENT_ID_HEADER.to_string(), format!("{:016x}", parent_id));
+        }
+        headers.insert(SAMPLED_HEADER.to_string(), if self.sampled { "1" } else { "0" }.to_string());
+    }
+}
+
+/// Split a hex trace id of up to 32 characters into its upper and lower 64 bits.
+fn parse_trace_id(val: &str) -> Option<(u64, u64)> {
+    if val.is_empty() || val.len() > 32 || !val.is_char_boundary(val.len().saturating_sub(16)) {
+        return None;
+    }
+
+    let (high, low) = val.split_at(val.len().saturating_sub(16));
+    let high = match high {
+        "" => 0,
+        high => match u64::from_str_radix(high, 16) {
+            Ok(high) => high,
+            Err(_) => return None
+        }
+    };
+
+    u64::from_str_radix(low, 16).ok().map(|low| (high, low))
+}
+
+/// Which side of a call a span was recorded on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Client,
+    Server
+}
+
+/// A finished call.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub trace: TraceContext,
+    /// The name of the method that was called.
+    pub name: String,
+    pub kind: Kind,
+    pub start: SystemTime,
+    pub duration: Duration,
+    /// Why the call failed, if it did.
+    pub error: Option<String>
+}
+
+/// Where finished spans are sent, like a Zipkin collector.
+///
+/// Reporters are called from the dispatcher and worker threads, so they should hand
+/// spans off instead of blocking on I/O.
+pub trait Reporter: Send + Sync {
+    fn report(&self, span: Span);
+}
+
+/// Keeps every span in memory, which is mostly useful in tests. Clones share the same
+/// spans.
+#[derive(Clone, Default)]
+pub struct MemoryReporter {
+    spans: Arc<Mutex<Vec<Span>>>
+}
+
+impl MemoryReporter {
+    pub fn new() -> MemoryReporter {
+        MemoryReporter::default()
+    }
+
+    /// The spans reported so far.
+    pub fn spans(&self) -> Vec<Span> {
+        self.spans.lock().expect("Failed to take the spans lock.").clone()
+    }
+}
+
+impl Reporter for MemoryReporter {
+    fn report(&self, span: Span) {
+        self.spans.lock().expect("Failed to take the spans lock.").push(span);
+    }
+}
+
+/// A span that has started and is reported once it's finished.
+pub struct Started {
+    trace: TraceContext,
+    name: String,
+    kind: Kind,
+    start: SystemTime,
+    timer: Instant
+}
+
+impl Started {
+    pub fn new(trace: TraceContext, name: &str, kind: Kind) -> Started {
+        Started {
+            trace: trace,
+            name: name.to_string(),
+            kind: kind,
+            start: SystemTime::now(),
+            timer: Instant::now()
+        }
+    }
+
+    pub fn trace(&self) -> &TraceContext {
+        &self.trace
+    }
+
+    /// End the span with its outcome.
+    pub fn end(self, error: Option<String>) -> Span {
+        Span {
+            trace: self.trace,
+            name: self.name,
+            kind: self.kind,
+            start: self.start,
+            duration: self.timer.elapsed(),
+            error: error
+        }
+    }
+
+    /// End the span, reporting it if its trace is sampled.
+    pub fn finish(self, error: Option<String>) {
+        if !self.trace.sampled {
+            return;
+        }
+
+        if let Some(reporter) = reporter() {
+            reporter.report(self.end(error));
+        }
+    }
+}
+
+struct Tracing {
+    reporter: Option<Arc<Reporter>>,
+    sample_rate: f64
+}
+
+lazy_static! {
+    static ref TRACING: RwLock<Tracing> = RwLock::new(Tracing {
+        reporter: None,
+        sample_rate: 1.0
+    });
+
+    /// Held by the scoped reporter that's installed.
+    static ref SCOPE: Mutex<()> = Mutex::new(());
+}
+
+/// Report spans to `reporter`. Traces started by this process are sampled with a
+/// probability of `sample_rate`, while traces joined from other services keep the
+/// decision made where they started.
+///
+/// Without a reporter, traces are still propagated but never started.
+pub fn set_reporter<R: 'static + Reporter>(reporter: R, sample_rate: f64) {
+    let mut tracing = TRACING.write().expect("Failed to take the `TRACING` lock.");
+    tracing.reporter = Some(Arc::new(reporter));
+    tracing.sample_rate = sample_rate;
+}
+
+/// Puts back the reporter that was installed before `scoped_reporter` when it's
+/// dropped.
+pub struct ReporterGuard {
+    reporter: Option<Arc<Reporter>>,
+    sample_rate: f64,
+    _scope: MutexGuard<'static, ()>
+}
+
+impl Drop for ReporterGuard {
+    fn drop(&mut self) {
+        let mut tracing = TRACING.write().expect("Failed to take the `TRACING` lock.");
+        tracing.reporter = self.reporter.take();
+        tracing.sample_rate = self.sample_rate;
+    }
+}
+
+/// Report spans to `reporter` until the returned guard is dropped, like `set_reporter`.
+/// Only one scoped reporter is installed at a time and the others wait for it, so tests
+/// that each install their own don't report to each other's.
+pub fn scoped_reporter<R: 'static + Reporter>(reporter: R, sample_rate: f64) -> ReporterGuard {
+    // A test that panicked while holding the scope has still put its reporter back.
+    let scope = SCOPE.lock().unwrap_or_else(|err| err.into_inner());
+    let mut tracing = TRACING.write().expect("Failed to take the `TRACING` lock.");
+    let previous = mem::replace(&mut tracing.reporter, Some(Arc::new(reporter)));
+    let previous_rate = mem::replace(&mut tracing.sample_rate, sample_rate);
+
+    ReporterGuard {
+        reporter: previous,
+        sample_rate: previous_rate,
+        _scope: scope
+    }
+}
+
+fn reporter() -> Option<Arc<Reporter>> {
+    TRACING.read().expect("Failed to take the `TRACING` lock.").reporter.clone()
+}
+
+/// Continue `trace`, or start a new one if there's a reporter to send it to.
+fn or_root(trace: Option<TraceContext>) -> Option<TraceContext> {
+    let tracing = TRACING.read().expect("Failed to take the `TRACING` lock.");
+    match trace {
+        Some(trace) => Some(trace),
+        None if tracing.reporter.is_some() => Some(TraceContext::root(rand::random::<f64>() < tracing.sample_rate)),
+        None => None
+    }
+}
+
+/// Start the span of a call to `name`, as a child of `parent`.
+pub fn start_client(parent: Option<TraceContext>, name: &str) -> Option<Started> {
+    or_root(parent.map(|parent| parent.child())).map(|trace| Started::new(trace, name, Kind::Client))
+}
+
+/// Start the span of a received call to `name`. Servers share the span of the client
+/// that made the call.
+pub fn start_server(trace: Option<TraceContext>, name: &str) -> Option<Started> {
+    or_root(trace).map(|trace| Started::new(trace, name, Kind::Server))
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<TraceContext>> = RefCell::new(None)
+}
+
+/// The trace of the call being run on this thread, which calls made from it continue.
+pub fn current() -> Option<TraceContext> {
+    CURRENT.with(|current| *current.borrow())
+}
+
+/// Run `f` with `trace` as the current trace of this thread.
+pub fn with_current<F, T>(trace: Option<TraceContext>, f: F) -> T
+    where F: FnOnce() -> T
+{
+    let previous = CURRENT.with(|current| mem::replace(&mut *current.borrow_mut(), trace));
+    let ret = f();
+    CURRENT.with(|current| *current.borrow_mut() = previous);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use header::Headers;
+
+    #[test]
+    fn should_round_trip_headers() {
+        let trace = TraceContext::root(true).child();
+
+        let mut headers = Headers::new();
+        trace.to_headers(&mut headers);
+        assert_eq!(headers.get(SAMPLED_HEADER).unwrap(), "1");
+        assert_eq!(TraceContext::from_headers(&headers), Some(trace));
+    }
+
+    #[test]
+    fn should_keep_128_bit_trace_ids() {
+        let mut headers = Headers::new();
+        headers.insert(TRACE_ID_HEADER.to_string(), "463ac35c9f6413ad48485a3953bb6124".to_string());
+        headers.insert(SPAN_ID_HEADER.to_string(), "a2fb4a1d1a96d312".to_string());
+
+        let trace = TraceContext::from_headers(&headers).unwrap();
+        assert_eq!((trace.trace_id_high, trace.trace_id), (0x463ac35c9f6413ad, 0x48485a3953bb6124));
+
+        let mut propagated = Headers::new();
+        trace.child().to_headers(&mut propagated);
+        assert_eq!(propagated[TRACE_ID_HEADER], headers[TRACE_ID_HEADER]);
+    }
+
+    #[test]
+    fn should_reject_trace_ids_longer_than_128_bits() {
+        let mut headers = Headers::new();
+        headers.insert(TRACE_ID_HEADER.to_string(), "1463ac35c9f6413ad48485a3953bb6124".to_string());
+        headers.insert(SPAN_ID_HEADER.to_string(), "a2fb4a1d1a96d312".to_string());
+        assert_eq!(TraceContext::from_headers(&headers), None);
+    }
+
+    #[test]
+    fn should_put_the_previous_reporter_back() {
+        let reporter = MemoryReporter::new();
+        {
+            let _guard = scoped_reporter(reporter.clone(), 1.0);
+            start_server(None, "scoped").unwrap().finish(None);
+        }
+
+        // Whatever was installed before, spans no longer go to the scoped reporter.
+        if let Some(span) = start_server(None, "unscoped") {
+            span.finish(None);
+        }
+
+        let names: Vec<String> = reporter.spans().into_iter().map(|span| span.name).collect();
+        assert!(names.contains(&"scoped".to_string()));
+        assert!(!names.contains(&"unscoped".to_string()));
+    }
+
+    #[test]
+    fn should_ignore_missing_ids() {
+        let mut headers = Headers::new();
+        headers.insert(TRACE_ID_HEADER.to_string(), "abc".to_string());
+        assert_eq!(TraceContext::from_headers(&headers), None);
+    }
+
+    #[test]
+    fn should_continue_traces() {
+        let root = TraceContext::root(false);
+        let span = start_client(Some(root), "foo").unwrap();
+
+        assert_eq!(span.trace().trace_id, root.trace_id);
+        assert_eq!(span.trace().parent_id, Some(root.span_id));
+        assert!(!span.trace().sampled);
+
+        let span = span.end(Some("oops".to_string()));
+        assert_eq!(&*span.name, "foo");
+        assert_eq!(span.kind, Kind::Client);
+        assert_eq!(span.error, Some("oops".to_string()));
+    }
+
+    #[test]
+    fn should_set_the_current_trace() {
+        let trace = TraceContext::root(true);
+        assert_eq!(with_current(Some(trace), current), Some(trace));
+        assert_eq!(current(), None);
+    }
+}
@@ -1,5 +1,15 @@
-use result::{ThrustResult};
+use std::io::{self, Cursor, Read, Write};
+use std::net::{self, SocketAddr, Shutdown};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::mem;
+use std::sync::mpsc::{Sender, Receiver, channel};
+use byteorder::{ByteOrder, BigEndian};
+use result::{ThrustResult, ThrustError};
+use reactor::Limits;
 
+/// A blocking, byte-oriented channel to a remote peer. Transports are the synchronous
+/// counterpart to the `Reactor`, for scripts and tests that don't want an event loop.
 pub trait Transport {
     fn open(&mut self) -> ThrustResult<()>;
     fn close(&mut self) -> ThrustResult<()>;
@@ -7,3 +17,636 @@ pub trait Transport {
     fn write(&mut self, buf: &[u8]) -> ThrustResult<()>;
     fn flush(&mut self) -> ThrustResult<()>;
 }
+
+/// Fill the whole buffer from the transport, failing if the peer hangs up first.
+fn read_exact<T: Transport>(transport: &mut T, buf: &mut [u8]) -> ThrustResult<()> {
+    let mut pos = 0;
+
+    while pos < buf.len() {
+        match transport.read(&mut buf[pos..])? {
+            0 => return Err(ThrustError::Disconnected),
+            n => pos += n
+        }
+    }
+
+    Ok(())
+}
+
+fn not_open() -> ThrustError {
+    ThrustError::Str("The transport is not open.".to_string())
+}
+
+/// A blocking TCP socket.
+pub struct TcpTransport {
+    addr: SocketAddr,
+    stream: Option<net::TcpStream>
+}
+
+impl TcpTransport {
+    /// Create a transport that connects to `addr` once it's opened.
+    pub fn new(addr: SocketAddr) -> TcpTransport {
+        TcpTransport {
+            addr: addr,
+            stream: None
+        }
+    }
+
+    /// Wrap an already connected stream, such as one returned by `TcpListener::accept`.
+    pub fn from_stream(stream: net::TcpStream) -> ThrustResult<TcpTransport> {
+        Ok(TcpTransport {
+            addr: stream.peer_addr()?,
+            stream: Some(stream)
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Transport for TcpTransport {
+    fn open(&mut self) -> ThrustResult<()> {
+        if self.stream.is_none() {
+            self.stream = Some(net::TcpStream::connect(self.addr)?);
+        }
+
+        Ok(())
+    }
+
+    fn close(&mut self) -> ThrustResult<()> {
+        if let Some(stream) = self.stream.take() {
+            stream.shutdown(Shutdown::Both)?;
+        }
+
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> ThrustResult<usize> {
+        match self.stream {
+            Some(ref mut stream) => Ok(stream.read(buf)?),
+            None => Err(not_open())
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> ThrustResult<()> {
+        match self.stream {
+            Some(ref mut stream) => Ok(stream.write_all(buf)?),
+            None => Err(not_open())
+        }
+    }
+
+    fn flush(&mut self) -> ThrustResult<()> {
+        match self.stream {
+            Some(ref mut stream) => Ok(stream.flush()?),
+            None => Err(not_open())
+        }
+    }
+}
+
+/// A blocking Unix domain socket.
+pub struct UnixTransport {
+    path: PathBuf,
+    stream: Option<UnixStream>
+}
+
+impl UnixTransport {
+    /// Create a transport that connects to the socket at `path` once it's opened.
+    pub fn new<P: AsRef<Path>>(path: P) -> UnixTransport {
+        UnixTransport {
+            path: path.as_ref().to_path_buf(),
+            stream: None
+        }
+    }
+
+    /// Wrap an already connected stream, such as one returned by `UnixListener::accept`.
+    pub fn from_stream(stream: UnixStream) -> UnixTransport {
+        let path = stream.peer_addr().ok()
+            .and_then(|addr| addr.as_pathname().map(|path| path.to_path_buf()))
+            .unwrap_or_else(PathBuf::new);
+
+        UnixTransport {
+            path: path,
+            stream: Some(stream)
+        }
+    }
+}
+
+impl Transport for UnixTransport {
+    fn open(&mut self) -> ThrustResult<()> {
+        if self.stream.is_none() {
+            self.stream = Some(UnixStream::connect(&self.path)?);
+        }
+
+        Ok(())
+    }
+
+    fn close(&mut self) -> ThrustResult<()> {
+        if let Some(stream) = self.stream.take() {
+            stream.shutdown(Shutdown::Both)?;
+        }
+
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> ThrustResult<usize> {
+        match self.stream {
+            Some(ref mut stream) => Ok(stream.read(buf)?),
+            None => Err(not_open())
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> ThrustResult<()> {
+        match self.stream {
+            Some(ref mut stream) => Ok(stream.write_all(buf)?),
+            None => Err(not_open())
+        }
+    }
+
+    fn flush(&mut self) -> ThrustResult<()> {
+        match self.stream {
+            Some(ref mut stream) => Ok(stream.flush()?),
+            None => Err(not_open())
+        }
+    }
+}
+
+/// An in-memory transport. Reads are served from a fixed input buffer and everything
+/// written is collected in an output buffer.
+pub struct MemoryTransport {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>
+}
+
+impl MemoryTransport {
+    pub fn new(input: Vec<u8>) -> MemoryTransport {
+        MemoryTransport {
+            input: Cursor::new(input),
+            output: Vec::new()
+        }
+    }
+
+    /// Everything that has been written to the transport so far.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Take the written data out of the transport.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.output, Vec::new())
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn open(&mut self) -> ThrustResult<()> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> ThrustResult<()> {
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> ThrustResult<usize> {
+        Ok(self.input.read(buf)?)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> ThrustResult<()> {
+        self.output.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> ThrustResult<()> {
+        Ok(())
+    }
+}
+
+/// One end of an in-process pipe made with `loopback`. Whatever is written to one end
+/// is read from the other, so a client and a server in the same process can talk
+/// without any sockets.
+pub struct ChannelTransport {
+    tx: Option<Sender<Vec<u8>>>,
+    rx: Receiver<Vec<u8>>,
+    rbuffer: Cursor<Vec<u8>>
+}
+
+/// Create both ends of an in-process pipe. Closing or dropping one end shows up as the
+/// end of the stream on the other.
+pub fn loopback() -> (ChannelTransport, ChannelTransport) {
+    let (a_tx, a_rx) = channel();
+    let (b_tx, b_rx) = channel();
+
+    let a = ChannelTransport {
+        tx: Some(a_tx),
+        rx: b_rx,
+        rbuffer: Cursor::new(Vec::new())
+    };
+
+    let b = ChannelTransport {
+        tx: Some(b_tx),
+        rx: a_rx,
+        rbuffer: Cursor::new(Vec::new())
+    };
+
+    (a, b)
+}
+
+impl Transport for ChannelTransport {
+    fn open(&mut self) -> ThrustResult<()> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> ThrustResult<()> {
+        self.tx = None;
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> ThrustResult<usize> {
+        while self.rbuffer.position() as usize == self.rbuffer.get_ref().len() {
+            match self.rx.recv() {
+                Ok(data) => self.rbuffer = Cursor::new(data),
+                // The other end is gone.
+                Err(_) => return Ok(0)
+            }
+        }
+
+        Ok(self.rbuffer.read(buf)?)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> ThrustResult<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        match self.tx {
+            Some(ref tx) => tx.send(buf.to_vec()).map_err(|_| ThrustError::Disconnected),
+            None => Err(not_open())
+        }
+    }
+
+    fn flush(&mut self) -> ThrustResult<()> {
+        Ok(())
+    }
+}
+
+/// Wraps a transport to read and write frames, compatible with `TFramedTransport`. Writes
+/// are buffered until `flush`, which sends them as a single frame preceeded by its
+/// 32-bit length.
+pub struct Framed<T: Transport> {
+    inner: T,
+    rbuffer: Cursor<Vec<u8>>,
+    wbuffer: Vec<u8>,
+    max_frame_size: usize
+}
+
+impl<T: Transport> Framed<T> {
+    pub fn new(inner: T) -> Framed<T> {
+        Framed::with_max_frame_size(inner, Limits::default().max_frame_size)
+    }
+
+    /// Fail to read frames longer than `max_frame_size` bytes, instead of allocating
+    /// whatever the peer announces.
+    pub fn with_max_frame_size(inner: T, max_frame_size: usize) -> Framed<T> {
+        Framed {
+            inner: inner,
+            rbuffer: Cursor::new(Vec::new()),
+            wbuffer: Vec::new(),
+            max_frame_size: max_frame_size
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn read_frame(&mut self) -> ThrustResult<()> {
+        let mut header = [0u8; 4];
+        let mut len = 0;
+
+        // An empty frame isn't the end of the stream, so skip to the next one.
+        while len == 0 {
+            read_exact(&mut self.inner, &mut header)?;
+            len = BigEndian::read_u32(&header) as usize;
+        }
+
+        if len > self.max_frame_size {
+            return Err(ThrustError::Str(format!("A frame of {} bytes is larger than the limit of {} bytes.", len, self.max_frame_size)));
+        }
+
+        let mut frame = vec![0u8; len];
+        read_exact(&mut self.inner, &mut frame)?;
+        self.rbuffer = Cursor::new(frame);
+        Ok(())
+    }
+}
+
+impl<T: Transport> Transport for Framed<T> {
+    fn open(&mut self) -> ThrustResult<()> {
+        self.inner.open()
+    }
+
+    fn close(&mut self) -> ThrustResult<()> {
+        self.inner.close()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> ThrustResult<usize> {
+        if self.rbuffer.position() as usize == self.rbuffer.get_ref().len() {
+            self.read_frame()?;
+        }
+
+        Ok(self.rbuffer.read(buf)?)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> ThrustResult<()> {
+        self.wbuffer.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> ThrustResult<()> {
+        let mut header = [0u8; 4];
+        BigEndian::write_u32(&mut header, self.wbuffer.len() as u32);
+
+        let frame = mem::replace(&mut self.wbuffer, Vec::new());
+        self.inner.write(&header)?;
+        self.inner.write(&frame)?;
+        self.inner.flush()
+    }
+}
+
+/// Wraps a transport with read and write buffers, compatible with `TBufferedTransport`.
+/// Writes are held back until `flush` and reads are served from a buffer that's
+/// refilled in large chunks.
+pub struct Buffered<T: Transport> {
+    inner: T,
+    rbuffer: Vec<u8>,
+    rpos: usize,
+    wbuffer: Vec<u8>
+}
+
+impl<T: Transport> Buffered<T> {
+    pub fn new(inner: T) -> Buffered<T> {
+        Buffered {
+            inner: inner,
+            rbuffer: Vec::new(),
+            rpos: 0,
+            wbuffer: Vec::new()
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Transport> Transport for Buffered<T> {
+    fn open(&mut self) -> ThrustResult<()> {
+        self.inner.open()
+    }
+
+    fn close(&mut self) -> ThrustResult<()> {
+        self.inner.close()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> ThrustResult<usize> {
+        if self.rpos == self.rbuffer.len() {
+            let mut chunk = vec![0u8; 4096];
+            let n = self.inner.read(&mut chunk)?;
+            chunk.truncate(n);
+            self.rbuffer = chunk;
+            self.rpos = 0;
+        }
+
+        let n = (&self.rbuffer[self.rpos..]).read(buf)?;
+        self.rpos += n;
+        Ok(n)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> ThrustResult<()> {
+        self.wbuffer.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> ThrustResult<()> {
+        let buf = mem::replace(&mut self.wbuffer, Vec::new());
+        self.inner.write(&buf)?;
+        self.inner.flush()
+    }
+}
+
+fn to_io_error(err: ThrustError) -> io::Error {
+    match err {
+        ThrustError::IO(err) => err,
+        ThrustError::Disconnected => io::Error::new(io::ErrorKind::UnexpectedEof, "The peer hung up."),
+        err => io::Error::new(io::ErrorKind::Other, format!("{:?}", err))
+    }
+}
+
+/// The wrapper transports implement `Read` and `Write` so protocols can be used on top
+/// of them directly.
+impl<T: Transport> Read for Framed<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Transport::read(self, buf).map_err(to_io_error)
+    }
+}
+
+impl<T: Transport> Write for Framed<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Transport::write(self, buf).map_err(to_io_error)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Transport::flush(self).map_err(to_io_error)
+    }
+}
+
+impl<T: Transport> Read for Buffered<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Transport::read(self, buf).map_err(to_io_error)
+    }
+}
+
+impl<T: Transport> Write for Buffered<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Transport::write(self, buf).map_err(to_io_error)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Transport::flush(self).map_err(to_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::read_exact;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, SocketAddr};
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+    use std::env;
+    use std::fs;
+    use libc;
+    use byteorder::{BigEndian, WriteBytesExt};
+
+    #[test]
+    fn should_write_frame() {
+        let mut framed = Framed::new(MemoryTransport::new(Vec::new()));
+        Transport::write(&mut framed, b"ab").unwrap();
+        Transport::write(&mut framed, b"c").unwrap();
+        assert_eq!(framed.get_ref().output(), b"");
+
+        Transport::flush(&mut framed).unwrap();
+        assert_eq!(framed.get_ref().output(), &[0, 0, 0, 3, 97, 98, 99]);
+    }
+
+    #[test]
+    fn should_read_frames() {
+        let mut input = Vec::new();
+        input.write_u32::<BigEndian>(3);
+        input.write(b"abc");
+        input.write_u32::<BigEndian>(1);
+        input.write(b"d");
+
+        let mut framed = Framed::new(MemoryTransport::new(input));
+        let mut buf = [0u8; 8];
+        assert_eq!(Transport::read(&mut framed, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"abc");
+        assert_eq!(Transport::read(&mut framed, &mut buf).unwrap(), 1);
+        assert_eq!(&buf[..1], b"d");
+
+        match Transport::read(&mut framed, &mut buf) {
+            Err(ThrustError::Disconnected) => {},
+            res => panic!("Expected the transport to be exhausted, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn should_skip_empty_frames() {
+        let mut input = Vec::new();
+        input.write_u32::<BigEndian>(0);
+        input.write_u32::<BigEndian>(1);
+        input.write(b"d");
+
+        let mut framed = Framed::new(MemoryTransport::new(input));
+        let mut buf = [0u8; 8];
+        assert_eq!(Transport::read(&mut framed, &mut buf).unwrap(), 1);
+        assert_eq!(&buf[..1], b"d");
+    }
+
+    #[test]
+    fn should_reject_frames_over_the_limit() {
+        let mut input = Vec::new();
+        input.write_u32::<BigEndian>(4);
+        input.write(b"abcd");
+
+        let mut framed = Framed::with_max_frame_size(MemoryTransport::new(input), 3);
+        let mut buf = [0u8; 8];
+        match Transport::read(&mut framed, &mut buf) {
+            Err(ThrustError::Str(_)) => {},
+            res => panic!("Expected the frame to be rejected, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn should_buffer_reads_and_writes() {
+        let mut buffered = Buffered::new(MemoryTransport::new(b"hello".to_vec()));
+        let mut buf = [0u8; 3];
+        assert_eq!(Transport::read(&mut buffered, &mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"hel");
+        assert_eq!(Transport::read(&mut buffered, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"lo");
+
+        Transport::write(&mut buffered, b"abc").unwrap();
+        assert_eq!(buffered.get_ref().output(), b"");
+        Transport::flush(&mut buffered).unwrap();
+        assert_eq!(buffered.get_ref().output(), b"abc");
+    }
+
+    #[test]
+    fn should_use_tcp_transport() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut framed = Framed::new(TcpTransport::from_stream(stream).unwrap());
+            let mut buf = [0u8; 3];
+            framed.read_exact(&mut buf).unwrap();
+            framed.write_all(&buf).unwrap();
+            Write::flush(&mut framed).unwrap();
+        });
+
+        let mut framed = Framed::new(TcpTransport::new(addr));
+        framed.open().unwrap();
+        framed.write_all(b"abc").unwrap();
+        Write::flush(&mut framed).unwrap();
+
+        let mut buf = [0u8; 3];
+        framed.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"abc");
+        framed.close().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn should_use_unix_transport() {
+        let pid = unsafe { libc::getpid() };
+        let path = env::temp_dir().join(format!("thrust-transport-{}.sock", pid));
+        fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut buffered = Buffered::new(UnixTransport::from_stream(stream));
+            let mut buf = [0u8; 3];
+            buffered.read_exact(&mut buf).unwrap();
+            buffered.write_all(&buf).unwrap();
+            Write::flush(&mut buffered).unwrap();
+        });
+
+        let mut buffered = Buffered::new(UnixTransport::new(&path));
+        buffered.open().unwrap();
+        buffered.write_all(b"abc").unwrap();
+        Write::flush(&mut buffered).unwrap();
+
+        let mut buf = [0u8; 3];
+        buffered.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"abc");
+        buffered.close().unwrap();
+        server.join().unwrap();
+        fs::remove_file(&path);
+    }
+
+    #[test]
+    fn should_use_loopback_transport() {
+        let (mut a, mut b) = loopback();
+
+        a.write(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        read_exact(&mut b, &mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        b.write(b"pong").unwrap();
+        read_exact(&mut a, &mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+
+        a.close().unwrap();
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -0,0 +1,624 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Instant;
+use mio::Token;
+use num_cpus;
+use tangle::{Future, Async};
+use protocol::{ThriftDeserializer, ThriftMessageType, Protocol};
+use dispatcher::Incoming;
+use runner::Runner;
+use server::{Chain, Exception, ExceptionKind};
+use client;
+use header::{self, Header, Headers};
+use context::Context;
+use reactor::Peer;
+use tracing;
+use metrics::{self, Side};
+use blocking;
+
+/// How a server runs its handlers.
+#[derive(Debug, Clone, Copy)]
+pub struct Workers {
+    /// The number of threads requests are decoded and run on.
+    pub threads: usize,
+    /// Run the requests of a connection one at a time, replying in the order they
+    /// arrived. Requests from different connections still run concurrently.
+    pub ordered: bool
+}
+
+impl Default for Workers {
+    fn default() -> Workers {
+        Workers {
+            threads: num_cpus::get(),
+            ordered: false
+        }
+    }
+}
+
+/// Run the requests coming in from a server `Dispatcher` on a pool of threads. Every
+/// thread gets its own `Runner` from `new_runner`, and replies are routed back through
+/// `Incoming::Reply`. Each request passes through the interceptors in `chain`, and is
+/// decoded with `protocol`. Metrics are recorded under the name `service`.
+pub fn spawn<F, R, P>(workers: Workers, chain: Chain, protocol: P, service: &str, requests: Receiver<(Token, Peer, Vec<u8>)>,
+                      replies: Sender<Incoming>, new_runner: F)
+    where F: Fn() -> R,
+          R: 'static + Runner + Send,
+          P: Protocol
+{
+    let threads = if workers.threads == 0 { 1 } else { workers.threads };
+
+    if !workers.ordered {
+        // Whichever worker is idle picks up the next request.
+        let requests = Arc::new(Mutex::new(requests));
+        for _ in 0..threads {
+            spawn_worker(requests.clone(), new_runner(), chain.clone(), protocol.clone(), service.to_string(), replies.clone(), false);
+        }
+
+        return;
+    }
+
+    // Requests of a connection always go to the same worker, which runs them in order.
+    let mut queues = Vec::new();
+    for _ in 0..threads {
+        let (tx, rx) = channel();
+        spawn_worker(Arc::new(Mutex::new(rx)), new_runner(), chain.clone(), protocol.clone(), service.to_string(), replies.clone(), true);
+        queues.push(tx);
+    }
+
+    thread::spawn(move || {
+        for (token, peer, buf) in requests.iter() {
+            let Token(id) = token;
+            // Workers outlive panicking handlers, so this shouldn't happen. Other
+            // connections are still served if it does.
+            if queues[id % queues.len()].send((token, peer, buf)).is_err() {
+                error!("dropping a request, its worker is gone; token={:?}", token);
+                replies.send(Incoming::Release(token));
+            }
+        }
+    });
+}
+
+fn spawn_worker<R, P>(requests: Arc<Mutex<Receiver<(Token, Peer, Vec<u8>)>>>, mut runner: R, chain: Chain,
+                      protocol: P, service: String, replies: Sender<Incoming>, ordered: bool)
+    where R: 'static + Runner + Send,
+          P: Protocol
+{
+    thread::spawn(move || {
+        loop {
+            let (token, peer, buf) = match requests.lock() {
+                Ok(rx) => match rx.recv() {
+                    Ok(req) => req,
+                    Err(_) => break
+                },
+                Err(_) => break
+            };
+
+            // A panicking handler fails its own request, not the worker. The request's
+            // slot is released as it unwinds.
+            let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                handle(&mut runner, &chain, &protocol, &service, token, peer, buf, &replies, ordered)
+            }));
+
+            if res.is_err() {
+                error!("a handler panicked; token={:?}", token);
+            }
+        }
+    });
+}
+
+fn handle<R, P>(runner: &mut R, chain: &Chain, protocol: &P, service: &str, token: Token, peer: Peer, buf: Vec<u8>,
+                replies: &Sender<Incoming>, ordered: bool)
+    where R: Runner,
+          P: Protocol
+{
+    // Every path that doesn't reply gives the request's in-flight slot back by dropping
+    // this.
+    let mut slot = Slot::new(token, replies);
+
+    let (request_header, buf) = match header::unwrap(buf) {
+        Ok(unwrapped) => unwrapped,
+        Err(err) => {
+            warn!("error parsing the THeader; token={:?} err={:?}", token, err);
+            return;
+        }
+    };
+
+    // Requests that came with a THeader are replied to with one, always in the protocol
+    // the server speaks.
+    let reply_header = request_header.as_ref().map(|request_header| {
+        let mut reply_header = request_header.reply();
+        reply_header.protocol = protocol.header_id();
+        reply_header
+    });
+
+    let headers = match request_header {
+        Some(request_header) => {
+            if request_header.protocol != protocol.header_id() {
+                // The payload can't be read, so the exception goes back under the
+                // THeader's sequence id.
+                warn!("unsupported protocol in the THeader; token={:?} protocol={}", token, request_header.protocol);
+                let message = format!("Unsupported protocol {}.", request_header.protocol);
+                match Exception::new(ExceptionKind::ProtocolError, &message).encode(protocol, "", request_header.seq as i32) {
+                    Ok(buf) => slot.reply(&reply_header, buf),
+                    Err(err) => error!("error encoding an exception; token={:?} err={:?}", token, err)
+                }
+
+                return;
+            }
+
+            request_header.info
+        },
+        None => Headers::new()
+    };
+
+    let msg = match protocol.read_message(&buf) {
+        Ok(msg) => msg,
+        Err(err) => {
+            warn!("error parsing thrift message; token={:?} err={:?}", token, err);
+            return;
+        }
+    };
+
+    // A client cancelled one of its calls. There's nothing to reply.
+    if &*msg.name == client::CANCEL {
+        let mut de = protocol.deserializer(buf);
+        match de.read_message_begin().and_then(|_| client::read_cancel(&mut de)) {
+            Ok(method) => chain.cancelled(&method, msg.seq),
+            Err(err) => warn!("error parsing a cancellation; token={:?} err={:?}", token, err)
+        }
+
+        return;
+    }
+
+    let started = Instant::now();
+    let method = metrics::method_label(runner.methods(), &msg.name).to_string();
+    metrics::started(Side::Server, service, &method);
+
+    // The handler runs as part of the call's trace, so its own calls continue it.
+    let mut ctx = Context::new(Some(peer.addr), &msg, headers.clone());
+    ctx.identity = peer.identity;
+    let span = tracing::start_server(ctx.trace, &msg.name);
+    ctx.trace = span.as_ref().map(|span| *span.trace());
+
+    let trace = ctx.trace;
+    let future = header::with_request_headers(headers, || tracing::with_current(trace, || {
+        if let Err(exception) = chain.before(&msg, &buf) {
+            return match exception.encode(protocol, &msg.name, msg.seq) {
+                Ok(buf) => Some(Future::unit(buf)),
+                Err(err) => {
+                    error!("error encoding an exception; method={} err={:?}", msg.name, err);
+                    None
+                }
+            };
+        }
+
+        let mut de = protocol.deserializer(buf);
+        if let Err(err) = de.read_message_begin() {
+            warn!("error parsing thrift message; token={:?} err={:?}", token, err);
+            return None;
+        }
+
+        match runner.run(&ctx, protocol, &mut de, msg.clone()) {
+            Ok(future) => Some(chain.after(&msg, future)),
+            Err(err) => {
+                error!("error running the handler; method={} err={:?}", msg.name, err);
+                None
+            }
+        }
+    }));
+
+    let future = match future {
+        Some(future) => {
+            let protocol = protocol.clone();
+            let service = service.to_string();
+            future.map(move |buf| {
+                let failure = failure(&protocol, &buf);
+                metrics::finished(Side::Server, &service, &method, started.elapsed(), failure.is_some());
+                if let Some(span) = span {
+                    span.finish(failure);
+                }
+
+                buf
+            })
+        },
+        None => {
+            metrics::finished(Side::Server, service, &method, started.elapsed(), true);
+            if let Some(span) = span {
+                span.finish(Some("The call wasn't replied to.".to_string()));
+            }
+
+            return;
+        }
+    };
+
+    if ordered {
+        // Wait for the reply so the next request of this connection can't overtake it.
+        match blocking::wait(future) {
+            Ok(buf) => slot.reply(&reply_header, buf),
+            Err(err) => error!("error waiting for the reply; token={:?} err={:?}", token, err)
+        }
+    } else {
+        future.and_then(move |buf| {
+            slot.reply(&reply_header, buf);
+            Async::Ok(())
+        });
+    }
+}
+
+/// The message of the exception `buf` holds, if the reply is one.
+fn failure<P: Protocol>(protocol: &P, buf: &[u8]) -> Option<String> {
+    match protocol.read_message(buf) {
+        Ok(ref msg) if msg.ty == ThriftMessageType::Exception => {
+            let mut de = protocol.deserializer(buf.to_vec());
+            match de.read_message_begin().and_then(|_| Exception::read(&mut de)) {
+                Ok(exception) => Some(exception.message),
+                Err(err) => Some(format!("{:?}", err))
+            }
+        },
+        _ => None
+    }
+}
+
+/// The slot a request takes up under `Limits::max_in_flight` on its connection. Replying
+/// frees it, and so does dropping it without a reply, which sends `Incoming::Release`.
+struct Slot {
+    token: Token,
+    replies: Sender<Incoming>,
+    replied: bool
+}
+
+impl Slot {
+    fn new(token: Token, replies: &Sender<Incoming>) -> Slot {
+        Slot {
+            token: token,
+            replies: replies.clone(),
+            replied: false
+        }
+    }
+
+    /// Send a reply back through the dispatcher, prefixed with `header` if there is one.
+    fn reply(&mut self, header: &Option<Header>, buf: Vec<u8>) {
+        let buf = match *header {
+            Some(ref header) => match header::wrap(header, &buf) {
+                Ok(buf) => buf,
+                Err(err) => {
+                    error!("error writing the THeader; token={:?} err={:?}", self.token, err);
+                    return;
+                }
+            },
+            None => buf
+        };
+
+        self.replied = true;
+        self.replies.send(Incoming::Reply(self.token, buf));
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if !self.replied {
+            self.replies.send(Incoming::Release(self.token));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+    use mio::Token;
+    use tangle::Future;
+    use protocol::{Deserializer, ThriftDeserializer, ThriftMessage, ThriftMessageType, Protocol, Error};
+    use binary_protocol::{Binary, BinaryDeserializer};
+    use dispatcher::Incoming;
+    use runner::Runner;
+    use std::sync::{Arc, Mutex};
+    use server::{Chain, Interceptor, Exception, ExceptionKind};
+    use client;
+    use header::{self, Header, BINARY_PROTOCOL};
+    use context::{self, Context};
+    use reactor::{Addr, Peer};
+    use tracing::{self, TraceContext, MemoryReporter, Kind};
+    use metrics::{self, Side};
+    use util;
+
+    /// Replies with the name of the call, after sleeping on calls named "slow". Calls
+    /// named "panic" do.
+    struct Sleepy;
+
+    impl Runner for Sleepy {
+        fn run<P, D>(&mut self, ctx: &Context, protocol: &P, de: &mut D, msg: ThriftMessage) -> Result<Future<Vec<u8>>, Error>
+            where P: Protocol,
+                  D: Deserializer + ThriftDeserializer
+        {
+            if &*msg.name == "slow" {
+                thread::sleep(Duration::from_millis(100));
+            }
+
+            if &*msg.name == "panic" {
+                panic!("[test]: Panicking on purpose.");
+            }
+
+            Ok(Future::unit(util::create_empty_thrift_message(&msg.name, ThriftMessageType::Reply)))
+        }
+    }
+
+    /// Records the calls that were cancelled.
+    struct Cancelled(Arc<Mutex<Vec<(String, i32)>>>);
+
+    impl Interceptor for Cancelled {
+        fn cancelled(&self, method: &str, seq: i32) {
+            self.0.lock().unwrap().push((method.to_string(), seq));
+        }
+    }
+
+    /// Records the `user` info header of every request.
+    struct Users(Arc<Mutex<Vec<String>>>);
+
+    impl Interceptor for Users {
+        fn before(&self, msg: &ThriftMessage, payload: &[u8]) -> Result<(), Exception> {
+            let user = header::request_headers().get("user").cloned().unwrap_or_default();
+            self.0.lock().unwrap().push(user);
+            Ok(())
+        }
+    }
+
+    /// Keeps the context of the last call it ran.
+    struct Peek(Arc<Mutex<Option<Context>>>);
+
+    impl Runner for Peek {
+        fn methods(&self) -> &'static [&'static str] {
+            static METHODS: &'static [&'static str] = &["fast", "traced"];
+            METHODS
+        }
+
+        fn run<P, D>(&mut self, ctx: &Context, protocol: &P, de: &mut D, msg: ThriftMessage) -> Result<Future<Vec<u8>>, Error>
+            where P: Protocol,
+                  D: Deserializer + ThriftDeserializer
+        {
+            *self.0.lock().unwrap() = Some(ctx.clone());
+            Ok(Future::unit(util::create_empty_thrift_message(&msg.name, ThriftMessageType::Reply)))
+        }
+    }
+
+    fn peer() -> Peer {
+        Peer {
+            addr: Addr::Tcp("127.0.0.1:4000".parse().unwrap()),
+            identity: Some("billing".to_string())
+        }
+    }
+
+    fn reply_name(msg: Incoming) -> (Token, String) {
+        match msg {
+            Incoming::Reply(token, buf) => {
+                let mut de = BinaryDeserializer::new(Cursor::new(buf));
+                (token, de.read_message_begin().unwrap().name)
+            },
+            _ => panic!("[test]: Expected a reply.")
+        }
+    }
+
+    #[test]
+    fn should_run_requests_concurrently() {
+        let (req_tx, req_rx) = channel();
+        let (reply_tx, reply_rx) = channel();
+        spawn(Workers { threads: 2, ordered: false }, Chain::new(), Binary, "Test", req_rx, reply_tx, || Sleepy);
+
+        req_tx.send((Token(1), peer(), util::create_empty_thrift_message("slow", ThriftMessageType::Call))).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        req_tx.send((Token(2), peer(), util::create_empty_thrift_message("fast", ThriftMessageType::Call))).unwrap();
+
+        // The fast request doesn't wait for the slow one.
+        assert_eq!(reply_name(reply_rx.recv().unwrap()), (Token(2), "fast".to_string()));
+        assert_eq!(reply_name(reply_rx.recv().unwrap()), (Token(1), "slow".to_string()));
+    }
+
+    #[test]
+    fn should_keep_ordering_per_connection() {
+        let (req_tx, req_rx) = channel();
+        let (reply_tx, reply_rx) = channel();
+        spawn(Workers { threads: 2, ordered: true }, Chain::new(), Binary, "Test", req_rx, reply_tx, || Sleepy);
+
+        req_tx.send((Token(1), peer(), util::create_empty_thrift_message("slow", ThriftMessageType::Call))).unwrap();
+        req_tx.send((Token(1), peer(), util::create_empty_thrift_message("fast", ThriftMessageType::Call))).unwrap();
+
+        assert_eq!(reply_name(reply_rx.recv().unwrap()), (Token(1), "slow".to_string()));
+        assert_eq!(reply_name(reply_rx.recv().unwrap()), (Token(1), "fast".to_string()));
+    }
+
+    #[test]
+    fn should_pass_cancellations_to_interceptors() {
+        let cancelled = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = Chain::new();
+        chain.push(Cancelled(cancelled.clone()));
+
+        let (req_tx, req_rx) = channel();
+        let (reply_tx, reply_rx) = channel();
+        spawn(Workers { threads: 1, ordered: false }, chain, Binary, "Test", req_rx, reply_tx, || Sleepy);
+
+        req_tx.send((Token(1), peer(), client::cancel_request(&Binary, "slow", 7).unwrap())).unwrap();
+        req_tx.send((Token(1), peer(), util::create_empty_thrift_message("fast", ThriftMessageType::Call))).unwrap();
+
+        // Only the call is replied to, the cancellation gives its slot back.
+        match reply_rx.recv().unwrap() {
+            Incoming::Release(token) => assert_eq!(token, Token(1)),
+            _ => panic!("[test]: Expected the cancellation to be released.")
+        }
+
+        assert_eq!(reply_name(reply_rx.recv().unwrap()), (Token(1), "fast".to_string()));
+        assert_eq!(*cancelled.lock().unwrap(), vec![("slow".to_string(), 7)]);
+    }
+
+    #[test]
+    fn should_survive_panicking_handlers() {
+        let (req_tx, req_rx) = channel();
+        let (reply_tx, reply_rx) = channel();
+        spawn(Workers { threads: 1, ordered: true }, Chain::new(), Binary, "Test", req_rx, reply_tx, || Sleepy);
+
+        req_tx.send((Token(1), peer(), util::create_empty_thrift_message("panic", ThriftMessageType::Call))).unwrap();
+        req_tx.send((Token(1), peer(), util::create_empty_thrift_message("fast", ThriftMessageType::Call))).unwrap();
+
+        match reply_rx.recv().unwrap() {
+            Incoming::Release(token) => assert_eq!(token, Token(1)),
+            _ => panic!("[test]: Expected the panicked request to be released.")
+        }
+
+        assert_eq!(reply_name(reply_rx.recv().unwrap()), (Token(1), "fast".to_string()));
+    }
+
+    #[test]
+    fn should_release_requests_without_a_reply() {
+        let (req_tx, req_rx) = channel();
+        let (reply_tx, reply_rx) = channel();
+        spawn(Workers { threads: 1, ordered: true }, Chain::new(), Binary, "Test", req_rx, reply_tx, || Sleepy);
+
+        req_tx.send((Token(1), peer(), vec![1, 2, 3])).unwrap();
+        req_tx.send((Token(2), peer(), client::cancel_request(&Binary, "slow", 7).unwrap())).unwrap();
+
+        for &expected in [Token(1), Token(2)].iter() {
+            match reply_rx.recv().unwrap() {
+                Incoming::Release(token) => assert_eq!(token, expected),
+                _ => panic!("[test]: Expected the request to be released.")
+            }
+        }
+    }
+
+    #[test]
+    fn should_reply_with_a_theader() {
+        let users = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = Chain::new();
+        chain.push(Users(users.clone()));
+
+        let (req_tx, req_rx) = channel();
+        let (reply_tx, reply_rx) = channel();
+        spawn(Workers { threads: 1, ordered: false }, chain, Binary, "Test", req_rx, reply_tx, || Sleepy);
+
+        let mut request_header = Header::new(BINARY_PROTOCOL);
+        request_header.seq = 3;
+        request_header.info.insert("user".to_string(), "foo".to_string());
+        let call = util::create_empty_thrift_message("fast", ThriftMessageType::Call);
+        req_tx.send((Token(1), peer(), header::wrap(&request_header, &call).unwrap())).unwrap();
+
+        match reply_rx.recv().unwrap() {
+            Incoming::Reply(token, buf) => {
+                let (reply_header, buf) = header::unwrap(buf).unwrap();
+                assert_eq!(reply_header.unwrap().seq, 3);
+                assert_eq!(reply_name(Incoming::Reply(token, buf)), (Token(1), "fast".to_string()));
+            },
+            _ => panic!("[test]: Expected a reply.")
+        }
+
+        assert_eq!(*users.lock().unwrap(), vec!["foo".to_string()]);
+    }
+
+    #[test]
+    fn should_reject_unsupported_protocols() {
+        let (req_tx, req_rx) = channel();
+        let (reply_tx, reply_rx) = channel();
+        spawn(Workers { threads: 1, ordered: false }, Chain::new(), Binary, "Test", req_rx, reply_tx, || Sleepy);
+
+        // The compact protocol's id.
+        let mut request_header = Header::new(2);
+        request_header.seq = 3;
+        let call = util::create_empty_thrift_message("fast", ThriftMessageType::Call);
+        req_tx.send((Token(1), peer(), header::wrap(&request_header, &call).unwrap())).unwrap();
+
+        match reply_rx.recv().unwrap() {
+            Incoming::Reply(Token(1), buf) => {
+                let (reply_header, buf) = header::unwrap(buf).unwrap();
+                let reply_header = reply_header.unwrap();
+                assert_eq!((reply_header.seq, reply_header.protocol), (3, BINARY_PROTOCOL));
+
+                let msg = Binary.read_message(&buf).unwrap();
+                assert_eq!((msg.ty, msg.seq), (ThriftMessageType::Exception, 3));
+
+                let mut de = Binary.deserializer(buf);
+                de.read_message_begin().unwrap();
+                assert_eq!(Exception::read(&mut de).unwrap().kind, ExceptionKind::ProtocolError);
+            },
+            _ => panic!("[test]: Expected a reply.")
+        }
+    }
+
+    #[test]
+    fn should_pass_the_context() {
+        let last = Arc::new(Mutex::new(None));
+        let peek = last.clone();
+
+        let (req_tx, req_rx) = channel();
+        let (reply_tx, reply_rx) = channel();
+        spawn(Workers { threads: 1, ordered: false }, Chain::new(), Binary, "Context", req_rx, reply_tx, move || Peek(peek.clone()));
+
+        let mut request_header = Header::new(BINARY_PROTOCOL);
+        request_header.info.insert(context::TIMEOUT_HEADER.to_string(), "60000".to_string());
+        let call = util::create_empty_thrift_message("fast", ThriftMessageType::Call);
+        req_tx.send((Token(1), peer(), header::wrap(&request_header, &call).unwrap())).unwrap();
+        reply_rx.recv().unwrap();
+
+        let ctx = last.lock().unwrap().take().unwrap();
+        assert_eq!(ctx.peer, Some(peer().addr));
+        assert_eq!(ctx.identity, Some("billing".to_string()));
+        assert_eq!(&*ctx.name, "fast");
+        assert!(ctx.deadline.is_some());
+
+        let key = metrics::Key {
+            side: Side::Server,
+            service: "Context".to_string(),
+            method: "fast".to_string()
+        };
+        let stats = metrics::snapshot().methods[&key].clone();
+        assert_eq!((stats.requests, stats.errors, stats.in_flight), (1, 0, 0));
+    }
+
+    #[test]
+    fn should_record_unknown_methods_under_one_label() {
+        let (req_tx, req_rx) = channel();
+        let (reply_tx, reply_rx) = channel();
+        spawn(Workers { threads: 1, ordered: false }, Chain::new(), Binary, "Unknown", req_rx, reply_tx, || Sleepy);
+
+        for name in ["foo", "bar"].iter() {
+            let call = util::create_empty_thrift_message(name, ThriftMessageType::Call);
+            req_tx.send((Token(1), peer(), call)).unwrap();
+            reply_rx.recv().unwrap();
+        }
+
+        let methods = metrics::snapshot().methods;
+        let unknown: Vec<_> = methods.iter().filter(|&(key, _)| &*key.service == "Unknown").collect();
+        assert_eq!(unknown.len(), 1);
+        assert_eq!(&*unknown[0].0.method, metrics::UNKNOWN_METHOD);
+        assert_eq!(unknown[0].1.requests, 2);
+    }
+
+    #[test]
+    fn should_join_the_trace() {
+        let last = Arc::new(Mutex::new(None));
+        let peek = last.clone();
+        let reporter = MemoryReporter::new();
+        let _guard = tracing::scoped_reporter(reporter.clone(), 1.0);
+
+        let (req_tx, req_rx) = channel();
+        let (reply_tx, reply_rx) = channel();
+        spawn(Workers { threads: 1, ordered: false }, Chain::new(), Binary, "Test", req_rx, reply_tx, move || Peek(peek.clone()));
+
+        let trace = TraceContext::root(true);
+        let mut request_header = Header::new(BINARY_PROTOCOL);
+        trace.to_headers(&mut request_header.info);
+        let call = util::create_empty_thrift_message("traced", ThriftMessageType::Call);
+        req_tx.send((Token(1), peer(), header::wrap(&request_header, &call).unwrap())).unwrap();
+        reply_rx.recv().unwrap();
+
+        let ctx = last.lock().unwrap().take().unwrap();
+        assert_eq!(ctx.trace, Some(trace));
+
+        let spans: Vec<_> = reporter.spans().into_iter().filter(|span| &*span.name == "traced").collect();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].trace, trace);
+        assert_eq!(spans[0].kind, Kind::Server);
+        assert_eq!(spans[0].error, None);
+    }
+}
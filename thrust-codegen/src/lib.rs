@@ -2,52 +2,66 @@
 
 use std::io::{self, Write};
 
-pub fn write_runner_match(wr: &mut Write, name: &str, method: &ServiceMethod) {
+pub fn write_runner_match(wr: &mut Write, name: &str, method: &ServiceMethod, options: &Options) {
     write!(wr, "\"{method}\" => {{\n", method=method.ident);
     write!(wr, "let args: {service}_{method}_Args = try!(Deserialize::deserialize(de));\n", service=name, method=method.ident);
     write!(wr, "let ret = self.service.{method}(", method=method.ident);
 
+    if options.context {
+        write!(wr, "ctx,");
+    }
+
     for arg in method.args.iter() {
         write!(wr, "args.{},", arg.ident);
     }
 
     write!(wr, "
-    ).map(|val| {{
-        let mut buf = Vec::new();
-        {{
-            let mut s = BinarySerializer::new(&mut buf);
-
-            s.write_message_begin(\"{method}\", ThriftMessageType::Reply);
-            s.write_struct_begin(\"{method}_ret\");
-            s.write_field_begin(\"ret\", {ty}, 1);
-            val.serialize(&mut s);
-            s.write_field_stop();
-            s.write_field_end();
-            s.write_struct_end();
-            s.write_message_end();
-        }}
-        buf
+    );
+    let protocol = protocol.clone();
+    let seq = msg.seq;
+    let ret = ret.map(move |val| {{
+        let mut s = protocol.serializer();
+
+        s.write_message_begin_seq(\"{method}\", ThriftMessageType::Reply, seq);
+        s.write_struct_begin(\"{method}_ret\");
+        s.write_field_begin(\"ret\", {ty}, 1);
+        val.serialize(&mut s);
+        s.write_field_stop();
+        s.write_field_end();
+        s.write_struct_end();
+        s.write_message_end();
+        protocol.finish(s)
     }});
     Ok(ret)", method=method.ident, ty=method.ty.to_protocol());
 
     write!(wr, "\n}},");
 }
 
-pub fn write_runner_impl_begin(wr: &mut Write, name: &str) -> Result<(), Error> {
+pub fn write_runner_impl_begin(wr: &mut Write, service: &Service) -> Result<(), Error> {
+    let methods: Vec<String> = service.methods.iter().map(|method| format!("\"{}\"", method.ident)).collect();
     write!(wr, "
 impl<S> Runner for {name}Runner<S>
     where S: {name}Service
 {{
-    fn run<D>(&mut self, de: &mut D, msg: ThriftMessage) -> Result<Future<Vec<u8>>, Error>
-        where D: Deserializer + ThriftDeserializer
+    fn methods(&self) -> &'static [&'static str] {{
+        static METHODS: &'static [&'static str] = &[{methods}];
+        METHODS
+    }}
+
+    fn run<P, D>(&mut self, ctx: &Context, protocol: &P, de: &mut D, msg: ThriftMessage) -> Result<Future<Vec<u8>>, Error>
+        where P: Protocol,
+              D: Deserializer + ThriftDeserializer
     {{
         match &*msg.name {{
-", name=name);
+", name=service.ident, methods=methods.join(", "));
     Ok(())
 }
 
 pub fn write_runner_impl_end(wr: &mut Write) {
-    write!(wr, "            _ => unimplemented!()
+    write!(wr, "            _ => {{
+                let exception = Exception::new(ExceptionKind::UnknownMethod, &format!(\"Unknown method {{}}.\", msg.name));
+                Ok(Future::unit(try!(exception.encode(protocol, &msg.name, msg.seq))))
+            }}
         }}
     }}
 }}");
@@ -77,48 +91,227 @@ pub struct {name}Server {{ \
 }}
 
 impl {name}Server {{
-    pub fn new<S>(service: S, addr: SocketAddr) -> {name}Server
+    pub fn new<S>(service: S, addr: SocketAddr) -> ThrustResult<{name}Server>
+        where S: 'static + {name}Service + Clone
+    {{
+        {name}Server::build(service, addr, ServerBuilder::new())
+    }}
+
+    /// Like `new`, but the worker threads share a single `service` and take turns
+    /// calling it, so every request sees the state behind `&mut self`. One slow call
+    /// holds up all the others.
+    pub fn new_serialized<S>(service: S, addr: SocketAddr) -> ThrustResult<{name}Server>
         where S: 'static + {name}Service
     {{
-        use std::thread;
+        {name}Server::build_serialized(service, addr, ServerBuilder::new())
+    }}
+
+    /// Listen on either a `SocketAddr` or, given a `PathBuf`, a Unix domain socket. The
+    /// `Reactor` needs to be running, binding errors are returned once it has tried.
+    ///
+    /// Requests are run on a pool of threads, see `build`.
+    pub fn with_options<S, A>(service: S, addr: A, framing: Framing, limits: Limits, pool: Workers) -> ThrustResult<{name}Server>
+        where S: 'static + {name}Service + Clone,
+              A: Into<Addr>
+    {{
+        {name}Server::build(service, addr, ServerBuilder::new().framing(framing).limits(limits).workers(pool))
+    }}
+
+    /// Start a server configured by `builder`, which is also where interceptors are added
+    /// and the protocol is chosen.
+    ///
+    /// Every worker thread gets its own clone of `service`, and calls it without waiting
+    /// on the others. State behind `&mut self` isn't shared between requests then,
+    /// anything that should be has to live behind an `Arc`.
+    pub fn build<S, A, P>(service: S, addr: A, builder: ServerBuilder<P>) -> ThrustResult<{name}Server>
+        where S: 'static + {name}Service + Clone,
+              A: Into<Addr>,
+              P: Protocol
+    {{
+        let (handle, tx) = try!(builder.service(\"{name}\").spawn(addr.into(), || {name}Runner::new(service.clone())));
+
+        Ok({name}Server {{
+            dispatcher: tx,
+            handle: handle,
+        }})
+    }}
+
+    /// Like `build`, but with a single `service` shared by the worker threads, see
+    /// `new_serialized`.
+    pub fn build_serialized<S, A, P>(service: S, addr: A, builder: ServerBuilder<P>) -> ThrustResult<{name}Server>
+        where S: 'static + {name}Service,
+              A: Into<Addr>,
+              P: Protocol
+    {{
+        {name}Server::build(Arc::new(Mutex::new(service)), addr, builder)
+    }}
+
+    /// The address the server is actually listening on, which is useful after binding
+    /// to port 0. Blocks until the listener has been bound.
+    pub fn local_addr(&self) -> ThrustResult<Addr> {{
         use std::sync::mpsc::channel;
-        use std::io::Cursor;
 
-        let (sender, receiver) = channel();
-        let (handle, tx) = Dispatcher::spawn(dispatcher::Role::Server(addr, sender)).unwrap();
+        let (tx, rx) = channel();
+        try!(self.dispatcher.send(Incoming::LocalAddr(tx)));
+        Ok(try!(rx.recv()))
+    }}
+}}", name=name);
+    Ok(())
+}
+
+pub fn write_sync_server(wr: &mut Write, name: &str) -> Result<(), Error> {
+    write!(wr, "\n
+pub struct {name}SyncServer<P: Protocol = Binary> {{
+    listener: TcpListener,
+    framing: Framing,
+    protocol: P
+}}
+
+impl {name}SyncServer<Binary> {{
+    pub fn bind(addr: SocketAddr) -> ThrustResult<{name}SyncServer<Binary>> {{
+        {name}SyncServer::with_framing(addr, Framing::Framed)
+    }}
+
+    pub fn with_framing(addr: SocketAddr, framing: Framing) -> ThrustResult<{name}SyncServer<Binary>> {{
+        {name}SyncServer::with_protocol(addr, framing, Binary)
+    }}
+}}
+
+impl<P: Protocol> {name}SyncServer<P> {{
+    pub fn with_protocol(addr: SocketAddr, framing: Framing, protocol: P) -> ThrustResult<{name}SyncServer<P>> {{
+        Ok({name}SyncServer {{
+            listener: try!(TcpListener::bind(addr)),
+            framing: framing,
+            protocol: protocol
+        }})
+    }}
 
-        let send_tx = tx.clone();
+    pub fn local_addr(&self) -> ThrustResult<SocketAddr> {{
+        Ok(try!(self.listener.local_addr()))
+    }}
+
+    /// Accept connections forever. Each connection is served on its own thread with its
+    /// own clone of the service. Wrap the service in an `Arc<Mutex<_>>` to share a
+    /// single one between connections instead.
+    pub fn serve<S>(self, service: S) -> ThrustResult<()>
+        where S: 'static + Send + {name}Service + Clone
+    {{
+        blocking::serve(self.listener, self.framing, Limits::default(), self.protocol, move || {{
+            {name}Runner::new(service.clone())
+        }})
+    }}
+}}", name=name);
+    Ok(())
+}
+
+pub fn write_sync_client(wr: &mut Write, service: &Service) -> Result<(), Error> {
+    write!(wr, "\n
+pub struct {name}SyncClient<T: Read + Write, P: Protocol = Binary> {{
+    transport: T,
+    protocol: P
+}}
+
+impl {name}SyncClient<Framed<TcpTransport>> {{
+    pub fn connect(addr: SocketAddr) -> ThrustResult<{name}SyncClient<Framed<TcpTransport>>> {{
+        let mut transport = Framed::new(TcpTransport::new(addr));
+        try!(transport.open());
+        Ok({name}SyncClient::new(transport))
+    }}
+}}
+
+impl {name}SyncClient<Buffered<ChannelTransport>> {{
+    /// Serve `service` on a background thread and connect to it in-process, without
+    /// any sockets involved.
+    pub fn loopback<S>(service: S) -> {name}SyncClient<Buffered<ChannelTransport>>
+        where S: 'static + {name}Service
+    {{
+        use std::thread;
+
+        let (client, server) = loopback();
         thread::spawn(move || {{
-            let mut runner = {name}Runner::new(service);
-            for (token, buf) in receiver.iter() {{
-                let mut de = BinaryDeserializer::new(Cursor::new(buf));
-                match de.read_message_begin() {{
-                    Ok(msg) => {{
-                        match runner.run(&mut de, msg) {{
-                            Ok(f) => {{
-                                let chan = send_tx.clone();
-                                f.and_then(move |buf| {{
-                                    chan.send(Incoming::Reply(token, buf));
-                                    Async::Ok(())
-                                }});
-                            }},
-                            Err(err) => {{\n
-                            }}
-                        }}
-                    }},
-                    Err(err) => {{
-                        println!(\"[server]: error parsing thrift message: {{:?}}\", err);
-                    }}
-                }}
-            }}
+            blocking::serve_connection(&mut {name}Runner::new(service), &Binary, None, &mut Buffered::new(server), Limits::default().max_frame_size)
         }});
 
-        {name}Server {{
-            dispatcher: tx,
-            handle: handle,
+        {name}SyncClient::new(Buffered::new(client))
+    }}
+}}
+
+impl<T: Read + Write> {name}SyncClient<T> {{
+    pub fn new(transport: T) -> {name}SyncClient<T> {{
+        {name}SyncClient::with_protocol(transport, Binary)
+    }}
+}}
+
+impl<T: Read + Write, P: Protocol> {name}SyncClient<T, P> {{
+    pub fn with_protocol(transport: T, protocol: P) -> {name}SyncClient<T, P> {{
+        {name}SyncClient {{
+            transport: transport,
+            protocol: protocol
         }}
     }}
-}}", name=name);
+", name=service.ident);
+
+    for method in service.methods.iter() {
+        write_sync_client_method(wr, &service.ident, method)?;
+    }
+
+    write!(wr, "}}\n");
+    Ok(())
+}
+
+/// Write the function that decodes the reply to a call to `method`. An exception the
+/// server replied with becomes a `ThrustError::Exception`. Every client decodes its
+/// replies through it.
+pub fn write_reply_reader(wr: &mut Write, name: &str, method: &ServiceMethod) -> Result<(), Error> {
+    write!(wr, "
+fn {name}_{method}_reply<P: Protocol>(protocol: &P, buf: Vec<u8>) -> ThrustResult<{ty}> {{
+    let mut de = protocol.deserializer(buf);
+    let msg = try!(de.read_message_begin());
+    match msg.ty {{
+        ThriftMessageType::Reply => {{}},
+        ThriftMessageType::Exception => return Err(ThrustError::Exception(try!(Exception::read(&mut de)))),
+        _ => return Err(ThrustError::Str(format!(\"Unexpected reply to {method}: {{:?}}\", msg)))
+    }}
+
+    try!(de.read_struct_begin());
+    try!(de.read_field_begin());
+    let ret = try!(Deserialize::deserialize(&mut de));
+    try!(de.read_field_end());
+    // The stop that ends the reply struct.
+    try!(de.read_field_begin());
+    try!(de.read_struct_end());
+    try!(de.read_message_end());
+    Ok(ret)
+}}\n", name=name, method=method.ident, ty=method.ty.to_string());
+
+    Ok(())
+}
+
+pub fn write_sync_client_method(wr: &mut Write, name: &str, method: &ServiceMethod) -> Result<(), Error> {
+    write!(wr, "\n    pub fn {method}(&mut self", method=method.ident);
+    MethodCodegen::args(wr, &method.args)?;
+    write!(wr, ") -> ThrustResult<{ty}> {{
+        let mut se = self.protocol.serializer();
+        {{
+            try!(se.write_message_begin(\"{method}\", ThriftMessageType::Call));
+            let args = {name}_{method}_Args {{\n", method=method.ident, name=name, ty=method.ty.to_string());
+
+    for arg in method.args.iter() {
+        ws(wr, 4);
+        write!(wr, "{}: {},\n", arg.ident, arg.ident);
+    }
+
+    write!(wr, "            }};
+            try!(args.serialize(&mut se));
+            try!(se.write_message_end());
+        }}
+        try!(self.transport.write_all(&self.protocol.finish(se)));
+        try!(self.transport.flush());
+
+        let buf = try!(self.protocol.read_from(&mut self.transport, Limits::default().max_frame_size));
+        {name}_{method}_reply(&self.protocol, buf)
+    }}\n", method=method.ident, name=name);
+
     Ok(())
 }
 
@@ -168,26 +361,53 @@ pub fn find_rust_namespace(parser: &mut Parser) -> Result<Namespace, Error> {
     }
 }
 
+/// What to generate besides the defaults.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    /// Give every service method the `thrust::Context` of the call as its first
+    /// argument, after `self`.
+    pub context: bool
+}
+
 pub fn compile(parser: &mut Parser, wr: &mut Write) -> Result<(), Error> {
+    compile_with(parser, wr, &Options::default())
+}
+
+/// Generate the code for every service `parser` finds. Generated clients log through
+/// the `log` macros, so the crate the code ends up in needs `#[macro_use] extern crate log;`.
+pub fn compile_with(parser: &mut Parser, wr: &mut Write, options: &Options) -> Result<(), Error> {
 
     // let ns = find_rust_namespace(parser)?;
     write!(wr, "// autogenerated by thrust\n");
-    write!(wr, "#![allow(dead_code, unused_imports)]\n");
+    write!(wr, "// The crate this is part of needs `#[macro_use] extern crate log;`.\n");
+    write!(wr, "#![allow(dead_code, unused_imports, unused_variables)]\n");
     let modules = vec![
         "thrust::protocol::{Error, ThriftType}",
         "thrust::{ThrustResult, ThrustError}",
-        "thrust::dispatcher::{self, Dispatcher, Incoming}",
-        "thrust::reactor::Message",
+        "thrust::dispatcher::{self, Dispatcher, Incoming, Reply}",
+        "thrust::workers::Workers",
+        "thrust::server::{ServerBuilder, Exception, ExceptionKind}",
+        "thrust::pool::{Pool, PoolConfig}",
+        "thrust::breaker::{Breaker, BreakerConfig}",
+        "thrust::client::{self, CancelGuard}",
+        "thrust::tracing",
+        "thrust::reactor::{Message, Framing, Limits, Addr}",
         "std::thread::JoinHandle",
         "std::net::SocketAddr",
-        "thrust::Runner",
+        "std::time::Duration",
+        "thrust::{Runner, Context}",
+        "std::sync::{Arc, Mutex}",
         "std::sync::mpsc::{Sender, Receiver}",
         "tangle::{Future, Async}",
         "std::collections::{HashMap, HashSet}",
         "thrust::protocol::{ThriftDeserializer, ThriftSerializer, ThriftMessageType}",
-        "thrust::protocol::{Serializer, Deserializer}",
+        "thrust::protocol::{Serializer, Deserializer, Protocol}",
         "thrust::protocol::{Deserialize, Serialize, ThriftMessage}",
-        "thrust::binary_protocol::{BinarySerializer, BinaryDeserializer}"
+        "thrust::binary_protocol::{Binary, BinarySerializer, BinaryDeserializer}",
+        "thrust::transport::{Transport, Framed, Buffered, TcpTransport, ChannelTransport, loopback}",
+        "thrust::blocking",
+        "std::net::TcpListener",
+        "std::io::{Read, Write}"
     ];
 
     for module in modules.iter() {
@@ -203,7 +423,7 @@ pub fn compile(parser: &mut Parser, wr: &mut Write) -> Result<(), Error> {
             parser.parse_struct()?;
         } else if parser.lookahead_keyword(Keyword::Service) {
             let service = parser.parse_service()?;
-            ServiceCodegen::build(wr, &service)?;
+            ServiceCodegen::build(wr, &service, options)?;
         } else {
             break;
         }
@@ -219,9 +439,13 @@ pub struct MethodCodegen;
 /// Return type -> Future<$ty>
 /// ```
 impl MethodCodegen {
-    pub fn build(wr: &mut Write, method: &ServiceMethod) -> Result<(), Error> {
+    pub fn build(wr: &mut Write, method: &ServiceMethod, options: &Options) -> Result<(), Error> {
         write!(wr, "fn {}(&mut self", method.ident);
 
+        if options.context {
+            write!(wr, ", ctx: &Context");
+        }
+
         MethodCodegen::args(wr, &method.args)?;
 
         write!(wr, ") ");
@@ -256,21 +480,32 @@ fn ws(wr: &mut Write, n: usize) -> Result<(), Error> {
 }
 
 impl ServiceCodegen {
-    pub fn build(wr: &mut Write, service: &Service) -> Result<(), Error> {
-        ServiceCodegen::build_trait(wr, service)?;
+    pub fn build(wr: &mut Write, service: &Service, options: &Options) -> Result<(), Error> {
+        ServiceCodegen::build_trait(wr, service, options)?;
+        ServiceCodegen::impl_shared_service(wr, service, options)?;
         ServiceCodegen::build_client_struct(wr, service)?;
         ServiceCodegen::build_client_impl(wr, service)?;
         ServiceCodegen::build_args_struct(wr, service)?;
         ServiceCodegen::impl_serialize_args(wr, service)?;
         ServiceCodegen::impl_deserialize_args(wr, service)?;
-        ServiceCodegen::impl_service_client(wr, service)?;
+
+        write!(wr, "\n");
+        for method in service.methods.iter() {
+            write_reply_reader(wr, &service.ident, method)?;
+        }
+
+        ServiceCodegen::impl_service_client(wr, service, options)?;
+        ServiceCodegen::build_pool_client(wr, service)?;
+        ServiceCodegen::impl_service_pool_client(wr, service, options)?;
 
         write_server(wr, &service.ident);
+        write_sync_client(wr, service)?;
+        write_sync_server(wr, &service.ident)?;
         write_runner(wr, &service.ident);
-        write_runner_impl_begin(wr, &service.ident);
+        write_runner_impl_begin(wr, service);
 
         for method in service.methods.iter() {
-            write_runner_match(wr, &service.ident, method);
+            write_runner_match(wr, &service.ident, method, options);
         }
 
         write_runner_impl_end(wr);
@@ -278,64 +513,88 @@ impl ServiceCodegen {
         Ok(())
     }
 
-    pub fn impl_service_client(wr: &mut Write, service: &Service) -> Result<(), Error> {
-        write!(wr, "\nimpl {}Service for {}Client {{\n", service.ident, service.ident);
+    pub fn impl_service_client(wr: &mut Write, service: &Service, options: &Options) -> Result<(), Error> {
+        write!(wr, "\nimpl<P: Protocol> {}Service for {}Client<P> {{\n", service.ident, service.ident);
 
         for method in service.methods.iter() {
+            let args: Vec<&str> = method.args.iter().map(|arg| &*arg.ident).collect();
+
             write!(wr, "\n");
             ws(wr, 1);
-            MethodCodegen::build(wr, method)?;
+            MethodCodegen::build(wr, method, options)?;
             write!(wr, " {{\n");
 
             ws(wr, 2);
-            write!(wr, "use std::io::Cursor;\n");
-
-            ws(wr, 2);
-            write!(wr, "let (res, future) = Future::<(ThriftMessage, BinaryDeserializer<Cursor<Vec<u8>>>)>::channel();\n");
+            write!(wr, "let (future, guard) = self.{}_cancellable({});\n", method.ident, args.join(", "));
 
             ws(wr, 2);
-            write!(wr, "let mut buf = Vec::new();\n");
+            write!(wr, "guard.disarm();\n");
 
             ws(wr, 2);
-            write!(wr, "{{\n");
+            write!(wr, "future\n");
 
-            ws(wr, 3);
-            write!(wr, "let mut se = BinarySerializer::new(&mut buf);\n");
-
-            ws(wr, 3);
-            write!(wr, "se.write_message_begin(\"{method}\", ThriftMessageType::Call);\n", method=method.ident);
+            ws(wr, 1);
+            write!(wr, "}}\n");
+        }
 
-            ws(wr, 3);
-            write!(wr, "let args = {}_{}_Args {{\n", service.ident, method.ident);
+        write!(wr, "}}\n");
+        write!(wr, "\nimpl<P: Protocol> {}Client<P> {{", service.ident);
 
-            for arg in method.args.iter() {
-                ws(wr, 4);
-                write!(wr, "{}: {},\n", arg.ident, arg.ident);
-            }
+        for method in service.methods.iter() {
+            write!(wr, "\n");
+            ws(wr, 1);
+            write!(wr, "/// Like `{}`, but the call is cancelled once the returned guard is dropped,\n", method.ident);
+            ws(wr, 1);
+            write!(wr, "/// unless its reply has arrived by then.\n");
+            ws(wr, 1);
+            write!(wr, "pub fn {}_cancellable(&mut self", method.ident);
+            MethodCodegen::args(wr, &method.args)?;
+            write!(wr, ") -> (Future<{}>, CancelGuard) {{\n", method.ty.to_string());
 
-            ws(wr, 3);
-            write!(wr, "}};\n");
+            ServiceCodegen::write_call(wr, service, method, "self.protocol")?;
 
-            ws(wr, 3);
-            write!(wr, "args.serialize(&mut se);\n");
+            ws(wr, 2);
+            write!(wr, "self.dispatcher.send(Incoming::Call(\"{}\".to_string(), seq, buf, Some(res), tracing::current())).unwrap();\n", method.ident);
 
-            ws(wr, 3);
-            write!(wr, "se.write_message_end();\n");
+            ws(wr, 2);
+            write!(wr, "let (future, guard) = client::cancellable(self.dispatcher.clone(), seq, future);\n");
 
             ws(wr, 2);
+            write!(wr, "(");
+            ServiceCodegen::write_reply(wr, service, method, "self.protocol")?;
+            write!(wr, ", guard)\n");
+
+            ws(wr, 1);
             write!(wr, "}}\n");
+        }
 
-            ws(wr, 2);
-            write!(wr, "self.dispatcher.send(Incoming::Call(\"{}\".to_string(), buf, Some(res))).unwrap();\n", method.ident);
+        write!(wr, "}}\n");
+        Ok(())
+    }
 
-            ws(wr, 2);
-            write!(wr, "future.and_then(move |(msg, de)| {{\n");
+    pub fn impl_service_pool_client(wr: &mut Write, service: &Service, options: &Options) -> Result<(), Error> {
+        write!(wr, "\nimpl<P: Protocol> {}Service for {}PoolClient<P> {{\n", service.ident, service.ident);
+
+        for method in service.methods.iter() {
+            write!(wr, "\n");
+            ws(wr, 1);
+            MethodCodegen::build(wr, method, options)?;
+            write!(wr, " {{\n");
+
+            ServiceCodegen::write_call(wr, service, method, "self.pool.protocol()")?;
 
+            ws(wr, 2);
+            write!(wr, "if let Err(err) = self.pool.call(\"{}\".to_string(), seq, buf, res) {{\n", method.ident);
+            ws(wr, 3);
+            write!(wr, "warn!(\"call failed; method={} err={{:?}}\", err);\n", method.ident);
             ws(wr, 3);
-            write!(wr, "Async::Ok(\"foobar\".to_string())\n");
+            write!(wr, "return Future::err(());\n");
+            ws(wr, 2);
+            write!(wr, "}}\n");
 
             ws(wr, 2);
-            write!(wr, "}})\n");
+            ServiceCodegen::write_reply(wr, service, method, "self.pool.protocol()")?;
+            write!(wr, "\n");
 
             ws(wr, 1);
             write!(wr, "}}\n");
@@ -345,6 +604,84 @@ impl ServiceCodegen {
         Ok(())
     }
 
+    /// Decode the reply `future` resolves with the protocol `protocol` evaluates to. A
+    /// call that fails, or is answered with an exception, is logged and fails the future.
+    fn write_reply(wr: &mut Write, service: &Service, method: &ServiceMethod, protocol: &str) -> Result<(), Error> {
+        write!(wr, "{{\n");
+
+        ws(wr, 3);
+        write!(wr, "let protocol = {}.clone();\n", protocol);
+
+        ws(wr, 3);
+        write!(wr, "future.and_then(move |(_, buf)| {{\n");
+
+        ws(wr, 4);
+        write!(wr, "match {}_{}_reply(&protocol, buf) {{\n", service.ident, method.ident);
+
+        ws(wr, 5);
+        write!(wr, "Ok(ret) => Async::Ok(ret),\n");
+
+        ws(wr, 5);
+        write!(wr, "Err(err) => {{\n");
+
+        ws(wr, 6);
+        write!(wr, "warn!(\"call failed; method={} err={{:?}}\", err);\n", method.ident);
+
+        ws(wr, 6);
+        write!(wr, "Async::Err(())\n");
+
+        ws(wr, 5);
+        write!(wr, "}}\n");
+
+        ws(wr, 4);
+        write!(wr, "}}\n");
+
+        ws(wr, 3);
+        write!(wr, "}})\n");
+
+        ws(wr, 2);
+        write!(wr, "}}");
+        Ok(())
+    }
+
+    /// Encode a call to `method` with the protocol `protocol` evaluates to, leaving the
+    /// call in `buf`, its sequence id in `seq` and the reply channel in `res`, for
+    /// `future`.
+    fn write_call(wr: &mut Write, service: &Service, method: &ServiceMethod, protocol: &str) -> Result<(), Error> {
+        ws(wr, 2);
+        write!(wr, "let seq = client::next_seq();\n");
+
+        ws(wr, 2);
+        write!(wr, "let (res, future) = Future::<Reply>::channel();\n");
+
+        ws(wr, 2);
+        write!(wr, "let mut se = {}.serializer();\n", protocol);
+
+        ws(wr, 2);
+        write!(wr, "se.write_message_begin_seq(\"{method}\", ThriftMessageType::Call, seq);\n", method=method.ident);
+
+        ws(wr, 2);
+        write!(wr, "let args = {}_{}_Args {{\n", service.ident, method.ident);
+
+        for arg in method.args.iter() {
+            ws(wr, 3);
+            write!(wr, "{}: {},\n", arg.ident, arg.ident);
+        }
+
+        ws(wr, 2);
+        write!(wr, "}};\n");
+
+        ws(wr, 2);
+        write!(wr, "args.serialize(&mut se);\n");
+
+        ws(wr, 2);
+        write!(wr, "se.write_message_end();\n");
+
+        ws(wr, 2);
+        write!(wr, "let buf = {}.finish(se);\n", protocol);
+        Ok(())
+    }
+
     pub fn impl_serialize_args(wr: &mut Write, service: &Service) -> Result<(), Error> {
         for method in service.methods.iter() {
             ServiceCodegen::impl_serialize_arg(wr, &service.ident, method)?;
@@ -360,8 +697,6 @@ impl ServiceCodegen {
         ws(wr, 2);
         write!(wr, "try!(self.{}.serialize(s));\n", arg.ident);
         ws(wr, 2);
-        write!(wr, "try!(s.write_field_stop());\n");
-        ws(wr, 2);
         write!(wr, "try!(s.write_field_end());\n");
 
         Ok(())
@@ -434,6 +769,9 @@ impl ServiceCodegen {
         ws(wr, 2);
         write!(wr, "}};\n");
 
+        // Consume the stop that ends the struct, so the whole message has been read.
+        ws(wr, 2);
+        write!(wr, "try!(de.read_field_begin());\n");
         ws(wr, 2);
         write!(wr, "try!(de.read_struct_end());\n");
         ws(wr, 2);
@@ -461,6 +799,10 @@ impl ServiceCodegen {
             ServiceCodegen::serialize_arg(wr, arg)?;
         }
 
+        // A single stop marks the end of the struct, unframed transports rely on it to
+        // find where a message ends.
+        ws(wr, 2);
+        write!(wr, "try!(s.write_field_stop());\n");
         ws(wr, 2);
         write!(wr, "try!(s.write_struct_end());\n");
         ws(wr, 2);
@@ -500,17 +842,44 @@ impl ServiceCodegen {
 
     pub fn build_client_impl(wr: &mut Write, service: &Service) -> Result<(), Error> {
         write!(wr, "\n\n");
-        write!(wr, "impl {}Client {{\n", service.ident);
+        write!(wr, "impl {}Client<Binary> {{\n", service.ident);
+
+        ws(wr, 1);
+        write!(wr, "pub fn new(addr: SocketAddr) -> ThrustResult<{}Client<Binary>> {{\n", service.ident);
+
+        ws(wr, 2);
+        write!(wr, "{}Client::with_framing(addr, Framing::Framed)\n", service.ident);
+
+        ws(wr, 1);
+        write!(wr, "}}\n\n");
+
+        ws(wr, 1);
+        write!(wr, "/// Connect to either a `SocketAddr` or, given a `PathBuf`, a Unix domain socket.\n");
+        ws(wr, 1);
+        write!(wr, "pub fn with_framing<A: Into<Addr>>(addr: A, framing: Framing) -> ThrustResult<{}Client<Binary>> {{\n", service.ident);
+
+        ws(wr, 2);
+        write!(wr, "{}Client::with_protocol(addr, framing, Binary)\n", service.ident);
+
+        ws(wr, 1);
+        write!(wr, "}}\n");
+
+        write!(wr, "}}\n\n");
+        write!(wr, "impl<P: Protocol> {}Client<P> {{\n", service.ident);
 
         ws(wr, 1);
-        write!(wr, "pub fn new(addr: SocketAddr) -> {}Client {{\n", service.ident);
+        write!(wr, "/// Like `with_framing`, but speaking `protocol`.\n");
+        ws(wr, 1);
+        write!(wr, "pub fn with_protocol<A: Into<Addr>>(addr: A, framing: Framing, protocol: P) -> ThrustResult<{}Client<P>> {{\n", service.ident);
 
         ws(wr, 2);
-        write!(wr, "let (handle, tx) = Dispatcher::spawn(dispatcher::Role::Client(addr)).unwrap();\n");
+        write!(wr, "let (handle, tx) = try!(Dispatcher::spawn(dispatcher::Role::Client(addr.into(), framing), protocol.clone()));\n");
+        ws(wr, 2);
+        write!(wr, "try!(tx.send(Incoming::Service(\"{}\".to_string())));\n", service.ident);
 
         write!(wr, "\n");
         ws(wr, 2);
-        write!(wr, "{}Client {{\n", service.ident);
+        write!(wr, "Ok({}Client {{\n", service.ident);
 
         ws(wr, 3);
         write!(wr, "dispatcher: tx,\n");
@@ -518,8 +887,39 @@ impl ServiceCodegen {
         ws(wr, 3);
         write!(wr, "handle: handle,\n");
 
+        ws(wr, 3);
+        write!(wr, "protocol: protocol,\n");
+
         ws(wr, 2);
-        write!(wr, "}}\n");
+        write!(wr, "}})\n");
+
+        ws(wr, 1);
+        write!(wr, "}}\n\n");
+
+        ws(wr, 1);
+        write!(wr, "/// Fail calls fast while `breaker` is open.\n");
+        ws(wr, 1);
+        write!(wr, "pub fn with_breaker(self, breaker: Breaker) -> {}Client<P> {{\n", service.ident);
+
+        ws(wr, 2);
+        write!(wr, "self.dispatcher.send(Incoming::Guard(breaker));\n");
+
+        ws(wr, 2);
+        write!(wr, "self\n");
+
+        ws(wr, 1);
+        write!(wr, "}}\n\n");
+
+        ws(wr, 1);
+        write!(wr, "/// Fail calls that haven't been replied to after `deadline`.\n");
+        ws(wr, 1);
+        write!(wr, "pub fn with_deadline(self, deadline: Duration) -> {}Client<P> {{\n", service.ident);
+
+        ws(wr, 2);
+        write!(wr, "self.dispatcher.send(Incoming::Deadline(deadline));\n");
+
+        ws(wr, 2);
+        write!(wr, "self\n");
 
         ws(wr, 1);
         write!(wr, "}}\n");
@@ -529,27 +929,86 @@ impl ServiceCodegen {
     }
 
     pub fn build_client_struct(wr: &mut Write, service: &Service) -> Result<(), Error> {
-        write!(wr, "\npub struct {}Client {{\n", service.ident);
+        write!(wr, "\npub struct {}Client<P: Protocol = Binary> {{\n", service.ident);
         ws(wr, 1);
         write!(wr, "dispatcher: Sender<dispatcher::Incoming>,\n");
         ws(wr, 1);
         write!(wr, "pub handle: JoinHandle<ThrustResult<()>>,\n");
+        ws(wr, 1);
+        write!(wr, "protocol: P,\n");
+        write!(wr, "}}\n");
+        Ok(())
+    }
+
+    pub fn build_pool_client(wr: &mut Write, service: &Service) -> Result<(), Error> {
+        write!(wr, "\n/// Spreads calls over connections to several endpoints.\n");
+        write!(wr, "pub struct {}PoolClient<P: Protocol = Binary> {{\n", service.ident);
+        ws(wr, 1);
+        write!(wr, "pool: Pool<P>\n");
+        write!(wr, "}}\n\n");
+
+        write!(wr, "impl {}PoolClient<Binary> {{\n", service.ident);
+        ws(wr, 1);
+        write!(wr, "pub fn new(endpoints: Vec<Addr>, config: PoolConfig) -> ThrustResult<{}PoolClient<Binary>> {{\n", service.ident);
+        ws(wr, 2);
+        write!(wr, "{}PoolClient::with_protocol(endpoints, config, Binary)\n", service.ident);
+        ws(wr, 1);
+        write!(wr, "}}\n");
+        write!(wr, "}}\n\n");
+
+        write!(wr, "impl<P: Protocol> {}PoolClient<P> {{\n", service.ident);
+        ws(wr, 1);
+        write!(wr, "pub fn with_protocol(endpoints: Vec<Addr>, config: PoolConfig, protocol: P) -> ThrustResult<{}PoolClient<P>> {{\n", service.ident);
+        ws(wr, 2);
+        write!(wr, "Ok({}PoolClient {{\n", service.ident);
+        ws(wr, 3);
+        write!(wr, "pool: try!(Pool::with_protocol(endpoints, config, protocol)).named(\"{}\")\n", service.ident);
+        ws(wr, 2);
+        write!(wr, "}})\n");
+        ws(wr, 1);
+        write!(wr, "}}\n");
         write!(wr, "}}\n");
         Ok(())
     }
 
-    pub fn build_trait(wr: &mut Write, service: &Service) -> Result<(), Error> {
+    pub fn build_trait(wr: &mut Write, service: &Service, options: &Options) -> Result<(), Error> {
         write!(wr, "\npub trait {}Service: Send {{\n", service.ident)?;
 
         for method in service.methods.iter() {
             ws(wr, 1);
-            MethodCodegen::build(wr, method);
+            MethodCodegen::build(wr, method, options);
             write!(wr, ";\n");
         }
 
         write!(wr, "}}\n")?;
         Ok(())
     }
+
+    /// A service shared between threads takes turns handling calls.
+    pub fn impl_shared_service(wr: &mut Write, service: &Service, options: &Options) -> Result<(), Error> {
+        write!(wr, "\nimpl<S: {}Service> {}Service for Arc<Mutex<S>> {{\n", service.ident, service.ident)?;
+
+        for method in service.methods.iter() {
+            let mut args: Vec<&str> = method.args.iter().map(|arg| &*arg.ident).collect();
+            if options.context {
+                args.insert(0, "ctx");
+            }
+
+            write!(wr, "\n");
+            ws(wr, 1);
+            MethodCodegen::build(wr, method, options)?;
+            write!(wr, " {{\n");
+
+            ws(wr, 2);
+            write!(wr, "self.lock().expect(\"Failed to take the service lock.\").{}({})\n", method.ident, args.join(", "));
+
+            ws(wr, 1);
+            write!(wr, "}}\n");
+        }
+
+        write!(wr, "}}\n")?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -563,7 +1022,8 @@ mod tests {
         Enum,
         Namespace,
         FieldAttribute,
-        StructField
+        StructField,
+        Parser
     };
 
     #[test]
@@ -629,7 +1089,7 @@ mod tests {
             ty: Ty::String,
             attr: FieldAttribute::Required,
             args: vec![arg]
-        });
+        }, &Options::default());
         assert_eq!(&*String::from_utf8(buf).unwrap(), "fn query(&mut self, voodoo: i32) -> Future<String>;\n");
     }
 
@@ -653,6 +1113,81 @@ mod tests {
             }]
         };
 
-        let ret = ServiceCodegen::build_trait(&mut buf, &service);
+        let ret = ServiceCodegen::build_trait(&mut buf, &service, &Options::default());
+    }
+
+    #[test]
+    fn service_method_build_with_context() {
+        let mut buf = Vec::new();
+        let ret = MethodCodegen::build(&mut buf, &ServiceMethod {
+            ident: "query".to_string(),
+            ty: Ty::String,
+            attr: FieldAttribute::Required,
+            args: Vec::new()
+        }, &Options { context: true });
+        assert_eq!(&*String::from_utf8(buf).unwrap(), "fn query(&mut self, ctx: &Context) -> Future<String>");
+    }
+
+    #[test]
+    fn pool_client_method() {
+        let mut buf = Vec::new();
+        let service = Service {
+            ident: "Flock".to_string(),
+            methods: vec![ServiceMethod {
+                ident: "query".to_string(),
+                ty: Ty::String,
+                attr: FieldAttribute::Required,
+                args: Vec::new()
+            }]
+        };
+
+        ServiceCodegen::impl_service_pool_client(&mut buf, &service, &Options::default()).unwrap();
+        let out = String::from_utf8(buf).unwrap();
+        assert!(out.contains("warn!(\"call failed; method=query err={:?}\", err);\n            return Future::err(());"));
+        assert!(out.contains("match Flock_query_reply(&protocol, buf) {"));
+        assert!(!out.contains("foobar"));
+    }
+
+    #[test]
+    fn clients_decode_the_return_type() {
+        let service = Service {
+            ident: "Flock".to_string(),
+            methods: vec![ServiceMethod {
+                ident: "count".to_string(),
+                ty: Ty::I64,
+                attr: FieldAttribute::Required,
+                args: Vec::new()
+            }]
+        };
+
+        let mut buf = Vec::new();
+        write_reply_reader(&mut buf, &service.ident, &service.methods[0]).unwrap();
+        let reader = String::from_utf8(buf).unwrap();
+        assert!(reader.contains("fn Flock_count_reply<P: Protocol>(protocol: &P, buf: Vec<u8>) -> ThrustResult<i64> {"));
+        assert!(reader.contains("ThriftMessageType::Exception => return Err(ThrustError::Exception(try!(Exception::read(&mut de)))),"));
+
+        let mut buf = Vec::new();
+        ServiceCodegen::impl_service_pool_client(&mut buf, &service, &Options::default()).unwrap();
+        let pool = String::from_utf8(buf).unwrap();
+        assert!(pool.contains("fn count(&mut self) -> Future<i64> {"));
+        assert!(pool.contains("match Flock_count_reply(&protocol, buf) {"));
+
+        let mut buf = Vec::new();
+        ServiceCodegen::impl_service_client(&mut buf, &service, &Options::default()).unwrap();
+        let client = String::from_utf8(buf).unwrap();
+        assert!(client.contains("pub fn count_cancellable(&mut self) -> (Future<i64>, CancelGuard) {"));
+        assert!(client.contains("match Flock_count_reply(&protocol, buf) {"));
+        assert!(!client.contains("foobar"));
+    }
+    /// thrust-examples builds the code generated from its `main.thrift`, which is only
+    /// a check of the generator as long as the two stay the same.
+    #[test]
+    fn example_is_up_to_date() {
+        let mut parser = Parser::new(include_str!("../../thrust-examples/main.thrift"));
+        find_rust_namespace(&mut parser).unwrap();
+
+        let mut buf = Vec::new();
+        compile(&mut parser, &mut buf).unwrap();
+        assert_eq!(&*String::from_utf8(buf).unwrap(), include_str!("../../thrust-examples/src/foobar1.rs"));
     }
 }
@@ -1,39 +1,86 @@
 // autogenerated by thrust
-#![allow(dead_code, unused_imports)]
+// The crate this is part of needs `#[macro_use] extern crate log;`.
+#![allow(dead_code, unused_imports, unused_variables)]
 use thrust::protocol::{Error, ThriftType};
 use thrust::{ThrustResult, ThrustError};
-use thrust::dispatcher::{self, Dispatcher, Incoming};
-use thrust::reactor::Message;
+use thrust::dispatcher::{self, Dispatcher, Incoming, Reply};
+use thrust::workers::Workers;
+use thrust::server::{ServerBuilder, Exception, ExceptionKind};
+use thrust::pool::{Pool, PoolConfig};
+use thrust::breaker::{Breaker, BreakerConfig};
+use thrust::client::{self, CancelGuard};
+use thrust::tracing;
+use thrust::reactor::{Message, Framing, Limits, Addr};
 use std::thread::JoinHandle;
 use std::net::SocketAddr;
-use thrust::Runner;
+use std::time::Duration;
+use thrust::{Runner, Context};
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{Sender, Receiver};
 use tangle::{Future, Async};
 use std::collections::{HashMap, HashSet};
 use thrust::protocol::{ThriftDeserializer, ThriftSerializer, ThriftMessageType};
-use thrust::protocol::{Serializer, Deserializer};
+use thrust::protocol::{Serializer, Deserializer, Protocol};
 use thrust::protocol::{Deserialize, Serialize, ThriftMessage};
-use thrust::binary_protocol::{BinarySerializer, BinaryDeserializer};
+use thrust::binary_protocol::{Binary, BinarySerializer, BinaryDeserializer};
+use thrust::transport::{Transport, Framed, Buffered, TcpTransport, ChannelTransport, loopback};
+use thrust::blocking;
+use std::net::TcpListener;
+use std::io::{Read, Write};
 
 
 pub trait BlizzardService: Send {
     fn ack(&mut self, source_id: i64, tuple_id: i64) -> Future<String>;
 }
 
-pub struct BlizzardClient {
+impl<S: BlizzardService> BlizzardService for Arc<Mutex<S>> {
+
+    fn ack(&mut self, source_id: i64, tuple_id: i64) -> Future<String> {
+        self.lock().expect("Failed to take the service lock.").ack(source_id, tuple_id)
+    }
+}
+
+pub struct BlizzardClient<P: Protocol = Binary> {
     dispatcher: Sender<dispatcher::Incoming>,
     pub handle: JoinHandle<ThrustResult<()>>,
+    protocol: P,
 }
 
 
-impl BlizzardClient {
-    pub fn new(addr: SocketAddr) -> BlizzardClient {
-        let (handle, tx) = Dispatcher::spawn(dispatcher::Role::Client(addr)).unwrap();
+impl BlizzardClient<Binary> {
+    pub fn new(addr: SocketAddr) -> ThrustResult<BlizzardClient<Binary>> {
+        BlizzardClient::with_framing(addr, Framing::Framed)
+    }
+
+    /// Connect to either a `SocketAddr` or, given a `PathBuf`, a Unix domain socket.
+    pub fn with_framing<A: Into<Addr>>(addr: A, framing: Framing) -> ThrustResult<BlizzardClient<Binary>> {
+        BlizzardClient::with_protocol(addr, framing, Binary)
+    }
+}
 
-        BlizzardClient {
+impl<P: Protocol> BlizzardClient<P> {
+    /// Like `with_framing`, but speaking `protocol`.
+    pub fn with_protocol<A: Into<Addr>>(addr: A, framing: Framing, protocol: P) -> ThrustResult<BlizzardClient<P>> {
+        let (handle, tx) = try!(Dispatcher::spawn(dispatcher::Role::Client(addr.into(), framing), protocol.clone()));
+        try!(tx.send(Incoming::Service("Blizzard".to_string())));
+
+        Ok(BlizzardClient {
             dispatcher: tx,
             handle: handle,
-        }
+            protocol: protocol,
+        })
+    }
+
+    /// Fail calls fast while `breaker` is open.
+    pub fn with_breaker(self, breaker: Breaker) -> BlizzardClient<P> {
+        self.dispatcher.send(Incoming::Guard(breaker));
+        self
+    }
+
+    /// Fail calls that haven't been replied to after `deadline`.
+    pub fn with_deadline(self, deadline: Duration) -> BlizzardClient<P> {
+        self.dispatcher.send(Incoming::Deadline(deadline));
+        self
     }
 }
 
@@ -49,12 +96,11 @@ impl Serialize for Blizzard_ack_Args {
         try!(s.write_struct_begin("Blizzard_ack_Args"));
         try!(s.write_field_begin("source_id", ThriftType::I64, 1));
         try!(self.source_id.serialize(s));
-        try!(s.write_field_stop());
         try!(s.write_field_end());
         try!(s.write_field_begin("tuple_id", ThriftType::I64, 2));
         try!(self.tuple_id.serialize(s));
-        try!(s.write_field_stop());
         try!(s.write_field_end());
+        try!(s.write_field_stop());
         try!(s.write_struct_end());
         Ok(())
     }
@@ -84,30 +130,122 @@ impl Deserialize for Blizzard_ack_Args {
                 val
             },
         };
+        try!(de.read_field_begin());
         try!(de.read_struct_end());
         Ok(args)
     }
 }
-impl BlizzardService for BlizzardClient {
+
+fn Blizzard_ack_reply<P: Protocol>(protocol: &P, buf: Vec<u8>) -> ThrustResult<String> {
+    let mut de = protocol.deserializer(buf);
+    let msg = try!(de.read_message_begin());
+    match msg.ty {
+        ThriftMessageType::Reply => {},
+        ThriftMessageType::Exception => return Err(ThrustError::Exception(try!(Exception::read(&mut de)))),
+        _ => return Err(ThrustError::Str(format!("Unexpected reply to ack: {:?}", msg)))
+    }
+
+    try!(de.read_struct_begin());
+    try!(de.read_field_begin());
+    let ret = try!(Deserialize::deserialize(&mut de));
+    try!(de.read_field_end());
+    // The stop that ends the reply struct.
+    try!(de.read_field_begin());
+    try!(de.read_struct_end());
+    try!(de.read_message_end());
+    Ok(ret)
+}
+
+impl<P: Protocol> BlizzardService for BlizzardClient<P> {
 
     fn ack(&mut self, source_id: i64, tuple_id: i64) -> Future<String> {
-        use std::io::Cursor;
-        let (res, future) = Future::<(ThriftMessage, BinaryDeserializer<Cursor<Vec<u8>>>)>::channel();
-        let mut buf = Vec::new();
+        let (future, guard) = self.ack_cancellable(source_id, tuple_id);
+        guard.disarm();
+        future
+    }
+}
+
+impl<P: Protocol> BlizzardClient<P> {
+    /// Like `ack`, but the call is cancelled once the returned guard is dropped,
+    /// unless its reply has arrived by then.
+    pub fn ack_cancellable(&mut self, source_id: i64, tuple_id: i64) -> (Future<String>, CancelGuard) {
+        let seq = client::next_seq();
+        let (res, future) = Future::<Reply>::channel();
+        let mut se = self.protocol.serializer();
+        se.write_message_begin_seq("ack", ThriftMessageType::Call, seq);
+        let args = Blizzard_ack_Args {
+            source_id: source_id,
+            tuple_id: tuple_id,
+        };
+        args.serialize(&mut se);
+        se.write_message_end();
+        let buf = self.protocol.finish(se);
+        self.dispatcher.send(Incoming::Call("ack".to_string(), seq, buf, Some(res), tracing::current())).unwrap();
+        let (future, guard) = client::cancellable(self.dispatcher.clone(), seq, future);
+        ({
+            let protocol = self.protocol.clone();
+            future.and_then(move |(_, buf)| {
+                match Blizzard_ack_reply(&protocol, buf) {
+                    Ok(ret) => Async::Ok(ret),
+                    Err(err) => {
+                        warn!("call failed; method=ack err={:?}", err);
+                        Async::Err(())
+                    }
+                }
+            })
+        }, guard)
+    }
+}
+
+/// Spreads calls over connections to several endpoints.
+pub struct BlizzardPoolClient<P: Protocol = Binary> {
+    pool: Pool<P>
+}
+
+impl BlizzardPoolClient<Binary> {
+    pub fn new(endpoints: Vec<Addr>, config: PoolConfig) -> ThrustResult<BlizzardPoolClient<Binary>> {
+        BlizzardPoolClient::with_protocol(endpoints, config, Binary)
+    }
+}
+
+impl<P: Protocol> BlizzardPoolClient<P> {
+    pub fn with_protocol(endpoints: Vec<Addr>, config: PoolConfig, protocol: P) -> ThrustResult<BlizzardPoolClient<P>> {
+        Ok(BlizzardPoolClient {
+            pool: try!(Pool::with_protocol(endpoints, config, protocol)).named("Blizzard")
+        })
+    }
+}
+
+impl<P: Protocol> BlizzardService for BlizzardPoolClient<P> {
+
+    fn ack(&mut self, source_id: i64, tuple_id: i64) -> Future<String> {
+        let seq = client::next_seq();
+        let (res, future) = Future::<Reply>::channel();
+        let mut se = self.pool.protocol().serializer();
+        se.write_message_begin_seq("ack", ThriftMessageType::Call, seq);
+        let args = Blizzard_ack_Args {
+            source_id: source_id,
+            tuple_id: tuple_id,
+        };
+        args.serialize(&mut se);
+        se.write_message_end();
+        let buf = self.pool.protocol().finish(se);
+        if let Err(err) = self.pool.call("ack".to_string(), seq, buf, res) {
+            warn!("call failed; method=ack err={:?}", err);
+            return Future::err(());
+        }
         {
-            let mut se = BinarySerializer::new(&mut buf);
-            se.write_message_begin("ack", ThriftMessageType::Call);
-            let args = Blizzard_ack_Args {
-                source_id: source_id,
-                tuple_id: tuple_id,
-            };
-            args.serialize(&mut se);
-            se.write_message_end();
+            let protocol = self.pool.protocol().clone();
+            future.and_then(move |(_, buf)| {
+                match Blizzard_ack_reply(&protocol, buf) {
+                    Ok(ret) => Async::Ok(ret),
+                    Err(err) => {
+                        warn!("call failed; method=ack err={:?}", err);
+                        Async::Err(())
+                    }
+                }
+            })
         }
-        self.dispatcher.send(Incoming::Call("ack".to_string(), buf, Some(res))).unwrap();
-        future.and_then(move |(msg, de)| {
-            Async::Ok("foobar".to_string())
-        })
     }
 }
 
@@ -117,48 +255,175 @@ pub struct BlizzardServer { dispatcher: Sender<dispatcher::Incoming>,
 }
 
 impl BlizzardServer {
-    pub fn new<S>(service: S, addr: SocketAddr) -> BlizzardServer
+    pub fn new<S>(service: S, addr: SocketAddr) -> ThrustResult<BlizzardServer>
+        where S: 'static + BlizzardService + Clone
+    {
+        BlizzardServer::build(service, addr, ServerBuilder::new())
+    }
+
+    /// Like `new`, but the worker threads share a single `service` and take turns
+    /// calling it, so every request sees the state behind `&mut self`. One slow call
+    /// holds up all the others.
+    pub fn new_serialized<S>(service: S, addr: SocketAddr) -> ThrustResult<BlizzardServer>
         where S: 'static + BlizzardService
     {
-        use std::thread;
+        BlizzardServer::build_serialized(service, addr, ServerBuilder::new())
+    }
+
+    /// Listen on either a `SocketAddr` or, given a `PathBuf`, a Unix domain socket. The
+    /// `Reactor` needs to be running, binding errors are returned once it has tried.
+    ///
+    /// Requests are run on a pool of threads, see `build`.
+    pub fn with_options<S, A>(service: S, addr: A, framing: Framing, limits: Limits, pool: Workers) -> ThrustResult<BlizzardServer>
+        where S: 'static + BlizzardService + Clone,
+              A: Into<Addr>
+    {
+        BlizzardServer::build(service, addr, ServerBuilder::new().framing(framing).limits(limits).workers(pool))
+    }
+
+    /// Start a server configured by `builder`, which is also where interceptors are added
+    /// and the protocol is chosen.
+    ///
+    /// Every worker thread gets its own clone of `service`, and calls it without waiting
+    /// on the others. State behind `&mut self` isn't shared between requests then,
+    /// anything that should be has to live behind an `Arc`.
+    pub fn build<S, A, P>(service: S, addr: A, builder: ServerBuilder<P>) -> ThrustResult<BlizzardServer>
+        where S: 'static + BlizzardService + Clone,
+              A: Into<Addr>,
+              P: Protocol
+    {
+        let (handle, tx) = try!(builder.service("Blizzard").spawn(addr.into(), || BlizzardRunner::new(service.clone())));
+
+        Ok(BlizzardServer {
+            dispatcher: tx,
+            handle: handle,
+        })
+    }
+
+    /// Like `build`, but with a single `service` shared by the worker threads, see
+    /// `new_serialized`.
+    pub fn build_serialized<S, A, P>(service: S, addr: A, builder: ServerBuilder<P>) -> ThrustResult<BlizzardServer>
+        where S: 'static + BlizzardService,
+              A: Into<Addr>,
+              P: Protocol
+    {
+        BlizzardServer::build(Arc::new(Mutex::new(service)), addr, builder)
+    }
+
+    /// The address the server is actually listening on, which is useful after binding
+    /// to port 0. Blocks until the listener has been bound.
+    pub fn local_addr(&self) -> ThrustResult<Addr> {
         use std::sync::mpsc::channel;
-        use std::io::Cursor;
 
-        let (sender, receiver) = channel();
-        let (handle, tx) = Dispatcher::spawn(dispatcher::Role::Server(addr, sender)).unwrap();
+        let (tx, rx) = channel();
+        try!(self.dispatcher.send(Incoming::LocalAddr(tx)));
+        Ok(try!(rx.recv()))
+    }
+}
+
+pub struct BlizzardSyncClient<T: Read + Write, P: Protocol = Binary> {
+    transport: T,
+    protocol: P
+}
+
+impl BlizzardSyncClient<Framed<TcpTransport>> {
+    pub fn connect(addr: SocketAddr) -> ThrustResult<BlizzardSyncClient<Framed<TcpTransport>>> {
+        let mut transport = Framed::new(TcpTransport::new(addr));
+        try!(transport.open());
+        Ok(BlizzardSyncClient::new(transport))
+    }
+}
 
-        let send_tx = tx.clone();
+impl BlizzardSyncClient<Buffered<ChannelTransport>> {
+    /// Serve `service` on a background thread and connect to it in-process, without
+    /// any sockets involved.
+    pub fn loopback<S>(service: S) -> BlizzardSyncClient<Buffered<ChannelTransport>>
+        where S: 'static + BlizzardService
+    {
+        use std::thread;
+
+        let (client, server) = loopback();
         thread::spawn(move || {
-            let mut runner = BlizzardRunner::new(service);
-            for (token, buf) in receiver.iter() {
-                let mut de = BinaryDeserializer::new(Cursor::new(buf));
-                match de.read_message_begin() {
-                    Ok(msg) => {
-                        match runner.run(&mut de, msg) {
-                            Ok(f) => {
-                                let chan = send_tx.clone();
-                                f.and_then(move |buf| {
-                                    chan.send(Incoming::Reply(token, buf));
-                                    Async::Ok(())
-                                });
-                            },
-                            Err(err) => {
-
-                            }
-                        }
-                    },
-                    Err(err) => {
-                        println!("[server]: error parsing thrift message: {:?}", err);
-                    }
-                }
-            }
+            blocking::serve_connection(&mut BlizzardRunner::new(service), &Binary, None, &mut Buffered::new(server), Limits::default().max_frame_size)
         });
 
-        BlizzardServer {
-            dispatcher: tx,
-            handle: handle,
+        BlizzardSyncClient::new(Buffered::new(client))
+    }
+}
+
+impl<T: Read + Write> BlizzardSyncClient<T> {
+    pub fn new(transport: T) -> BlizzardSyncClient<T> {
+        BlizzardSyncClient::with_protocol(transport, Binary)
+    }
+}
+
+impl<T: Read + Write, P: Protocol> BlizzardSyncClient<T, P> {
+    pub fn with_protocol(transport: T, protocol: P) -> BlizzardSyncClient<T, P> {
+        BlizzardSyncClient {
+            transport: transport,
+            protocol: protocol
         }
     }
+
+    pub fn ack(&mut self, source_id: i64, tuple_id: i64) -> ThrustResult<String> {
+        let mut se = self.protocol.serializer();
+        {
+            try!(se.write_message_begin("ack", ThriftMessageType::Call));
+            let args = Blizzard_ack_Args {
+                source_id: source_id,
+                tuple_id: tuple_id,
+            };
+            try!(args.serialize(&mut se));
+            try!(se.write_message_end());
+        }
+        try!(self.transport.write_all(&self.protocol.finish(se)));
+        try!(self.transport.flush());
+
+        let buf = try!(self.protocol.read_from(&mut self.transport, Limits::default().max_frame_size));
+        Blizzard_ack_reply(&self.protocol, buf)
+    }
+}
+
+
+pub struct BlizzardSyncServer<P: Protocol = Binary> {
+    listener: TcpListener,
+    framing: Framing,
+    protocol: P
+}
+
+impl BlizzardSyncServer<Binary> {
+    pub fn bind(addr: SocketAddr) -> ThrustResult<BlizzardSyncServer<Binary>> {
+        BlizzardSyncServer::with_framing(addr, Framing::Framed)
+    }
+
+    pub fn with_framing(addr: SocketAddr, framing: Framing) -> ThrustResult<BlizzardSyncServer<Binary>> {
+        BlizzardSyncServer::with_protocol(addr, framing, Binary)
+    }
+}
+
+impl<P: Protocol> BlizzardSyncServer<P> {
+    pub fn with_protocol(addr: SocketAddr, framing: Framing, protocol: P) -> ThrustResult<BlizzardSyncServer<P>> {
+        Ok(BlizzardSyncServer {
+            listener: try!(TcpListener::bind(addr)),
+            framing: framing,
+            protocol: protocol
+        })
+    }
+
+    pub fn local_addr(&self) -> ThrustResult<SocketAddr> {
+        Ok(try!(self.listener.local_addr()))
+    }
+
+    /// Accept connections forever. Each connection is served on its own thread with its
+    /// own clone of the service. Wrap the service in an `Arc<Mutex<_>>` to share a
+    /// single one between connections instead.
+    pub fn serve<S>(self, service: S) -> ThrustResult<()>
+        where S: 'static + Send + BlizzardService + Clone
+    {
+        blocking::serve(self.listener, self.framing, Limits::default(), self.protocol, move || {
+            BlizzardRunner::new(service.clone())
+        })
+    }
 }
 pub struct BlizzardRunner<S: BlizzardService> {
     service: S
@@ -174,31 +439,40 @@ impl<S> BlizzardRunner<S> where S: BlizzardService {
 impl<S> Runner for BlizzardRunner<S>
     where S: BlizzardService
 {
-    fn run<D>(&mut self, de: &mut D, msg: ThriftMessage) -> Result<Future<Vec<u8>>, Error>
-        where D: Deserializer + ThriftDeserializer
+    fn methods(&self) -> &'static [&'static str] {
+        static METHODS: &'static [&'static str] = &["ack"];
+        METHODS
+    }
+
+    fn run<P, D>(&mut self, ctx: &Context, protocol: &P, de: &mut D, msg: ThriftMessage) -> Result<Future<Vec<u8>>, Error>
+        where P: Protocol,
+              D: Deserializer + ThriftDeserializer
     {
         match &*msg.name {
 "ack" => {
 let args: Blizzard_ack_Args = try!(Deserialize::deserialize(de));
 let ret = self.service.ack(args.source_id,args.tuple_id,
-    ).map(|val| {
-        let mut buf = Vec::new();
-        {
-            let mut s = BinarySerializer::new(&mut buf);
-
-            s.write_message_begin("ack", ThriftMessageType::Reply);
-            s.write_struct_begin("ack_ret");
-            s.write_field_begin("ret", ThriftType::String, 1);
-            val.serialize(&mut s);
-            s.write_field_stop();
-            s.write_field_end();
-            s.write_struct_end();
-            s.write_message_end();
-        }
-        buf
+    );
+    let protocol = protocol.clone();
+    let seq = msg.seq;
+    let ret = ret.map(move |val| {
+        let mut s = protocol.serializer();
+
+        s.write_message_begin_seq("ack", ThriftMessageType::Reply, seq);
+        s.write_struct_begin("ack_ret");
+        s.write_field_begin("ret", ThriftType::String, 1);
+        val.serialize(&mut s);
+        s.write_field_stop();
+        s.write_field_end();
+        s.write_struct_end();
+        s.write_message_end();
+        protocol.finish(s)
     });
     Ok(ret)
-},            _ => unimplemented!()
+},            _ => {
+                let exception = Exception::new(ExceptionKind::UnknownMethod, &format!("Unknown method {}.", msg.name));
+                Ok(Future::unit(try!(exception.encode(protocol, &msg.name, msg.seq))))
+            }
         }
     }
 }
\ No newline at end of file
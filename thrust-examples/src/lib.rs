@@ -1,3 +1,5 @@
+#[macro_use]
+extern crate log;
 extern crate thrust;
 extern crate tangle;
 
@@ -5,15 +7,22 @@ pub mod foobar1;
 use foobar1::{
     BlizzardService,
     BlizzardClient,
-    BlizzardServer
+    BlizzardServer,
+    BlizzardSyncClient,
+    BlizzardSyncServer
 };
 
 use thrust::Reactor;
+use thrust::reactor::{Addr, Framing, Limits};
+use thrust::workers::Workers;
 use std::net::SocketAddr;
+use std::sync::{Once, ONCE_INIT};
+use std::sync::mpsc::channel;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tangle::{Async, Future};
 
+#[derive(Clone)]
 struct Blizzard;
 
 impl BlizzardService for Blizzard {
@@ -23,20 +32,81 @@ impl BlizzardService for Blizzard {
     }
 }
 
+/// Takes its time with every ack.
+#[derive(Clone)]
+struct Slow;
+
+impl BlizzardService for Slow {
+    fn ack(&mut self, source_id: i64, tuple_id: i64) -> Future<String> {
+        thread::sleep(Duration::from_millis(500));
+        Future::unit("ack was successful!".to_string())
+    }
+}
+
+/// Start the event loop the first time a test needs it. It's shared by all tests, so
+/// none of them shuts it down.
+fn reactor() {
+    static START: Once = ONCE_INIT;
+    START.call_once(|| { Reactor::run(); });
+}
+
+fn tcp_addr(server: &BlizzardServer) -> SocketAddr {
+    match server.local_addr().unwrap() {
+        Addr::Tcp(addr) => addr,
+        addr => panic!("Expected a TCP address, got {:?}.", addr)
+    }
+}
+
 #[test]
 fn create_a_client() {
-    let addr: SocketAddr = "127.0.0.1:2767".parse().unwrap();
-    let server = BlizzardServer::new(Blizzard, addr.clone());
-
-    thread::sleep(Duration::from_millis(25));
-    let mut rpc = BlizzardClient::new(addr.clone());
+    reactor();
+    let server = BlizzardServer::new(Blizzard, "127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = tcp_addr(&server);
 
+    let mut rpc = BlizzardClient::new(addr).unwrap();
+    let (tx, rx) = channel();
     rpc.ack(45, 99).and_then(move |res| {
         println!("{:?}", res);
+        tx.send(res);
         Async::Ok(())
     });
 
-    Reactor::run().join();
-    rpc.handle.join();
-    server.handle.join();
+    rx.recv().unwrap();
+}
+
+#[test]
+fn run_slow_calls_concurrently() {
+    reactor();
+    let workers = Workers { threads: 2, ordered: false };
+    let server = BlizzardServer::with_options(Slow, "127.0.0.1:0".parse::<SocketAddr>().unwrap(),
+                                              Framing::Framed, Limits::default(), workers).unwrap();
+    let mut rpc = BlizzardClient::new(tcp_addr(&server)).unwrap();
+
+    let started = Instant::now();
+    let first = rpc.ack(1, 1);
+    let second = rpc.ack(2, 2);
+    assert_eq!(first.recv().unwrap(), "ack was successful!");
+    assert_eq!(second.recv().unwrap(), "ack was successful!");
+
+    // Taking turns would have taken a second.
+    assert!(started.elapsed() < Duration::from_millis(900));
+}
+
+#[test]
+fn call_a_sync_server() {
+    let server = BlizzardSyncServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = server.local_addr().unwrap();
+
+    thread::spawn(move || server.serve(Blizzard));
+
+    let mut rpc = BlizzardSyncClient::connect(addr).unwrap();
+    assert_eq!(rpc.ack(45, 99).unwrap(), "ack was successful!");
+    assert_eq!(rpc.ack(46, 100).unwrap(), "ack was successful!");
+}
+
+#[test]
+fn call_over_loopback() {
+    let mut rpc = BlizzardSyncClient::loopback(Blizzard);
+    assert_eq!(rpc.ack(45, 99).unwrap(), "ack was successful!");
+    assert_eq!(rpc.ack(46, 100).unwrap(), "ack was successful!");
 }
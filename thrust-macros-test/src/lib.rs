@@ -104,15 +104,14 @@ fn serialize() {
 
         se.write_field_begin("voodoo", ThriftType::String, 1).unwrap();
         "Hello".to_string().serialize(&mut se);
-        se.write_field_stop();
         se.write_field_end();
 
         se.write_field_begin("mission_control", ThriftType::I32, 2).unwrap();
         let i: i32 = 500;
         i.serialize(&mut se);
-        se.write_field_stop();
         se.write_field_end();
 
+        se.write_field_stop();
         se.write_struct_end();
     }
 